@@ -1,15 +1,14 @@
 use std::{
-    net,
     io,
     io::Error,
     io::prelude::*,
-    net::{TcpListener, TcpStream},
+    net::TcpStream,
 };
 
 use crate::TCP_connections::protocols::*;
 
 
-pub fn client(commands:String)  -> Result<(), Error>{
+pub fn client()  -> Result<(), Error>{
     let mut stream = TcpStream::connect("127.0.0.1:7878").expect("error while connecting to addrs");
     println!("Connected to port --> 127.0.0.1:7878 <--");
     
@@ -32,23 +31,29 @@ pub fn client(commands:String)  -> Result<(), Error>{
         stream.write_all(&bytes)?;
         let mut buffer = [0;4];
         stream.read_exact(&mut buffer)?;
-        let mut vec: Vec<u8> = Vec::new();
-        vec.resize(u32::from_be_bytes(buffer) as usize, 0);
+        let mut vec: Vec<u8> = vec![0; u32::from_be_bytes(buffer) as usize];
         stream.read_exact(&mut vec)?;
 
-        let received_response = deserialize(&vec);
-
-        match received_response {
-            Response::Error(x) => {
+        match deserialize(&vec) {
+            Ok(Response::Error(x)) => {
                   eprintln!("DB --> {}",x);
             },
-            Response::Query(x) => {
+            Ok(Response::Query(x)) => {
                   println!("DB --> {}",x);
             }
-            Response::Return(packet) => {
+            Ok(Response::Return(packet)) => {
                 println!(" | Received a Packet | ");
                 println!("{}", packet);
             }
+            Ok(Response::Describe(description)) => {
+                println!("{}", description);
+            }
+            Ok(Response::Stats(stats)) => {
+                println!("{}", stats);
+            }
+            Err(err) => {
+                eprintln!("Couldn't read the response of the server: {}", err);
+            }
         }
 
     }
@@ -59,4 +64,4 @@ pub fn client(commands:String)  -> Result<(), Error>{
 
     
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,47 +1,220 @@
-use std::fmt::Error;
+use std::ffi::OsStr;
 use std::fs;
-use crate::dbengine::btrees::*;
+use std::io::{BufRead, BufReader};
+use std::ops::Bound;
+use std::path::Path;
+
 use crate::dbengine::pages::*;
-use crate::dbengine::engine::*;
-use crate::TCP_connections::server::Commands;
+use crate::database::Database;
+use crate::dbengine::error::PlanckError;
+use crate::dbengine::engine::{AlterTable, Table, TableStats};
+use crate::TCP_connections::protocols::{Description, Packet};
 
 
-pub fn create(file: &str,pk_index: u8, field_names: Vec<String>, field_types: Vec<u8>) -> Result<() , Error> {
-    let path = format!("{}{}{}", "PlanckDB/", file, ".db");
-    if fs::exists(path).unwrap() {
-        return Err(Error);
+// A single key column creates a table with a plain key, several a table with a composite key.
+pub fn create(db: &mut Database, file: &str, key_columns: Vec<u8>, field_names: Vec<String>, field_types: Vec<u8>) -> Result<(), PlanckError> {
+    match key_columns[..] {
+        [pk_index] => db.create_table(file, pk_index, field_names, field_types),
+        _ => db.create_table_with_key(file, key_columns, field_names, field_types),
     }
-    Table::new(file, pk_index,field_names, field_types);
-    return Ok(());
 }
 
-pub fn insert(kr: KeyRow, btree: &mut BPlusTree) {
-       btree.insert(kr);
-       btree.buffer_pool.flush_all();
+pub fn alter(db: &mut Database, file: &str, alteration: AlterTable) -> Result<(), PlanckError> {
+    db.alter_table(file, alteration)
+}
+
+pub fn rewrite(db: &mut Database, file: &str) -> Result<(), PlanckError> {
+    db.rewrite_table(file)
+}
+
+pub fn drop_table(db: &mut Database, file: &str) -> Result<(), PlanckError> {
+    db.drop_table(file)
+}
+
+pub fn rename_table(db: &mut Database, file: &str, new_name: &str) -> Result<(), PlanckError> {
+    db.rename_table(file, new_name)
+}
+
+// Every table as a row of a "tables" packet: name, row count, column count.
+pub fn show_tables(db: &mut Database) -> Result<Packet, PlanckError> {
+    let table = Table::new("tables", 0, vec!["name".to_string(), "rows".to_string(), "columns".to_string()], vec![7, 2, 2], 0);
+    let keyrows = db.tables()?.into_iter()
+        .map(|table| KeyRow { key: Value::string(table.name.clone()),
+            row: vec![Value::string(table.name), Value::Number(table.row_count), Value::Number(table.column_names.len() as u32)] })
+        .collect();
+    Ok(Packet { table, keyrows })
 }
 
-pub fn delete(key: Value, btree: &mut BPlusTree) {
-       btree.delete(key);
-       btree.buffer_pool.flush_all();
+pub fn describe(db: &mut Database, file: &str) -> Result<Description, PlanckError> {
+    let page_count = db.page_count(file)?;
+    Ok(Description { table: db.table(file)?.clone(), page_count })
 }
 
-pub fn update(kr: KeyRow, btree: &mut BPlusTree) {
-    btree.update(kr);
-    btree.buffer_pool.flush_all();
+pub fn stats(db: &mut Database, file: &str) -> Result<TableStats, PlanckError> {
+    db.stats(file)
 }
 
-pub fn read(key: Value, btree: &mut BPlusTree) -> Result<KeyRow,Error>{
-    let (id, parent_id) = btree.search(&key);
-    let node = btree.buffer_pool.get(id);
-    for slot in &node.slots {
-        if slot.value == key {
-            let row = &node.cells.get(&slot.pointer).unwrap();
-            return Ok(KeyRow{key: key, row: row.values.clone()});
+pub fn vacuum(db: &mut Database) -> Result<(), PlanckError> {
+    db.vacuum()
+}
+
+// Directory of the data directory holding the files a client may import, the server reads no other file.
+pub const IMPORT_DIR: &str = "import";
+
+// Bulk loads a file of the import directory into an empty table. Every line is a row written like the values
+// of an insert (`1 blabalabala`), in key order. The lines are loaded as they are read, a line out of order or
+// repeating a key stops the load and leaves the table empty. Errors only give the line, never its values.
+pub fn import(db: &mut Database, file: &str, name: &str) -> Result<u32, PlanckError> {
+    // A plain file name, "../x" or "/etc/x" would leave the import directory.
+    if Path::new(name).file_name() != Some(OsStr::new(name)) {
+        return Err(PlanckError::Syntax(format!("{} is not the name of a file of the import directory", name)));
+    }
+    let table = db.table(file)?.clone();
+    let reader = BufReader::new(fs::File::open(db.path().join(IMPORT_DIR).join(name))
+        .map_err(|_| PlanckError::NotFound(format!("file {} of the import directory", name)))?);
+    let mut line = 0;
+    // The loader checks the order too, but its errors would show the keys.
+    let mut previous: Option<Value> = None;
+    let rows = reader.lines().filter_map(move |text| {
+        line += 1;
+        let text = match text {
+            Ok(text) => text,
+            Err(_) => return Some(Err(PlanckError::Syntax(format!("line {} of {} is not utf-8 text", line, name)))),
+        };
+        let lexemes: Vec<&str> = text.split_whitespace().collect();
+        if lexemes.is_empty() {
+            return None;
+        }
+        let (key, row) = match import_row(&table, &lexemes) {
+            Ok(key_row) => key_row,
+            Err(reason) => return Some(Err(PlanckError::Syntax(format!("line {} of {}: {}", line, name, reason)))),
+        };
+        match &previous {
+            Some(previous) if key == *previous => return Some(Err(PlanckError::DuplicateKey(format!("line {} of {}", line, name)))),
+            Some(previous) if key < *previous => {
+                return Some(Err(PlanckError::SchemaMismatch(format!("line {} of {} is not in key order", line, name))));
+            }
+            _ => {}
         }
-    };
-    return Err(Error)
+        previous = Some(key);
+        Some(Ok(row))
+    });
+    db.bulk_load(file, rows)
+}
+
+// The key and values of a line of an import file, checked against the table. The reason of an error names no value.
+fn import_row(table: &Table, lexemes: &[&str]) -> Result<(Value, Vec<Value>), String> {
+    if lexemes.len() != table.column_types.len() {
+        return Err(format!("{} values for {} columns", lexemes.len(), table.column_types.len()));
+    }
+    let row = lexemes.iter().zip(&table.column_types).zip(&table.column_names)
+        .map(|((lexeme, types), column)| Value::parse(lexeme, *types).map_err(|_| format!("the value of {} does not fit its type", column)))
+        .collect::<Result<Vec<Value>, String>>()?;
+    table.check_row(&row).map_err(|_| "the row does not fit the table".to_string())?;
+    let key = table.key_of(&row).map_err(|_| "the key does not fit the table".to_string())?;
+    Ok((key, row))
+}
+
+pub fn insert(db: &mut Database, file: &str, row: Vec<Value>) -> Result<(), PlanckError> {
+       db.insert(file, row)
+}
+
+pub fn delete(db: &mut Database, file: &str, key: Value) -> Result<(), PlanckError> {
+       db.delete(file, key)
+}
+
+pub fn update(db: &mut Database, file: &str, row: Vec<Value>) -> Result<(), PlanckError> {
+    db.update(file, row)
+}
+
+pub fn read(db: &mut Database, file: &str, key: Value) -> Result<KeyRow, PlanckError>{
+    db.read(file, key)
+}
+
+pub fn read_prefix(db: &mut Database, file: &str, prefix: &[Value]) -> Result<Vec<KeyRow>, PlanckError> {
+    db.read_prefix(file, prefix)
+}
+
+pub fn read_range(db: &mut Database, file: &str, start: Bound<Value>, end: Bound<Value>, descending: bool) -> Result<Vec<KeyRow>, PlanckError> {
+    match descending {
+        true => db.read_range_desc(file, start, end),
+        false => db.read_range(file, start, end),
+    }
 }
 
 
 
 // TODO, I can add more advance ways to get data if I can find time.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A data directory with a users table and the given files in its import directory.
+    fn import_dir(name: &str, files: &[(&str, &str)]) -> (std::path::PathBuf, Database) {
+        let dir = std::env::temp_dir().join(format!("planck_import_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut db = Database::open(&dir).unwrap();
+        db.create_table("users", 0, vec!["id".to_string(), "name".to_string()], vec![NUMBER_TYPE, STRING_TYPE]).unwrap();
+        fs::create_dir_all(dir.join(IMPORT_DIR)).unwrap();
+        for (file, text) in files {
+            fs::write(dir.join(IMPORT_DIR).join(file), text).unwrap();
+        }
+        (dir, db)
+    }
+
+    fn row_count(db: &mut Database) -> u32 {
+        db.table("users").unwrap().row_count
+    }
+
+    #[test]
+    fn import_loads_the_lines_in_key_order() {
+        let text: String = (0..2000).map(|id| format!("{} name{}\n", id, id)).collect();
+        let (dir, mut db) = import_dir("ordered", &[("users.txt", &format!("\n{}\n", text))]);
+        assert_eq!(import(&mut db, "users", "users.txt").unwrap(), 2000);
+        assert_eq!(row_count(&mut db), 2000);
+        assert_eq!(db.read("users", Value::Number(1234)).unwrap().row, vec![Value::Number(1234), Value::string("name1234".to_string())]);
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn import_rejects_lines_out_of_order_without_showing_them() {
+        let (dir, mut db) = import_dir("errors", &[
+            ("unsorted.txt", "1 secret\n3 secret\n2 secret\n"),
+            ("repeated.txt", "1 secret\n2 secret\n2 secret\n"),
+            ("columns.txt", "1 secret\n2 secret secret\n"),
+            ("types.txt", "1 secret\nsecret secret\n"),
+        ]);
+        let errors = ["unsorted.txt", "repeated.txt", "columns.txt", "types.txt"].map(|name| import(&mut db, "users", name).unwrap_err());
+        assert!(matches!(errors[0], PlanckError::SchemaMismatch(_)));
+        assert!(matches!(errors[1], PlanckError::DuplicateKey(_)));
+        assert!(matches!(errors[2], PlanckError::Syntax(_)));
+        assert!(matches!(errors[3], PlanckError::Syntax(_)));
+        for (err, line) in errors.iter().zip([3, 3, 2, 2]) {
+            let message = err.to_string();
+            assert!(message.contains(&format!("line {} of", line)), "{}", message);
+            assert!(!message.contains("secret"), "{}", message);
+        }
+        assert_eq!(row_count(&mut db), 0);
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn import_only_reads_the_import_directory() {
+        let (dir, mut db) = import_dir("paths", &[]);
+        fs::write(dir.join("outside.txt"), "1 secret\n").unwrap();
+        let outside = dir.join("outside.txt").to_string_lossy().into_owned();
+        for name in ["../outside.txt", outside.as_str(), "..", ".", "", "import/../../outside.txt"] {
+            let err = import(&mut db, "users", name).unwrap_err();
+            assert!(matches!(err, PlanckError::Syntax(_)), "{}: {}", name, err);
+        }
+        let err = import(&mut db, "users", "missing.txt").unwrap_err();
+        assert!(matches!(err, PlanckError::NotFound(_)));
+        assert!(!err.to_string().contains(&dir.to_string_lossy().into_owned()), "{}", err);
+        assert_eq!(row_count(&mut db), 0);
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
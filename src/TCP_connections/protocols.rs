@@ -1,5 +1,6 @@
 use crate::dbengine::pages::*;
 use crate::dbengine::engine::*;
+use crate::dbengine::error::PlanckError;
 
 use std::fmt;
 
@@ -16,18 +17,72 @@ impl fmt::Display for Packet {
         writeln!(f, "{}", self.table)?;
         writeln!(f, "Keys and Rows:")?;
         for (i, keyrow) in self.keyrows.iter().enumerate() {
-            write!(f, "  {}: {}", i + 1, keyrow)?;
+            writeln!(f, "  {}: {}", i + 1, keyrow)?;
         }
         Ok(())
     }
 }
 
+// Answer to a describe command: the schema of a table and what the catalog knows about it.
+pub struct Description {
+    pub table: Table,
+    pub page_count: u32,
+}
+
+impl fmt::Display for Description {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Table: {}", self.table.name)?;
+        writeln!(f, "Columns:")?;
+        for (name, col_type) in self.table.column_names.iter().zip(self.table.column_types.iter()) {
+            match is_nullable(*col_type) {
+                true => writeln!(f, "  - {} ({}, nullable)", name, type_name(*col_type))?,
+                false => writeln!(f, "  - {} ({})", name, type_name(*col_type))?,
+            }
+        }
+        writeln!(f, "Primary Key: {}", self.table.key_names().join(", "))?;
+        writeln!(f, "Rows: {}", self.table.row_count)?;
+        writeln!(f, "Pages: {}", self.page_count)?;
+        writeln!(f, "Schema Version: {}", self.table.schema_version)
+    }
+}
+
 pub enum Response {
     Query(String),
     Return(Packet),
+    Describe(Description),
+    Stats(TableStats),
     Error(String),
 }
 
+// Bounds checked read of `len` bytes, moves the pointer forward. A short message is an error, not a panic.
+fn take<'a>(data: &'a [u8], pointer: &mut usize, len: usize) -> Result<&'a [u8], PlanckError> {
+    match data.get(*pointer..*pointer + len) {
+        Some(bytes) => {
+            *pointer += len;
+            Ok(bytes)
+        }
+        None => Err(PlanckError::Corruption("message is shorter than expected".to_string()))
+    }
+}
+
+fn take_u8(data: &[u8], pointer: &mut usize) -> Result<u8, PlanckError> {
+    Ok(take(data, pointer, 1)?[0])
+}
+
+fn take_u16(data: &[u8], pointer: &mut usize) -> Result<u16, PlanckError> {
+    let vl = take(data, pointer, 2)?;
+    Ok(u16::from_be_bytes([vl[0], vl[1]]))
+}
+
+fn take_u32(data: &[u8], pointer: &mut usize) -> Result<u32, PlanckError> {
+    let vl = take(data, pointer, 4)?;
+    Ok(u32::from_be_bytes([vl[0], vl[1], vl[2], vl[3]]))
+}
+
+fn take_string(data: &[u8], pointer: &mut usize, len: usize) -> Result<String, PlanckError> {
+    Ok(std::str::from_utf8(take(data, pointer, len)?)?.to_string())
+}
+
 fn serialize_table(table: Table) -> Vec<u8> {
     let mut vec:Vec<u8> = Vec::new();
     vec.extend_from_slice(&[0;2]);
@@ -41,274 +96,248 @@ fn serialize_table(table: Table) -> Vec<u8> {
     };
     let size = vec.len() as u16;
     vec[0..2].copy_from_slice(&(size - 2).to_be_bytes());
-    return vec;
+    vec
 }
 
-fn deconstruct_table(data: &[u8]) -> Table {
-   let len_tmp = data[0] as usize ;
-   let mut pointer = 1;
-   let name: String = std::str::from_utf8(&data[pointer..pointer+len_tmp]).unwrap().to_string();
-   pointer += len_tmp;
-   let len_tmp = data[pointer];
-   pointer += 1;
+fn deconstruct_table(data: &[u8]) -> Result<Table, PlanckError> {
+   let mut pointer = 0;
+   let len_tmp = take_u8(data, &mut pointer)? as usize;
+   let name: String = take_string(data, &mut pointer, len_tmp)?;
+   let len_tmp = take_u8(data, &mut pointer)?;
    let mut column_types = Vec::new();
    let mut column_names = Vec::new();
-   for i in 0..len_tmp {
-       column_types.push(data[pointer]);
-       pointer += 1;
-       let tmp_len = data[pointer] as usize;
-       pointer += 1;
-       column_names.push(std::str::from_utf8(&data[pointer..pointer + tmp_len]).unwrap().to_string());
-       pointer += tmp_len;
-
+   for _ in 0..len_tmp {
+       column_types.push(take_u8(data, &mut pointer)?);
+       let tmp_len = take_u8(data, &mut pointer)? as usize;
+       column_names.push(take_string(data, &mut pointer, tmp_len)?);
    }
    // Most of the values are empty or zero because we do not really need them from now on.
-   return Table { name: name,pk_column: 0, column_number: 0, column_names: column_names, column_types: column_types, page_id_count: 0, root_node_offset: 0, free_page_num: 0, free_page_list: vec![] };
+   Ok(Table::new(&name, 0, column_names, column_types, 0))
 }
 
-fn serialize_keyrow(mut kr: Vec<KeyRow>) -> Vec<u8> {
-    let mut vec: Vec<u8> = Vec::new();
-    vec.extend_from_slice(&[0;2]);
-    vec.push(kr.len() as u8);
-    let first = kr.remove(0);
-    match first.key {
+fn serialize_value(vec: &mut Vec<u8>, value: Value) {
+    match value {
         Value::Number(x) => {
-            vec.push(2);
             vec.extend_from_slice(&x.to_be_bytes());
         }
-        Value::String(len, string) => {
-            vec.push(7);
-            vec.push(len);
+        Value::String(_, string) => {
+            vec.extend_from_slice(&(string.len() as u32).to_be_bytes());
             vec.extend_from_slice(string.as_bytes());
         }
+        Value::Int64(x) => {
+            vec.extend_from_slice(&encode_i64(x));
+        }
+        Value::Float64(x) => {
+            vec.extend_from_slice(&encode_f64(x));
+        }
+        Value::Bool(x) => {
+            vec.push(x as u8);
+        }
+        Value::Date(x) => {
+            vec.extend_from_slice(&encode_i32(x));
+        }
+        Value::Timestamp(x) => {
+            vec.extend_from_slice(&encode_i64(x));
+        }
+        Value::Bytes(bytes) | Value::Composite(bytes) => {
+            vec.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            vec.extend_from_slice(&bytes);
+        }
+        // Rows are read through the tree before they are sent, which gives back whole values. Only the prefix is known here.
+        Value::Overflow { prefix, .. } => {
+            vec.extend_from_slice(&(prefix.len() as u32).to_be_bytes());
+            vec.extend_from_slice(&prefix);
+        }
+        // Marked in the null bitmap of the row.
+        Value::Null => {}
     }
-    vec.push(first.row.len() as u8);
-    for row in first.row {
-        match row {    
-            Value::Number(x) => {
-                vec.extend_from_slice(&x.to_be_bytes());
-            }
-            Value::String(len, string) => {
-                vec.push(len);
-                vec.extend_from_slice(string.as_bytes());
-            }
+}
+
+// A row on the wire is its length, a null bitmap like the one of a cell and the values that are not NULL.
+fn serialize_row(vec: &mut Vec<u8>, row: Vec<Value>) {
+    vec.push(row.len() as u8);
+    let mut bitmap = vec![0u8; null_bitmap_size(row.len())];
+    for (index, value) in row.iter().enumerate() {
+        if let Value::Null = value {
+            bitmap[index / 8] |= 1 << (index % 8);
         }
     }
+    vec.extend(bitmap);
+    for value in row {
+        serialize_value(vec, value);
+    }
+}
+
+fn serialize_keyrow(kr: Vec<KeyRow>) -> Vec<u8> {
+    let mut vec: Vec<u8> = Vec::new();
+    // Rows can hold large values, the size of this part takes 4 bytes.
+    vec.extend_from_slice(&[0;4]);
+    // A prefix read can return any number of rows.
+    vec.extend_from_slice(&(kr.len() as u32).to_be_bytes());
+    // The type of the key is sent once, every key in a packet has the same type.
+    vec.push(kr.first().map(|first| first.key.type_code()).unwrap_or(NUMBER_TYPE));
     for keyrow in kr {
-        match keyrow.key {
-            Value::Number(x) => {
-                vec.extend_from_slice(&x.to_be_bytes());
-            }
-            Value::String(len, string) => {
-                vec.push(len);
-                vec.extend_from_slice(string.as_bytes());
-            }
-        };
+        serialize_value(&mut vec, keyrow.key);
+        serialize_row(&mut vec, keyrow.row);
+    }
+    let size = vec.len() as u32;
+    vec[0..4].copy_from_slice(&(size - 4).to_be_bytes());
+    vec
+}
 
-        vec.push(keyrow.row.len() as u8);
-        for row in keyrow.row {
-            match row {    
-                Value::Number(x) => {
-                    vec.extend_from_slice(&x.to_be_bytes());
-                }
-                Value::String(len, string) => {
-                    vec.push(len);
-                    vec.extend_from_slice(string.as_bytes());
-                }
-            }
+fn deconstruct_value(data: &[u8], pointer: &mut usize, types: u8) -> Result<Value, PlanckError> {
+    match base_type(types) {
+        NUMBER_TYPE => Ok(Value::Number(take_u32(data, pointer)?)),
+        INT64_TYPE => Ok(Value::Int64(decode_i64(take(data, pointer, 8)?))),
+        FLOAT64_TYPE => Ok(Value::Float64(decode_f64(take(data, pointer, 8)?))),
+        BOOL_TYPE => Ok(Value::Bool(take_u8(data, pointer)? != 0)),
+        DATE_TYPE => Ok(Value::Date(decode_i32(take(data, pointer, 4)?))),
+        TIMESTAMP_TYPE => Ok(Value::Timestamp(decode_i64(take(data, pointer, 8)?))),
+        BYTES_TYPE => {
+            let tmp_len = take_u32(data, pointer)? as usize;
+            Ok(Value::Bytes(take(data, pointer, tmp_len)?.to_vec()))
         }
-
+        COMPOSITE_TYPE => {
+            let tmp_len = take_u32(data, pointer)? as usize;
+            Ok(Value::Composite(take(data, pointer, tmp_len)?.to_vec()))
+        }
+        STRING_TYPE => {
+            let tmp_len = take_u32(data, pointer)? as usize;
+            Ok(Value::string(take_string(data, pointer, tmp_len)?))
+        }
+        x => Err(PlanckError::Corruption(format!("unknown value type {} in message", x)))
     }
-    let size = vec.len() as u16;
-    vec[0..2].copy_from_slice(&(size - 2).to_be_bytes());
-    return vec;
 }
 
+fn deconstruct_keyrow(data: &[u8], column_types: &[u8]) -> Result<Vec<KeyRow>, PlanckError> {
 
-fn deconstruct_keyrow(data: &[u8], column_types: &Vec<u8>) -> Vec<KeyRow> {
-    
     let mut vec = Vec::new();
     let mut pointer = 0;
-    let tmp_len = data[pointer];
-    pointer += 1;
-    let is_string = match data[pointer]{
-        2 => false ,
-        7 => true,
-        _ => panic!()
-    };
-    pointer += 1;
-    for i in 0..tmp_len {
-        if is_string {
-           let tmp_len = data[pointer] as usize;
-           pointer += 1;
-           let string = std::str::from_utf8(&data[pointer..pointer+tmp_len]).unwrap().to_string();
-           pointer += tmp_len;
-           let mut row_vec = Vec::new();
-           for types in column_types {
-               match types {
-                  2 => {
-                    let vl = &data[pointer..pointer+4];
-                    pointer += 4;
-                    if vl.len() == 4 {
-                        let array: [u8; 4] = [vl[0], vl[1], vl[2], vl[3]];
-                        row_vec.push(Value::Number(u32::from_be_bytes(array)));
-                    } else {
-                        panic!()
-                    }
-                  },
-                  7 => {
-                        let tmp_len = data[pointer] as usize;
-                        pointer += 1;
-                        let str = std::str::from_utf8(&data[pointer..pointer+tmp_len]).unwrap().to_string();
-                        pointer += tmp_len;
-                        row_vec.push(Value::string(str))
-                  }
-                  _ => panic!()
-               }
-           }
-           vec.push(KeyRow{key: Value::string(string), row: row_vec})
-        } else {
-            let vl = &data[pointer..pointer+4];
-            pointer += 4;
-            let tmp_len = data[pointer];
-            if !(tmp_len as usize == column_types.len()) {
-                panic!()
-            }
-            pointer += 1;
-            let mut key;
-            if vl.len() == 4 {
-                let array: [u8; 4] = [vl[0], vl[1], vl[2], vl[3]];
-                key = Value::Number(u32::from_be_bytes(array));
+    let tmp_len = take_u32(data, &mut pointer)?;
+    let key_type = take_u8(data, &mut pointer)?;
+    for _ in 0..tmp_len {
+        let key = deconstruct_value(data, &mut pointer, key_type)?;
+        let row_len = take_u8(data, &mut pointer)?;
+        if row_len as usize != column_types.len() {
+            return Err(PlanckError::SchemaMismatch(format!("row has {} values but the table has {} columns", row_len, column_types.len())));
+        }
+        let bitmap = take(data, &mut pointer, null_bitmap_size(column_types.len()))?;
+        let mut row_vec = Vec::new();
+        for (index, types) in column_types.iter().enumerate() {
+            if bitmap[index / 8] & (1 << (index % 8)) != 0 {
+                row_vec.push(Value::Null);
             } else {
-                panic!()
-            } 
-            let mut row_vec = Vec::new();
-            for types in column_types {
-                match types {
-                    2 => {
-                        let vl = &data[pointer..pointer+4];
-                        pointer += 4;
-                        if vl.len() == 4 {
-                            let array: [u8; 4] = [vl[0], vl[1], vl[2], vl[3]];
-                            row_vec.push(Value::Number(u32::from_be_bytes(array)));
-                        } else {
-                            panic!()
-                        }
-                    },
-                    7 => {
-                            let tmp_len = data[pointer] as usize;
-                            pointer += 1;
-                            let str = std::str::from_utf8(&data[pointer..pointer+tmp_len]).unwrap().to_string();
-                            pointer += tmp_len;
-                            row_vec.push(Value::string(str))
-                    }
-                    x => panic!("{}", x)
-                }
+                row_vec.push(deconstruct_value(data, &mut pointer, *types)?);
             }
-            vec.push(KeyRow{key: key, row: row_vec})
         }
+        vec.push(KeyRow{key, row: row_vec})
     }
-    return vec;
+    Ok(vec)
 }
 
 pub fn serialize(response: Response) -> Vec<u8> {
     let mut packet = Vec::from(0u32.to_be_bytes());
-    
+
     match response {
         Response::Query(s) => {
             packet.push(b'+');
             let len = s.len() as u16;
             packet.extend_from_slice(&len.to_be_bytes());
             packet.extend_from_slice(s.as_bytes());
-            let size = packet.len() as u32 - 4;
-            packet[0..4].copy_from_slice(&size.to_be_bytes());
         },
         Response::Return(pack) => {
               packet.push(b'=');
               packet.extend(serialize_table(pack.table));
               packet.extend(serialize_keyrow(pack.keyrows));
-              let size = packet.len() as u32 - 4;
-              packet[0..4].copy_from_slice(&size.to_be_bytes());
+        },
+        Response::Describe(description) => {
+            packet.push(b'#');
+            packet.push(description.table.pk_column);
+            packet.push(description.table.key_columns.len() as u8);
+            packet.extend_from_slice(&description.table.key_columns);
+            packet.extend_from_slice(&description.table.row_count.to_be_bytes());
+            packet.extend_from_slice(&description.page_count.to_be_bytes());
+            packet.extend_from_slice(&description.table.schema_version.to_be_bytes());
+            packet.extend(serialize_table(description.table));
+        },
+        Response::Stats(stats) => {
+            packet.push(b'%');
+            packet.push(stats.name.len() as u8);
+            packet.extend_from_slice(stats.name.as_bytes());
+            for number in [stats.row_count, stats.tree.leaf_pages, stats.tree.internal_pages, stats.tree.height,
+                           stats.tree.bytes_used, stats.tree.min_page_bytes, stats.free_pages] {
+                packet.extend_from_slice(&number.to_be_bytes());
+            }
+            for count in stats.fill_histogram {
+                packet.extend_from_slice(&count.to_be_bytes());
+            }
         },
         Response::Error(s) => {
             packet.push(b'!');
             let len = s.len() as u16;
             packet.extend_from_slice(&len.to_be_bytes());
             packet.extend_from_slice(s.as_bytes());
-            let size = packet.len() as u32 - 4;
-            packet[0..4].copy_from_slice(&size.to_be_bytes());
         }
     }
-    return packet;
+    let size = packet.len() as u32 - 4;
+    packet[0..4].copy_from_slice(&size.to_be_bytes());
+    packet
 }
 
 
-pub fn deserialize(response: &[u8]) -> Response {
-    let mut pointer = 1;
-    match response[0] {
+pub fn deserialize(response: &[u8]) -> Result<Response, PlanckError> {
+    let mut pointer = 0;
+    match take_u8(response, &mut pointer)? {
         b'+' => {
-            let vl = &response[pointer..pointer +2];
-            pointer += 2;
-            let mut number;
-            if vl.len() == 2 {
-                number = [vl[0], vl[1]];
-            }
-            else {
-                panic!()
-            }
-            let mut len_tmp = u16::from_be_bytes(number) as usize;
-             let s = std::str::from_utf8(&response[pointer..pointer + len_tmp]).unwrap().to_string();
-             pointer += len_tmp;
-             return Response::Query(s) 
+             let len_tmp = take_u16(response, &mut pointer)? as usize;
+             Ok(Response::Query(take_string(response, &mut pointer, len_tmp)?))
         },
         b'=' => {
-             let vl = &response[pointer..pointer +2];
-             pointer += 2;
-             let mut number;
-             if vl.len() == 2 {
-                 number = [vl[0], vl[1]];
-             }
-             else {
-                 panic!()
-             }
-             let mut len_tmp = u16::from_be_bytes(number) as usize;
-             let table = deconstruct_table(&response[pointer..pointer+ len_tmp]);
-             pointer += len_tmp;
-             let vl = &response[pointer..pointer +2];
-             pointer += 2;
-             let mut number;
-             if vl.len() == 2 {
-                 number = [vl[0], vl[1]];
-             }
-             else {
-                 panic!()
-             }
-             let mut len_tmp = u16::from_be_bytes(number) as usize;
-             let keyrow = deconstruct_keyrow(&response[pointer..pointer+len_tmp], &table.column_types);
+             let len_tmp = take_u16(response, &mut pointer)? as usize;
+             let table = deconstruct_table(take(response, &mut pointer, len_tmp)?)?;
+             let len_tmp = take_u32(response, &mut pointer)? as usize;
+             let keyrow = deconstruct_keyrow(take(response, &mut pointer, len_tmp)?, &table.column_types)?;
 
-             Response::Return(Packet { table: table, keyrows: keyrow })
+             Ok(Response::Return(Packet { table, keyrows: keyrow }))
         },
-        b'!' => {
-            let vl = &response[pointer..pointer +2];
-            pointer += 2;
-            let mut number;
-            if vl.len() == 2 {
-                number = [vl[0], vl[1]];
-            }
-            else {
-                panic!()
-            }
-            let mut len_tmp = u16::from_be_bytes(number) as usize;
-             let s = std::str::from_utf8(&response[pointer..pointer + len_tmp]).unwrap().to_string();
-             pointer += len_tmp;
-             return Response::Error(s) 
+        b'#' => {
+             let pk_column = take_u8(response, &mut pointer)?;
+             let len_tmp = take_u8(response, &mut pointer)? as usize;
+             let key_columns = take(response, &mut pointer, len_tmp)?.to_vec();
+             let row_count = take_u32(response, &mut pointer)?;
+             let page_count = take_u32(response, &mut pointer)?;
+             let schema_version = take_u16(response, &mut pointer)?;
+             let len_tmp = take_u16(response, &mut pointer)? as usize;
+             let mut table = deconstruct_table(take(response, &mut pointer, len_tmp)?)?;
+             table.pk_column = pk_column;
+             table.key_columns = key_columns;
+             table.row_count = row_count;
+             table.schema_version = schema_version;
+             Ok(Response::Describe(Description { table, page_count }))
         },
-        _ => {
-            panic!()
+        b'%' => {
+             let len_tmp = take_u8(response, &mut pointer)? as usize;
+             let name = take_string(response, &mut pointer, len_tmp)?;
+             let row_count = take_u32(response, &mut pointer)?;
+             let tree = TreeStats {
+                 leaf_pages: take_u32(response, &mut pointer)?,
+                 internal_pages: take_u32(response, &mut pointer)?,
+                 height: take_u32(response, &mut pointer)?,
+                 bytes_used: take_u32(response, &mut pointer)?,
+                 min_page_bytes: take_u32(response, &mut pointer)?,
+             };
+             let free_pages = take_u32(response, &mut pointer)?;
+             let mut fill_histogram = [0u32; 10];
+             for count in fill_histogram.iter_mut() {
+                 *count = take_u32(response, &mut pointer)?;
+             }
+             Ok(Response::Stats(TableStats { name, row_count, tree, free_pages, fill_histogram }))
         },
-
+        b'!' => {
+             let len_tmp = take_u16(response, &mut pointer)? as usize;
+             Ok(Response::Error(take_string(response, &mut pointer, len_tmp)?))
+        },
+        x => Err(PlanckError::Corruption(format!("unknown message type {}", x))),
     }
-    
-}
-
 
+}
@@ -1,247 +1,388 @@
 use std::{
-    fs, io::{prelude::*, Error}, net::{self, TcpListener, TcpStream}, process::Command, vec
+    fs, io::{prelude::*, Error}, net::{TcpListener, TcpStream}, ops::Bound, path::PathBuf
 };
 
-use crate::dbengine::btrees::*;
-use crate::dbengine::engine::*;
 use crate::dbengine::pages::*;
+use crate::dbengine::error::PlanckError;
+use crate::dbengine::engine::{AlterTable, Table, DATABASE_FILE};
 use crate::TCP_connections::execute::*;
 use crate::TCP_connections::protocols::*;
+use crate::database::Database;
 
 
 pub enum Commands {
-    Insert(KeyRow),
+    // Whole rows, the key is one of their columns.
+    Insert(Vec<Value>),
     Delete(Value),
-    Update(KeyRow),
+    Update(Vec<Value>),
     Read(Value),
-    Create(String,u8,  Vec<String>, Vec<u8>),
+    ReadPrefix(Vec<Value>),
+    // First and last key, `10..200` leaves 200 out and `10..=200` takes it in. True for a `desc` read.
+    ReadRange(Bound<Value>, Bound<Value>, bool),
+    // Table name, the indexes of the key columns (several for a composite key), column names and types.
+    Create(String, Vec<u8>,  Vec<String>, Vec<u8>),
+    Alter(String, AlterTable),
+    Drop(String),
+    Rename(String, String),
+    ShowTables(),
+    Describe(String),
+    Vacuum(),
+    // Table name and the path of a file of rows, see execute::import.
+    Import(String, String),
+    Stats(String),
+    Rewrite(String),
     StartTransaction(String),
     CloseTransaction(),
-    SyntaxError(String)
 }
 
-fn identify_value(input: &str) -> Value {
-    if let Ok(x) = input.parse::<u32>() {
-        Value::Number(x)
-    } else {
-        Value::string(input.to_string())
+const CREATE_SYNTAX: &str = "Syntax: create table_name key -> Id[,Age...] columns | Id 2 | Age 2 | Website 7 null | ";
+const INSERT_SYNTAX: &str = "Syntax: insert row -> 1 blabalabala ";
+const UPDATE_SYNTAX: &str = "Syntax: update row -> 1 blabalabala ";
+const DELETE_SYNTAX: &str = "Syntax: delete key -> 1 ";
+const READ_SYNTAX: &str = "Syntax: read key -> 1 | read key -> 10..200 [desc] | read key -> 10..=200 [desc] | read prefix -> 42 ";
+const DROP_SYNTAX: &str = "Syntax: drop table_name ";
+const RENAME_SYNTAX: &str = "Syntax: rename table_name new_name ";
+const SHOW_SYNTAX: &str = "Syntax: show tables ";
+const DESCRIBE_SYNTAX: &str = "Syntax: describe table_name ";
+const STATS_SYNTAX: &str = "Syntax: stats table_name ";
+const IMPORT_SYNTAX: &str = "Syntax: import table_name rows.txt (a file of the import directory) ";
+const ALTER_SYNTAX: &str = "Syntax: alter table_name add Age 2 [null] | alter table_name drop Age | alter table_name rename Age Years | alter table_name rewrite ";
+
+// Keys are parsed with the types of the key columns. A composite key is written as comma separated values, e.g. `42,7`.
+fn parse_key(input: &str, table: Option<&Table>) -> Result<Value, PlanckError> {
+    match table {
+        Some(table) if table.is_composite() => Value::parse_composite(input, &table.key_types()),
+        Some(table) => Value::parse(input, table.key_types().first().copied().unwrap_or(0)),
+        None => Err(PlanckError::Syntax("You need to open a transaction to edit DB".to_string())),
     }
 }
 
-fn parse(str: String, column_types: Option<&Vec<u8>>) -> Commands {
-    let mut str_vec: Vec<&str> = str.split_whitespace().collect(); 
+// Bounds of a range of keys: `10..200`, `10..=200`, `10..` or `..200`, a missing side is unbounded.
+fn parse_range(input: &str, table: Option<&Table>) -> Result<(Bound<Value>, Bound<Value>), PlanckError> {
+    let (start, end) = match input.split_once("..") {
+        Some(sides) => sides,
+        None => return Err(PlanckError::Syntax(READ_SYNTAX.to_string())),
+    };
+    let start = match start {
+        "" => Bound::Unbounded,
+        start => Bound::Included(parse_key(start, table)?),
+    };
+    let end = match end.strip_prefix('=') {
+        Some(end) => Bound::Included(parse_key(end, table)?),
+        None if end.is_empty() => Bound::Unbounded,
+        None => Bound::Excluded(parse_key(end, table)?),
+    };
+    Ok((start, end))
+}
 
-    while str_vec.len() > 0 {
-         let lexeme = str_vec.remove(0);
+// The first values of a composite key, parsed with the types of the key.
+fn parse_prefix(input: &str, table: Option<&Table>) -> Result<Vec<Value>, PlanckError> {
+    match table {
+        Some(table) if table.is_composite() => Value::parse_composite(input, &table.key_types())?.components(),
+        Some(table) => Err(PlanckError::SchemaMismatch(format!("table {} does not have a composite key", table.name))),
+        None => Err(PlanckError::Syntax("You need to open a transaction to edit DB".to_string())),
+    }
+}
 
-         match lexeme {
-            "create" => {
-                if str_vec.len() < 3 {
-                    return Commands::SyntaxError("Syntax: create table_name key -> type_of_key columns | Age 2 | Website 7 | ".to_string());
-                }
-                let file = str_vec.remove(0);
-                let key_token = str_vec.remove(0);
-                if key_token != "key" {
-                    return Commands::SyntaxError("Syntax: create table_name key -> type_of_key columns | Age 2 | Website 7 | ".to_string());
-                 }
-                 if str_vec.remove(0) != "->" {
-                    return Commands::SyntaxError("Syntax: create table_name key -> type_of_key columns | Age 2 | Website 7 | ".to_string());
-                 }
-                 let pk = str_vec.remove(0).parse::<u8>().unwrap();
-                 let columns = str_vec.remove(0);
-                if columns != "columns" {
-                    return Commands::SyntaxError("Syntax: create table_name key -> type_of_key columns | Age 2 | Website 7 | ".to_string());
-                 }
-                 let mut name_vec = Vec::new();
-                 let mut type_vec = Vec::new();
-                while str_vec.len() > 1 {
-                    if str_vec.remove(0) != "|" {
-                        return Commands::SyntaxError("Syntax: create table_name key -> type_of_key columns | Age 2 | Website 7 | ".to_string());
-                    }
-                    let lexeme = str_vec.remove(0);
-                    name_vec.push(lexeme.to_string());
-                    let types = str_vec.remove(0);
-                    match types.parse::<u8>() {
-                        Ok(x) => {
-                            type_vec.push(x);
-                        }
-                        _ => return Commands::SyntaxError("Syntax: create table_name key -> type_of_key columns | Age 2 | Website 7 | ".to_string())
-                    }
-                }
-                if str_vec.remove(0) != "|" {
-                    return Commands::SyntaxError("Syntax: create table_name key -> type_of_key columns | Age 2 | Website 7 | ".to_string());
-                }
+// Takes the next token, running out of tokens is a syntax error.
+fn next_token<'a>(str_vec: &mut Vec<&'a str>, syntax: &str) -> Result<&'a str, PlanckError> {
+    if str_vec.is_empty() {
+        return Err(PlanckError::Syntax(syntax.to_string()));
+    }
+    Ok(str_vec.remove(0))
+}
 
-                return Commands::Create(file.to_string(), pk, name_vec, type_vec);
-            },
-            "open" => {
-                if str_vec.len() < 1 {
-                    return Commands::SyntaxError("You need to specifiy the file/table name".to_string());
-                }
-                let file = str_vec.remove(0);
-                return Commands::StartTransaction(file.to_string());
-            },
-            "close" => {
-                return Commands::CloseTransaction();
-            }
-            "insert" => {
-                if str_vec.len() < 6 {
-                    return Commands::SyntaxError("Syntax: insert key -> 1 row -> blabalabala ".to_string());
-                }
-                let key_token = str_vec.remove(0);
-                if key_token != "key" {
-                   return Commands::SyntaxError("Syntax: insert key -> 1 row -> blabalabala ".to_string());
-                }
-                if str_vec.remove(0) != "->" {
-                    return  Commands::SyntaxError("Syntax: insert key -> 1 row -> blabalabala ".to_string());
-                }
-                // TODO Youy can change it so that by pk_index you can know the type 
-                let key_value = identify_value(str_vec.remove(0));
+fn expect_token(str_vec: &mut Vec<&str>, expected: &str, syntax: &str) -> Result<(), PlanckError> {
+    if next_token(str_vec, syntax)? != expected {
+        return Err(PlanckError::Syntax(syntax.to_string()));
+    }
+    Ok(())
+}
 
-                if str_vec.remove(0) != "row" {
-                    return Commands::SyntaxError("Syntax: insert key -> 1 row -> blabalabala ".to_string());
-                }
-                if str_vec.remove(0) != "->" {
-                    return  Commands::SyntaxError("Syntax: insert key -> 1 row -> blabalabala ".to_string());
-                }
-                let mut row = Vec::new();
-                for types in column_types.unwrap() {
-                    match types {
-                        2 => {
-                            let lexeme = str_vec.remove(0);
-                            let number = lexeme.parse::<u32>().unwrap();
-                            row.push(Value::Number(number));
-                        }
+// An optional "null" after the type of a column makes it nullable.
+fn nullable_token(str_vec: &mut Vec<&str>) -> u8 {
+    match str_vec.first() {
+        Some(&"null") => {
+            str_vec.remove(0);
+            NULLABLE
+        }
+        _ => 0,
+    }
+}
 
-                        7 => {
-                            let lexeme = str_vec.remove(0);
-                            row.push(Value::string(lexeme.to_string()))
-                        }
-                        _ => panic!()
-                    }
-                }
+fn parse_row(str_vec: &mut Vec<&str>, table: Option<&Table>, syntax: &str) -> Result<Vec<Value>, PlanckError> {
+    let column_types = match table {
+        Some(table) => &table.column_types,
+        None => return Err(PlanckError::Syntax("You need to open a transaction to edit DB".to_string()))
+    };
+    let mut row = Vec::new();
+    for types in column_types {
+        let lexeme = next_token(str_vec, syntax)?;
+        row.push(Value::parse(lexeme, *types)?);
+    }
+    Ok(row)
+}
 
-                return  Commands::Insert(KeyRow { key: key_value, row: row }); 
-            },
-            "delete" => {
-                if str_vec.len() < 3 {
-                    return Commands::SyntaxError("Syntax: delete key -> 1 ".to_string());
-                }
-                let key_token = str_vec.remove(0);
-                if key_token != "key" {
-                    return Commands::SyntaxError("Syntax: delete key -> 1 ".to_string());
-                }
-                if str_vec.remove(0) != "->" {
-                    return Commands::SyntaxError("Syntax: delete key -> 1 ".to_string());
-                }
-                let key_value = identify_value(str_vec.remove(0));
-                return Commands::Delete(key_value);
-            },
-            "update" => {
-                if str_vec.len() < 6 {
-                    return Commands::SyntaxError("Syntax: update key -> 1 row -> blabalabala ".to_string());
-                }
-                let key_token = str_vec.remove(0);
-                if key_token != "key" {
-                    return Commands::SyntaxError("Syntax: update key -> 1 row -> blabalabala ".to_string());
-                }
-                if str_vec.remove(0) != "->" {
-                    return  Commands::SyntaxError("Syntax: insert key -> 1 row -> blabalabala ".to_string());
-                }
-                let key_value = identify_value(str_vec.remove(0));
-                if str_vec.remove(0) != "row" {
-                    return Commands::SyntaxError("Syntax: update key -> 1 row -> blabalabala ".to_string());
-                }
-                if str_vec.remove(0) != "->" {
-                    return Commands::SyntaxError("Syntax: update key -> 1 row -> blabalabala ".to_string());
-                }
-                let mut row = Vec::new();
-                for types in column_types.unwrap() {
-                    match types {
-                        2 => {
-                            let lexeme = str_vec.remove(0);
-                            let number = lexeme.parse::<u32>().unwrap();
-                            row.push(Value::Number(number));
-                        }
+// The table is the one of the open transaction, it gives the types of the keys and of the rows.
+fn parse(str: String, table: Option<&Table>) -> Result<Commands, PlanckError> {
+    let mut str_vec: Vec<&str> = str.split_whitespace().collect();
 
-                        7 => {
-                            let lexeme = str_vec.remove(0);
-                            row.push(Value::string(lexeme.to_string()))
-                        }
-                        _ => panic!()
+    if str_vec.is_empty() {
+        return Err(PlanckError::Syntax("Empty input string".to_string()));
+    }
+    let lexeme = str_vec.remove(0);
+
+    match lexeme {
+        "create" => {
+            let file = next_token(&mut str_vec, CREATE_SYNTAX)?;
+            expect_token(&mut str_vec, "key", CREATE_SYNTAX)?;
+            expect_token(&mut str_vec, "->", CREATE_SYNTAX)?;
+            let key_names = next_token(&mut str_vec, CREATE_SYNTAX)?;
+            expect_token(&mut str_vec, "columns", CREATE_SYNTAX)?;
+            let mut name_vec = Vec::new();
+            let mut type_vec = Vec::new();
+            while str_vec.len() > 1 {
+                expect_token(&mut str_vec, "|", CREATE_SYNTAX)?;
+                let lexeme = next_token(&mut str_vec, CREATE_SYNTAX)?;
+                name_vec.push(lexeme.to_string());
+                let types = next_token(&mut str_vec, CREATE_SYNTAX)?;
+                match types.parse::<u8>() {
+                    Ok(x) if is_column_type(x) => {
+                        type_vec.push(x | nullable_token(&mut str_vec));
                     }
+                    _ => return Err(PlanckError::Syntax(CREATE_SYNTAX.to_string()))
+                }
+            }
+            expect_token(&mut str_vec, "|", CREATE_SYNTAX)?;
+            let mut pk = Vec::new();
+            for key_name in key_names.split(',') {
+                match name_vec.iter().position(|name| name == key_name) {
+                    Some(index) => pk.push(index as u8),
+                    None => return Err(PlanckError::SchemaMismatch(format!("key column {} is not one of the columns", key_name)))
                 }
+            }
 
-                return  Commands::Update(KeyRow { key: key_value, row: row }); 
-            },
-            "read" => {
-                if str_vec.len() < 3 {
-                    return Commands::SyntaxError("Syntax: read key -> 1 ".to_string());
+            Ok(Commands::Create(file.to_string(), pk, name_vec, type_vec))
+        },
+        "alter" => {
+            let file = next_token(&mut str_vec, ALTER_SYNTAX)?.to_string();
+            let alteration = match next_token(&mut str_vec, ALTER_SYNTAX)? {
+                "add" => {
+                    let name = next_token(&mut str_vec, ALTER_SYNTAX)?.to_string();
+                    match next_token(&mut str_vec, ALTER_SYNTAX)?.parse::<u8>() {
+                        Ok(x) if is_column_type(x) => AlterTable::AddColumn(name, x | nullable_token(&mut str_vec)),
+                        _ => return Err(PlanckError::Syntax(ALTER_SYNTAX.to_string()))
+                    }
                 }
-                let key_token = str_vec.remove(0);
-                if key_token != "key" {
-                    return Commands::SyntaxError("Syntax: read key -> 1 ".to_string());
+                "drop" => AlterTable::DropColumn(next_token(&mut str_vec, ALTER_SYNTAX)?.to_string()),
+                "rename" => {
+                    let name = next_token(&mut str_vec, ALTER_SYNTAX)?.to_string();
+                    AlterTable::RenameColumn(name, next_token(&mut str_vec, ALTER_SYNTAX)?.to_string())
                 }
-                if str_vec.remove(0) != "->" {
-                    return Commands::SyntaxError("Syntax: read key -> 1 ".to_string());
+                "rewrite" => return Ok(Commands::Rewrite(file)),
+                _ => return Err(PlanckError::Syntax(ALTER_SYNTAX.to_string()))
+            };
+            Ok(Commands::Alter(file, alteration))
+        },
+        "drop" => {
+            Ok(Commands::Drop(next_token(&mut str_vec, DROP_SYNTAX)?.to_string()))
+        },
+        "rename" => {
+            let file = next_token(&mut str_vec, RENAME_SYNTAX)?.to_string();
+            Ok(Commands::Rename(file, next_token(&mut str_vec, RENAME_SYNTAX)?.to_string()))
+        },
+        "show" => {
+            expect_token(&mut str_vec, "tables", SHOW_SYNTAX)?;
+            Ok(Commands::ShowTables())
+        },
+        "describe" => {
+            Ok(Commands::Describe(next_token(&mut str_vec, DESCRIBE_SYNTAX)?.to_string()))
+        },
+        "stats" => {
+            Ok(Commands::Stats(next_token(&mut str_vec, STATS_SYNTAX)?.to_string()))
+        },
+        "vacuum" => {
+            Ok(Commands::Vacuum())
+        },
+        "import" => {
+            let file = next_token(&mut str_vec, IMPORT_SYNTAX)?.to_string();
+            Ok(Commands::Import(file, next_token(&mut str_vec, IMPORT_SYNTAX)?.to_string()))
+        },
+        "open" => {
+            let file = next_token(&mut str_vec, "You need to specifiy the file/table name")?;
+            Ok(Commands::StartTransaction(file.to_string()))
+        },
+        "close" => {
+            Ok(Commands::CloseTransaction())
+        }
+        "insert" => {
+            expect_token(&mut str_vec, "row", INSERT_SYNTAX)?;
+            expect_token(&mut str_vec, "->", INSERT_SYNTAX)?;
+            Ok(Commands::Insert(parse_row(&mut str_vec, table, INSERT_SYNTAX)?))
+        },
+        "delete" => {
+            expect_token(&mut str_vec, "key", DELETE_SYNTAX)?;
+            expect_token(&mut str_vec, "->", DELETE_SYNTAX)?;
+            let key_value = parse_key(next_token(&mut str_vec, DELETE_SYNTAX)?, table)?;
+            Ok(Commands::Delete(key_value))
+        },
+        "update" => {
+            expect_token(&mut str_vec, "row", UPDATE_SYNTAX)?;
+            expect_token(&mut str_vec, "->", UPDATE_SYNTAX)?;
+            Ok(Commands::Update(parse_row(&mut str_vec, table, UPDATE_SYNTAX)?))
+        },
+        "read" => {
+            let prefix = match next_token(&mut str_vec, READ_SYNTAX)? {
+                "key" => false,
+                "prefix" => true,
+                _ => return Err(PlanckError::Syntax(READ_SYNTAX.to_string()))
+            };
+            expect_token(&mut str_vec, "->", READ_SYNTAX)?;
+            let lexeme = next_token(&mut str_vec, READ_SYNTAX)?;
+            // Only a range can be read backwards.
+            let descending = str_vec.first() == Some(&"desc");
+            if descending && (prefix || !lexeme.contains("..")) {
+                return Err(PlanckError::Syntax(READ_SYNTAX.to_string()));
+            }
+            match prefix {
+                true => Ok(Commands::ReadPrefix(parse_prefix(lexeme, table)?)),
+                false if lexeme.contains("..") => {
+                    let (start, end) = parse_range(lexeme, table)?;
+                    Ok(Commands::ReadRange(start, end, descending))
                 }
-                let key_value = identify_value(str_vec.remove(0));
-                return Commands::Read(key_value);
-            },
-            _ => return Commands::SyntaxError("Unknown Command token".to_string()),
-         }
-    };
-    return Commands::SyntaxError("Empty input string".to_string());
+                false => Ok(Commands::Read(parse_key(lexeme, table)?)),
+            }
+        },
+        _ => Err(PlanckError::Syntax("Unknown Command token".to_string())),
+    }
+}
+
+// A transaction works on a copy of the whole database file in the "tmp" directory,
+// closing it copies the file back over the main one.
+fn tmp_dir(db: &Database) -> PathBuf {
+    db.path().join("tmp")
 }
-pub fn transactions(file: &str, str: String,  trans: &mut bool) -> Response { 
-    let tmp_path = "PlanckDB/tmp.db";
 
-    let table = Table::read_table("tmp");
-    
-    let mut btree = BPlusTree::new(table);
+fn run_transaction(db: &mut Database, file: &str, str: String, trans: &mut Option<Database>) -> Result<Response, PlanckError> {
+    let tmp = match trans {
+        Some(tmp) => tmp,
+        None => return Err(PlanckError::NotFound("open transaction".to_string())),
+    };
+    let table = tmp.table(file)?.clone();
 
-    let command = parse(str, Some(&btree.buffer_pool.file.column_types));
+    let command = parse(str, Some(&table))?;
 
     match command {
         Commands::CloseTransaction() => {
-            fs::copy(tmp_path, format!("{}{}{}", "PlanckDB/", file, ".db")).unwrap();
-            *trans = false;
-            return Response::Query("Successfully saved the file and ended the transaction".to_string());
-        }
-        Commands::SyntaxError(x) => {
-            return Response::Error(x);
+            tmp.flush()?;
+            let tmp_file = tmp.file_path();
+            *trans = None;
+            db.flush()?;
+            fs::copy(tmp_file, db.file_path())?;
+            let dir = db.path().to_path_buf();
+            *db = Database::open(dir)?;
+            Ok(Response::Query("Successfully saved the file and ended the transaction".to_string()))
         }
         Commands::Delete(x) => {
-            delete(x, &mut btree);
-            return Response::Query("Tried to delete a row".to_string());
+            delete(tmp, file, x)?;
+            Ok(Response::Query("Deleted the row".to_string()))
         }
         Commands::Insert(x) => {
-            insert(x, &mut btree);
-            return Response::Query("Tried to insert a row".to_string());
+            insert(tmp, file, x)?;
+            Ok(Response::Query("Inserted the row".to_string()))
         }
         Commands::Update(x) => {
-            update(x, &mut btree);
-            return Response::Query("Tried to insert a row".to_string());
+            update(tmp, file, x)?;
+            Ok(Response::Query("Updated the row".to_string()))
         }
         Commands::Read(x) => {
-            let result = read(x, &mut btree);
-            match result {
-                Err(_) => {
-                    return Response::Error("Couldn't successfully find and read a row".to_string())
-                }
-                Ok(x) => {
-                    return Response::Return(Packet{table: btree.buffer_pool.file.clone(), keyrows: vec![x]});
-                }
+            let row = read(tmp, file, x)?;
+            Ok(Response::Return(Packet{table: tmp.table(file)?.clone(), keyrows: vec![row]}))
+        }
+        Commands::ReadPrefix(x) => {
+            let keyrows = read_prefix(tmp, file, &x)?;
+            Ok(Response::Return(Packet{table: tmp.table(file)?.clone(), keyrows}))
+        }
+        Commands::ReadRange(start, end, descending) => {
+            let keyrows = read_range(tmp, file, start, end, descending)?;
+            Ok(Response::Return(Packet{table: tmp.table(file)?.clone(), keyrows}))
+        }
+        _ => {
+            Ok(Response::Error("You cannot start a transaction or create while another is open".to_string()))
+        }
+    }
+}
+
+pub fn transactions(db: &mut Database, file: &str, str: String, trans: &mut Option<Database>) -> Response {
+    match run_transaction(db, file, str, trans) {
+        Ok(response) => response,
+        Err(err) => Response::Error(err.to_string())
+    }
+}
+
+fn run_command(db: &mut Database, file: &mut String, str: String, trans: &mut Option<Database>) -> Result<Response, PlanckError> {
+    match parse(str, None)? {
+        Commands::StartTransaction(x) => {
+            if !db.exists(&x)? {
+                return Err(PlanckError::NotFound(format!("table {}", x)));
             }
+            db.flush()?;
+            let dir = tmp_dir(db);
+            if dir.exists() {
+                fs::remove_dir_all(&dir)?;
+            }
+            fs::create_dir_all(&dir)?;
+            fs::copy(db.file_path(), dir.join(DATABASE_FILE))?;
+            *trans = Some(Database::open(dir)?);
+            *file = x;
+            Ok(Response::Query("Successfully opened the table".to_string()))
+        },
+        Commands::Create(file, pk, names, types) => {
+            create(db, &file, pk, names, types)?;
+            Ok(Response::Query("Successfully created the table".to_string()))
+        }
+        Commands::Alter(file, alteration) => {
+            alter(db, &file, alteration)?;
+            Ok(Response::Query("Successfully altered the table".to_string()))
+        }
+        Commands::Rewrite(file) => {
+            rewrite(db, &file)?;
+            Ok(Response::Query("Successfully rewrote the table".to_string()))
+        }
+        Commands::Drop(file) => {
+            drop_table(db, &file)?;
+            Ok(Response::Query("Successfully dropped the table".to_string()))
+        }
+        Commands::Rename(file, new_name) => {
+            rename_table(db, &file, &new_name)?;
+            Ok(Response::Query("Successfully renamed the table".to_string()))
+        }
+        Commands::ShowTables() => {
+            Ok(Response::Return(show_tables(db)?))
+        }
+        Commands::Describe(file) => {
+            Ok(Response::Describe(describe(db, &file)?))
+        }
+        Commands::Stats(file) => {
+            Ok(Response::Stats(stats(db, &file)?))
+        }
+        Commands::Vacuum() => {
+            vacuum(db)?;
+            Ok(Response::Query("Successfully vacuumed the database".to_string()))
+        }
+        Commands::Import(file, path) => {
+            let count = import(db, &file, &path)?;
+            Ok(Response::Query(format!("Successfully imported {} rows", count)))
         }
         _ => {
-            return Response::Error("You cannot start a transaction or create while another is open".to_string())
+            Ok(Response::Error("You need to open a transaction to edit DB".to_string()))
         }
     }
 }
-pub fn handle_client(mut stream: TcpStream) -> Result<(), Error>{
-    let mut transaction = false;
-    let mut file: String = "tmp".to_string();
+
+pub fn handle_client(db: &mut Database, mut stream: TcpStream) -> Result<(), Error>{
+    let mut transaction: Option<Database> = None;
+    let mut file: String = String::new();
     let mut buffer: [u8;4 ]= [0;4];
     loop {
         let mut payload: Vec<u8> = Vec::new();
@@ -251,79 +392,46 @@ pub fn handle_client(mut stream: TcpStream) -> Result<(), Error>{
         payload.resize(size as usize, 0);
         stream.read_exact(&mut payload)?;
 
-        let response = deserialize(&payload);
-
-        match response {
-            Response::Query(string) => {
-                if transaction {
-                    let response = transactions(&file, string,  &mut transaction);
-                    
-                    let bytes = serialize(response);
-
-                    stream.write_all(&bytes)?;
-                    
-                }
-                else {
-                    let commands = parse(string, None);
-                    match commands {
-                        Commands::CloseTransaction() => break,
-                        Commands::StartTransaction(x) => {
-                            file = x;
-                            transaction = true;
-                            let tmp_path = "PlanckDB/tmp.db";
-                            fs::copy(format!("{}{}{}", "PlanckDB/", file, ".db"), tmp_path).unwrap();
-                            let bytes = serialize(Response::Query("Successfully opened the table".to_string()));
-                            stream.write_all(&bytes).unwrap();
-                        }, 
-                        Commands::Create(file, pk, names, types) => {
-                            match create(&file, pk, names, types) {
-                                Ok(_) => {
-                                    let bytes = serialize(Response::Query("Successfully created the table".to_string()));
-                                    stream.write_all(&bytes).unwrap();
-                                }
-                                _ => {
-                                    let bytes = serialize(Response::Error("While creating the file an error occurred, table might exists".to_string()));
-                                    stream.write_all(&bytes).unwrap();
-                                }
-                            }
-                        }
-                        Commands::SyntaxError(x) => {
-                            let bytes = serialize(Response::Error(x));
-                            stream.write_all(&bytes)?;
-                        }
-                        _ => {
-                            let bytes = serialize(Response::Error("You need to open a transaction to edit DB".to_string()));
-                            stream.write_all(&bytes)?;
-                        }
+        let response = match deserialize(&payload) {
+            Ok(Response::Query(string)) => {
+                if transaction.is_some() {
+                    transactions(db, &file, string,  &mut transaction)
+                } else if let Ok(Commands::CloseTransaction()) = parse(string.clone(), None) {
+                    break;
+                } else {
+                    match run_command(db, &mut file, string, &mut transaction) {
+                        Ok(response) => response,
+                        Err(err) => Response::Error(err.to_string())
                     }
                 }
             },
-            _ => todo!()
-        }
+            Ok(_) => Response::Error("Only queries can be sent to the server".to_string()),
+            Err(err) => Response::Error(err.to_string())
+        };
+        stream.write_all(&serialize(response))?;
     }
-    return Ok(())
+    Ok(())
 }
 
 pub fn server() {
-    match fs::create_dir("PlanckDB") {
-        Ok(_) => {
-           
-        }
-        _ => {
-
+    let mut db = match Database::open("PlanckDB") {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("Couldn't open the PlanckDB directory: {}", err);
+            return;
         }
-    }
+    };
     let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
     println!("Listening at 127.0.0.1:7878");
-    
-    for stream in listener.incoming() {
-        let safety = handle_client(stream.unwrap());
-        match safety {
-            Ok(_) => {
 
-            }
-            _ => eprintln!("Some kind of an error happened during data stream ? ")
+    for stream in listener.incoming() {
+        let safety = match stream {
+            Ok(stream) => handle_client(&mut db, stream),
+            Err(err) => Err(err)
+        };
+        if safety.is_err() {
+            eprintln!("Some kind of an error happened during data stream ? ")
         }
     }
 
-}
\ No newline at end of file
+}
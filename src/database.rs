@@ -0,0 +1,1378 @@
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::dbengine::btrees::*;
+use crate::dbengine::engine::*;
+use crate::dbengine::pages::*;
+use crate::dbengine::storage::*;
+use crate::dbengine::error::PlanckError;
+
+// Handle to a PlanckDB data directory.
+// Every table lives in the single database file of the directory, the catalog B+ tree maps table names
+// to their schema and root page. The handle can be embedded in-process without going through the TCP server.
+#[derive(Debug)]
+pub struct Database {
+    dir: PathBuf,
+    storage: Storage,
+    pager: SharedPager,
+    catalog: BPlusTree,
+    // Older schemas of altered tables, keyed by "table/version".
+    schemas: BPlusTree,
+    trees: HashMap<String, BPlusTree>,
+}
+
+// Bulk loaded pages are left 10% empty, so that the first inserts after a load do not split every one of them.
+const BULK_LOAD_FILL: f64 = 0.9;
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() < 256 && !name.contains(|c: char| c.is_whitespace() || c == ',')
+}
+
+// The columns are kept as a single string in the catalog and the whole catalog row has to fit in a cell,
+// catalog and schema rows never use overflow pages.
+fn check_columns(table: &Table) -> Result<(), PlanckError> {
+    if let Some(types) = table.column_types.iter().find(|types| !is_column_type(**types)) {
+        return Err(PlanckError::SchemaMismatch(format!("unknown column type {}", types)));
+    }
+    if table.columns_string().len() > MAX_INLINE_VALUE || cell_size(&table.to_catalog_row()) - 2 > MAX_CELL_SIZE {
+        return Err(PlanckError::SchemaMismatch(format!("the columns of table {} do not fit in the catalog", table.name)));
+    }
+    Ok(())
+}
+
+impl Database {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Database, PlanckError> {
+        Database::open_with(path, Storage::File)
+    }
+
+    // A database that only lives as long as the handle, nothing is written to disk.
+    pub fn open_in_memory() -> Result<Database, PlanckError> {
+        Database::open_with(PathBuf::new(), Storage::Memory)
+    }
+
+    pub fn open_with<P: AsRef<Path>>(path: P, storage: Storage) -> Result<Database, PlanckError> {
+        let dir = path.as_ref().to_path_buf();
+        let backend: Box<dyn StorageBackend> = match storage {
+            Storage::File => Box::new(Database::file_storage(&dir)?),
+            Storage::Mmap => Box::new(MmapStorage::new(Database::file_storage(&dir)?)?),
+            Storage::Memory => Box::new(MemoryStorage::new()),
+        };
+        Database::with_backend(dir, storage, backend)
+    }
+
+    fn with_backend(dir: PathBuf, storage: Storage, mut backend: Box<dyn StorageBackend>) -> Result<Database, PlanckError> {
+        // A new database, its first pages become the roots of the catalog and of the schemas tree.
+        while backend.len() <= SCHEMAS_ROOT {
+            backend.allocate(Page::new_leaf().page_to_buff()?)?;
+        }
+        let pager: SharedPager = Arc::new(Mutex::new(backend));
+        let catalog = BPlusTree::new(pager.clone(), Table::catalog(CATALOG_ROOT));
+        let schemas = BPlusTree::new(pager.clone(), Table::schemas(SCHEMAS_ROOT));
+        Ok(Database { dir, storage, pager, catalog, schemas, trees: HashMap::new() })
+    }
+
+    fn file_storage(dir: &Path) -> Result<FileStorage, PlanckError> {
+        fs::create_dir_all(dir)?;
+        let file = dir.join(DATABASE_FILE);
+        if !file.exists() {
+            return FileStorage::create(&file);
+        }
+        FileStorage::migrate(&file)?;
+        if FileStorage::open_readable(&file)?.format_version != FORMAT_VERSION {
+            Database::rebuild(dir)?;
+        }
+        FileStorage::open(&file)
+    }
+
+    // The leaves of a version 12 or 13 file are not linked both ways, and the string keys of a version 12 file are
+    // ordered by length first. Its tables are copied into a new file like vacuum does and the new file is renamed
+    // over it, a crash leaves either the old file or the new one. The old file is only read, the buffer pool never
+    // writes to a file of an older version.
+    fn rebuild(dir: &Path) -> Result<(), PlanckError> {
+        let rebuild_dir = dir.join("rebuild");
+        if rebuild_dir.exists() {
+            fs::remove_dir_all(&rebuild_dir)?;
+        }
+        let old = FileStorage::open_readable(&dir.join(DATABASE_FILE))?;
+        let mut source = Database::with_backend(dir.to_path_buf(), Storage::File, Box::new(old))?;
+        let mut target = Database::open_with(&rebuild_dir, Storage::File)?;
+        source.copy_tables(&mut target)?;
+        drop(target);
+        drop(source);
+        fs::rename(rebuild_dir.join(DATABASE_FILE), dir.join(DATABASE_FILE))?;
+        fs::File::open(dir)?.sync_all()?;
+        fs::remove_dir_all(&rebuild_dir)?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    // Path of the database file holding every table.
+    pub fn file_path(&self) -> PathBuf {
+        self.dir.join(DATABASE_FILE)
+    }
+
+    pub fn exists(&mut self, name: &str) -> Result<bool, PlanckError> {
+        if self.trees.contains_key(name) {
+            return Ok(true);
+        }
+        Ok(valid_name(name) && self.catalog.get(&Value::string(name.to_string()))?.is_some())
+    }
+
+    // The primary key is the column at pk_index, rows are inserted whole and their key is read from that column.
+    pub fn create_table(&mut self, name: &str, pk_index: u8, field_names: Vec<String>, field_types: Vec<u8>) -> Result<(), PlanckError> {
+        let table = Table::new(name, pk_index, field_names, field_types, 0);
+        self.create(table)
+    }
+
+    // Creates a table whose key is made of several columns, e.g. `vec![0, 2]` for (tenant, timestamp).
+    // Keys are built with Value::composite and read_prefix returns every row whose key starts with the given values.
+    pub fn create_table_with_key(&mut self, name: &str, key_columns: Vec<u8>, field_names: Vec<String>, field_types: Vec<u8>) -> Result<(), PlanckError> {
+        let mut table = Table::new(name, key_columns.first().copied().unwrap_or(0), field_names, field_types, 0);
+        table.key_columns = key_columns;
+        if table.key_columns.len() < 2 {
+            return Err(PlanckError::SchemaMismatch(format!("the composite key of table {} needs at least two columns", name)));
+        }
+        self.create(table)
+    }
+
+    fn create(&mut self, mut table: Table) -> Result<(), PlanckError> {
+        let name = table.name.clone();
+        if !valid_name(&name) || name == CATALOG_NAME || name == SCHEMAS_NAME {
+            return Err(PlanckError::Syntax(format!("invalid table name {:?}", name)));
+        }
+        if let Some(column) = table.column_names.iter().find(|column| !valid_name(column)) {
+            return Err(PlanckError::Syntax(format!("invalid column name {:?}", column)));
+        }
+        if self.exists(&name)? {
+            return Err(PlanckError::TableExists(name));
+        }
+        check_columns(&table)?;
+        table.check_key_columns()?;
+        table.root_node_offset = lock(&self.pager).allocate(Page::new_leaf().page_to_buff()?)?;
+        self.catalog.insert(KeyRow { key: Value::string(name.clone()), row: table.to_catalog_row() })?;
+        self.catalog.buffer_pool.flush_all()?;
+        self.trees.insert(name, BPlusTree::new(self.pager.clone(), table));
+        Ok(())
+    }
+
+    // Returns the tree of an opened table, looking the table up in the catalog the first time it is used.
+    pub fn tree(&mut self, name: &str) -> Result<&mut BPlusTree, PlanckError> {
+        if !self.trees.contains_key(name) {
+            let entry = match valid_name(name) {
+                true => self.catalog.get(&Value::string(name.to_string()))?,
+                false => None,
+            };
+            let mut table = match entry {
+                Some(kr) => Table::from_catalog_row(name, &kr.row)?,
+                None => return Err(PlanckError::NotFound(format!("table {}", name))),
+            };
+            for version in 0..table.schema_version {
+                if let Some(kr) = self.schemas.get(&Value::string(table.schema_key(version)))? {
+                    table.add_old_schema(version, &kr.row)?;
+                }
+            }
+            self.trees.insert(name.to_string(), BPlusTree::new(self.pager.clone(), table));
+        }
+        Ok(self.trees.get_mut(name).unwrap())
+    }
+
+    pub fn table(&mut self, name: &str) -> Result<&Table, PlanckError> {
+        Ok(&self.tree(name)?.buffer_pool.file)
+    }
+
+    // Writes the catalog entry of an opened table back, e.g. after its row count changed.
+    fn save_table(&mut self, name: &str) -> Result<(), PlanckError> {
+        let row = self.tree(name)?.buffer_pool.file.to_catalog_row();
+        self.catalog.update(KeyRow { key: Value::string(name.to_string()), row })?;
+        self.catalog.buffer_pool.flush_all()
+    }
+
+    // Changes the columns of a table. Rows already on disk keep their old layout until their page is read
+    // (or the whole table is rewritten with rewrite_table), then they are upgraded to the new schema.
+    // Adding a column makes every row longer and a full page would not hold its upgraded rows, so the rows
+    // are copied into a new tree right away and the old pages are freed once the catalog points to it.
+    pub fn alter_table(&mut self, name: &str, alteration: AlterTable) -> Result<(), PlanckError> {
+        match &alteration {
+            AlterTable::AddColumn(column, _) | AlterTable::RenameColumn(_, column) if !valid_name(column) => {
+                return Err(PlanckError::Syntax(format!("invalid column name {:?}", column)));
+            }
+            _ => {}
+        }
+        // The pages cached for the table hold rows of the old schema, they are written back and dropped first.
+        self.close(name)?;
+        let btree = self.tree(name)?;
+        let old_tree = match alteration {
+            AlterTable::AddColumn(..) => Some((btree.scan_stubs()?, btree.page_ids()?)),
+            _ => None,
+        };
+        btree.buffer_pool.flush_all()?;
+        let mut table = btree.buffer_pool.file.clone();
+        self.trees.remove(name);
+        let old_version = table.schema_version;
+        let old_columns = table.columns_string();
+        table.alter(&alteration)?;
+        check_columns(&table)?;
+        if let Some((rows, _)) = &old_tree {
+            table.root_node_offset = lock(&self.pager).allocate(Page::new_leaf().page_to_buff()?)?;
+            let mut btree = BPlusTree::new(self.pager.clone(), table.clone());
+            let upgraded = rows.iter().cloned().map(|kr| Ok(KeyRow { key: kr.key, row: table.upgrade_row(old_version, kr.row)? }));
+            if let Err(err) = btree.bulk_load(upgraded, BULK_LOAD_FILL) {
+                // The loader frees the pages it wrote, the new root is freed here and the old tree stays in place.
+                btree.buffer_pool.remove_page(table.root_node_offset)?;
+                return Err(err);
+            }
+            table.tree_stats = btree.buffer_pool.file.tree_stats;
+        }
+        if table.schema_version != old_version {
+            let key = Value::string(table.schema_key(old_version));
+            self.schemas.insert(KeyRow { key, row: vec![Value::string(old_columns)] })?;
+            self.schemas.buffer_pool.flush_all()?;
+        }
+        self.catalog.update(KeyRow { key: Value::string(name.to_string()), row: table.to_catalog_row() })?;
+        self.catalog.buffer_pool.flush_all()?;
+        if let Some((_, page_ids)) = old_tree {
+            // The overflow chains now belong to the new tree, only the pages of the old one are freed.
+            let mut pager = lock(&self.pager);
+            pager.sync()?;
+            for page_id in page_ids {
+                pager.free(page_id)?;
+            }
+        }
+        self.trees.insert(name.to_string(), BPlusTree::new(self.pager.clone(), table));
+        Ok(())
+    }
+
+    // Upgrades every row of the table to the current schema right away.
+    pub fn rewrite_table(&mut self, name: &str) -> Result<(), PlanckError> {
+        let btree = self.tree(name)?;
+        btree.rewrite()?;
+        btree.buffer_pool.file.tree_stats = btree.stats()?.0;
+        self.save_table(name)
+    }
+
+    // Walks the tree of the table for its exact shape, the catalog is left as it is.
+    pub fn stats(&mut self, name: &str) -> Result<TableStats, PlanckError> {
+        let btree = self.tree(name)?;
+        let (tree, fill_histogram) = btree.stats()?;
+        let row_count = btree.buffer_pool.file.row_count;
+        let free_pages = lock(&self.pager).free_pages();
+        Ok(TableStats { name: name.to_string(), row_count, tree, free_pages, fill_histogram })
+    }
+
+    // Every table of the database in name order.
+    pub fn tables(&mut self) -> Result<Vec<Table>, PlanckError> {
+        let mut tables = Vec::new();
+        for kr in self.catalog.scan()? {
+            match kr.key {
+                Value::String(_, name) => tables.push(Table::from_catalog_row(&name, &kr.row)?),
+                _ => return Err(PlanckError::Corruption("catalog key is not a table name".to_string()))
+            }
+        }
+        // Opened tables may have newer row counts than the catalog.
+        for table in tables.iter_mut() {
+            if let Some(btree) = self.trees.get(&table.name) {
+                *table = btree.buffer_pool.file.clone();
+            }
+        }
+        // The catalog of a file that is being rebuilt may still be in the order of its format version.
+        tables.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(tables)
+    }
+
+    // Number of pages the table uses, its B+ tree and its overflow pages.
+    pub fn page_count(&mut self, name: &str) -> Result<u32, PlanckError> {
+        let btree = self.tree(name)?;
+        Ok((btree.page_ids()?.len() + btree.overflow_page_ids()?.len()) as u32)
+    }
+
+    // Removes the table from the catalog first and only then frees its pages, a crash in between
+    // leaks some pages but never leaves the catalog pointing to free pages.
+    pub fn drop_table(&mut self, name: &str) -> Result<(), PlanckError> {
+        self.close(name)?;
+        let schema_version = self.tree(name)?.buffer_pool.file.schema_version;
+        let mut btree = self.trees.remove(name).unwrap();
+        let page_ids = btree.page_ids()?;
+        let overflow_ids = btree.overflow_page_ids()?;
+
+        self.catalog.delete(Value::string(name.to_string()))?;
+        self.catalog.buffer_pool.flush_all()?;
+        for version in 0..schema_version {
+            let key = Value::string(btree.buffer_pool.file.schema_key(version));
+            if self.schemas.get(&key)?.is_some() {
+                self.schemas.delete(key)?;
+            }
+        }
+        self.schemas.buffer_pool.flush_all()?;
+        lock(&self.pager).sync()?;
+
+        for page_id in page_ids {
+            btree.buffer_pool.remove_page(page_id)?;
+        }
+        let mut pager = lock(&self.pager);
+        for page_id in overflow_ids {
+            pager.free(page_id)?;
+        }
+        Ok(())
+    }
+
+    pub fn rename_table(&mut self, name: &str, new_name: &str) -> Result<(), PlanckError> {
+        if !valid_name(new_name) || new_name == CATALOG_NAME || new_name == SCHEMAS_NAME {
+            return Err(PlanckError::Syntax(format!("invalid table name {:?}", new_name)));
+        }
+        if self.exists(new_name)? {
+            return Err(PlanckError::TableExists(new_name.to_string()));
+        }
+        self.close(name)?;
+        let mut table = self.tree(name)?.buffer_pool.file.clone();
+        self.trees.remove(name);
+
+        let old_keys: Vec<String> = (0..table.schema_version).map(|version| table.schema_key(version)).collect();
+        table.name = new_name.to_string();
+        self.catalog.insert(KeyRow { key: Value::string(new_name.to_string()), row: table.to_catalog_row() })?;
+        self.catalog.delete(Value::string(name.to_string()))?;
+        for (version, old_key) in old_keys.into_iter().enumerate() {
+            let old_key = Value::string(old_key);
+            if let Some(kr) = self.schemas.get(&old_key)? {
+                self.schemas.insert(KeyRow { key: Value::string(table.schema_key(version as u16)), row: kr.row })?;
+                self.schemas.delete(old_key)?;
+            }
+        }
+        self.catalog.buffer_pool.flush_all()?;
+        self.schemas.buffer_pool.flush_all()
+    }
+
+    // Rebuilds every table into a new database file and swaps it in, so pages freed by deletes and drops
+    // are given back to the file system. The new file has no free pages and no rows of older schemas.
+    pub fn vacuum(&mut self) -> Result<(), PlanckError> {
+        self.flush()?;
+        let vacuum_dir = self.dir.join("vacuum");
+        let target_storage = match self.storage {
+            Storage::Memory => Storage::Memory,
+            _ => {
+                // Leftovers of a vacuum that did not finish, the main file was never touched by it.
+                if vacuum_dir.exists() {
+                    fs::remove_dir_all(&vacuum_dir)?;
+                }
+                Storage::File
+            }
+        };
+        let mut target = Database::open_with(&vacuum_dir, target_storage)?;
+        self.copy_tables(&mut target)?;
+
+        if self.storage == Storage::Memory {
+            target.dir = self.dir.clone();
+            *self = target;
+            return Ok(());
+        }
+        drop(target);
+        // rename is atomic, a crash leaves either the old file or the new one in place.
+        fs::rename(vacuum_dir.join(DATABASE_FILE), self.file_path())?;
+        fs::File::open(&self.dir)?.sync_all()?;
+        fs::remove_dir_all(&vacuum_dir)?;
+        *self = Database::open_with(&self.dir, self.storage)?;
+        Ok(())
+    }
+
+    // Bulk loads every row of every table into an empty database, with the current schema of each table.
+    // The catalog and the schemas tree of a file being rebuilt may be ordered in another way than keys compare
+    // now, so they are only read in full and never searched, and the rows of every table are sorted again.
+    fn copy_tables(&mut self, target: &mut Database) -> Result<(), PlanckError> {
+        let mut old_schemas = HashMap::new();
+        for kr in self.schemas.scan()? {
+            if let Value::String(_, key) = kr.key {
+                old_schemas.insert(key, kr.row);
+            }
+        }
+        for mut table in self.tables()? {
+            for version in 0..table.schema_version {
+                if let Some(row) = old_schemas.get(&table.schema_key(version)) {
+                    table.add_old_schema(version, row)?;
+                }
+            }
+            let name = table.name.clone();
+            let mut rows = BPlusTree::new(self.pager.clone(), table.clone()).scan()?;
+            rows.sort_by(|a, b| a.key.cmp(&b.key));
+            table.old_schemas.clear();
+            let mut root = Page::new_leaf();
+            root.schema_version = table.schema_version;
+            table.root_node_offset = lock(&target.pager).allocate(root.page_to_buff()?)?;
+
+            let mut new_tree = BPlusTree::new(target.pager.clone(), table.clone());
+            table.row_count = new_tree.bulk_load(rows.into_iter().map(Ok), BULK_LOAD_FILL)?;
+            table.tree_stats = new_tree.buffer_pool.file.tree_stats;
+            target.catalog.insert(KeyRow { key: Value::string(name), row: table.to_catalog_row() })?;
+        }
+        target.flush()
+    }
+
+    // Inserts a whole row, its key is taken from the key columns.
+    pub fn insert(&mut self, name: &str, row: Vec<Value>) -> Result<(), PlanckError> {
+        let btree = self.tree(name)?;
+        btree.buffer_pool.file.check_row(&row)?;
+        let key = btree.buffer_pool.file.key_of(&row)?;
+        btree.insert(KeyRow { key, row })?;
+        btree.buffer_pool.file.row_count += 1;
+        btree.buffer_pool.flush_all()?;
+        self.save_table(name)
+    }
+
+    // Loads rows sorted by key into an empty table, much faster than inserting them one by one
+    // (see BPlusTree::bulk_load). The rows may be read as they are loaded, the first error of the input
+    // stops the load and leaves the table empty. Returns how many rows were loaded.
+    pub fn bulk_load<I: IntoIterator<Item = Result<Vec<Value>, PlanckError>>>(&mut self, name: &str, rows: I) -> Result<u32, PlanckError> {
+        let btree = self.tree(name)?;
+        let table = btree.buffer_pool.file.clone();
+        let rows = rows.into_iter().map(|row| {
+            let row = row?;
+            table.check_row(&row)?;
+            Ok(KeyRow { key: table.key_of(&row)?, row })
+        });
+        let count = btree.bulk_load(rows, BULK_LOAD_FILL)?;
+        btree.buffer_pool.file.row_count = count;
+        self.save_table(name)?;
+        Ok(count)
+    }
+
+    pub fn delete(&mut self, name: &str, key: Value) -> Result<(), PlanckError> {
+        let btree = self.tree(name)?;
+        btree.buffer_pool.file.check_key(&key)?;
+        btree.delete(key)?;
+        btree.buffer_pool.file.row_count -= 1;
+        btree.buffer_pool.flush_all()?;
+        self.save_table(name)
+    }
+
+    // Replaces the row with the same key, the key columns cannot change.
+    pub fn update(&mut self, name: &str, row: Vec<Value>) -> Result<(), PlanckError> {
+        let btree = self.tree(name)?;
+        btree.buffer_pool.file.check_row(&row)?;
+        let key = btree.buffer_pool.file.key_of(&row)?;
+        let before = btree.buffer_pool.file.tree_stats;
+        btree.update(KeyRow { key, row })?;
+        btree.buffer_pool.flush_all()?;
+        // A longer row may split its leaf.
+        if btree.buffer_pool.file.tree_stats != before {
+            self.save_table(name)?;
+        }
+        Ok(())
+    }
+
+    pub fn read(&mut self, name: &str, key: Value) -> Result<KeyRow, PlanckError> {
+        let btree = self.tree(name)?;
+        btree.buffer_pool.file.check_key(&key)?;
+        match btree.get(&key)? {
+            Some(kr) => Ok(kr),
+            None => Err(PlanckError::NotFound(format!("key {}", key)))
+        }
+    }
+
+    // Every row of a table with a composite key whose key starts with the given values, in key order.
+    // E.g. `read_prefix("events", &[Value::Number(42)])` for all the events of tenant 42.
+    pub fn read_prefix(&mut self, name: &str, prefix: &[Value]) -> Result<Vec<KeyRow>, PlanckError> {
+        let btree = self.tree(name)?;
+        btree.buffer_pool.file.check_key_prefix(prefix)?;
+        btree.scan_prefix(&Value::composite(prefix)?)
+    }
+
+    // Rows whose key lies between the bounds, in key order.
+    // E.g. `read_range("users", Bound::Included(Value::Number(10)), Bound::Excluded(Value::Number(200)))`.
+    pub fn read_range(&mut self, name: &str, start: Bound<Value>, end: Bound<Value>) -> Result<Vec<KeyRow>, PlanckError> {
+        self.range_tree(name, &start, &end)?.range(start, end)?.collect()
+    }
+
+    // The rows of read_range from the largest key down. `BPlusTree::range_desc` reads only as many as it is asked
+    // for, e.g. `db.tree("events")?.range_desc(start, end)?.take(10)` for the latest ten events.
+    pub fn read_range_desc(&mut self, name: &str, start: Bound<Value>, end: Bound<Value>) -> Result<Vec<KeyRow>, PlanckError> {
+        self.range_tree(name, &start, &end)?.range_desc(start, end)?.collect()
+    }
+
+    fn range_tree(&mut self, name: &str, start: &Bound<Value>, end: &Bound<Value>) -> Result<&mut BPlusTree, PlanckError> {
+        let btree = self.tree(name)?;
+        for bound in [start, end] {
+            if let Bound::Included(key) | Bound::Excluded(key) = bound {
+                btree.buffer_pool.file.check_key(key)?;
+            }
+        }
+        Ok(btree)
+    }
+
+    // Flushes and forgets an opened table, the next access reads it from the catalog again.
+    pub fn close(&mut self, name: &str) -> Result<(), PlanckError> {
+        if let Some(mut btree) = self.trees.remove(name) {
+            btree.buffer_pool.flush_all()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), PlanckError> {
+        for btree in self.trees.values_mut() {
+            btree.buffer_pool.flush_all()?;
+        }
+        self.catalog.buffer_pool.flush_all()?;
+        self.schemas.buffer_pool.flush_all()?;
+        lock(&self.pager).sync()
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::RangeBounds;
+    use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+    use crate::dbengine::checker::check_database;
+
+    // A fresh directory for a test that needs a real file.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("planck_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn users() -> Database {
+        users_in(Database::open_in_memory().unwrap())
+    }
+
+    fn users_in(mut db: Database) -> Database {
+        db.create_table("users", 0, vec!["id".to_string(), "name".to_string()], vec![2, 7]).unwrap();
+        db
+    }
+
+    fn user(id: u32, name_len: usize) -> Vec<Value> {
+        vec![Value::Number(id), Value::string("n".repeat(name_len))]
+    }
+
+    fn free_pages(db: &Database) -> u32 {
+        lock(&db.pager).free_pages()
+    }
+
+    fn ids(db: &mut Database) -> Vec<u32> {
+        db.read_range("users", Bound::Unbounded, Bound::Unbounded).unwrap().into_iter()
+            .map(|kr| kr.key.extract_pointer().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn insert_read_update_delete() {
+        let mut db = users();
+        db.insert("users", user(1, 5)).unwrap();
+        db.insert("users", user(2, 7)).unwrap();
+        assert_eq!(db.read("users", Value::Number(1)).unwrap().row, user(1, 5));
+
+        db.update("users", user(1, 9)).unwrap();
+        assert_eq!(db.read("users", Value::Number(1)).unwrap().row, user(1, 9));
+
+        db.delete("users", Value::Number(1)).unwrap();
+        assert!(matches!(db.read("users", Value::Number(1)), Err(PlanckError::NotFound(_))));
+        assert_eq!(db.table("users").unwrap().row_count, 1);
+    }
+
+    // Memory pages that run out when `full` is set.
+    #[derive(Debug)]
+    struct LimitedStorage {
+        pages: MemoryStorage,
+        full: Arc<AtomicBool>,
+    }
+
+    impl StorageBackend for LimitedStorage {
+        fn read_page(&self, page_id: u32) -> Result<[u8; PAGE_SIZE], PlanckError> {
+            self.pages.read_page(page_id)
+        }
+        fn write_page(&mut self, buffer: [u8; PAGE_SIZE], page_id: u32) -> Result<(), PlanckError> {
+            self.pages.write_page(buffer, page_id)
+        }
+        fn allocate(&mut self, buffer: [u8; PAGE_SIZE]) -> Result<u32, PlanckError> {
+            match self.full.load(AtomicOrdering::SeqCst) {
+                true => Err(PlanckError::Io(std::io::Error::other("no space left"))),
+                false => self.pages.allocate(buffer),
+            }
+        }
+        fn free(&mut self, page_id: u32) -> Result<(), PlanckError> {
+            self.pages.free(page_id)
+        }
+        fn sync(&mut self) -> Result<(), PlanckError> {
+            self.pages.sync()
+        }
+        fn len(&self) -> u32 {
+            self.pages.len()
+        }
+        fn free_pages(&self) -> u32 {
+            self.pages.free_pages()
+        }
+    }
+
+    #[test]
+    fn update_that_cannot_split_keeps_the_old_row() {
+        let full = Arc::new(AtomicBool::new(false));
+        let storage = LimitedStorage { pages: MemoryStorage::new(), full: full.clone() };
+        let mut db = users_in(Database::with_backend(PathBuf::new(), Storage::Memory, Box::new(storage)).unwrap());
+        // A leaf with less room left than the update needs.
+        let mut id = 0;
+        while db.tree("users").unwrap().stats().unwrap().0.bytes_used + 130 < PAGE_SIZE as u32 {
+            db.insert("users", user(id, 100)).unwrap();
+            id += 1;
+        }
+        full.store(true, AtomicOrdering::SeqCst);
+        assert!(matches!(db.update("users", user(0, 250)), Err(PlanckError::Io(_))));
+        full.store(false, AtomicOrdering::SeqCst);
+        assert_eq!(db.read("users", Value::Number(0)).unwrap().row, user(0, 100));
+        assert_eq!(ids(&mut db), (0..id).collect::<Vec<u32>>());
+        db.update("users", user(0, 250)).unwrap();
+        assert_eq!(db.read("users", Value::Number(0)).unwrap().row, user(0, 250));
+        assert_eq!(ids(&mut db), (0..id).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn duplicate_and_missing_keys_are_errors() {
+        let mut db = users();
+        db.insert("users", user(1, 5)).unwrap();
+        assert!(matches!(db.insert("users", user(1, 6)), Err(PlanckError::DuplicateKey(_))));
+        assert!(matches!(db.update("users", user(2, 6)), Err(PlanckError::NotFound(_))));
+        assert!(matches!(db.delete("users", Value::Number(2)), Err(PlanckError::NotFound(_))));
+        assert!(matches!(db.insert("missing", user(1, 5)), Err(PlanckError::NotFound(_))));
+        assert_eq!(db.read("users", Value::Number(1)).unwrap().row, user(1, 5));
+    }
+
+    #[test]
+    fn key_column_values_are_type_checked() {
+        let mut db = users();
+        db.insert("users", user(1, 5)).unwrap();
+        assert!(matches!(db.insert("users", vec![Value::string("2".to_string()), Value::string("n".to_string())]), Err(PlanckError::SchemaMismatch(_))));
+        assert!(matches!(db.update("users", vec![Value::Int64(1), Value::string("n".to_string())]), Err(PlanckError::SchemaMismatch(_))));
+        // The key comes back as part of the row.
+        assert_eq!(db.read("users", Value::Number(1)).unwrap().row[0], Value::Number(1));
+        assert_eq!(db.table("users").unwrap().row_count, 1);
+    }
+
+    #[test]
+    fn files_with_a_bad_header_are_refused() {
+        let dir = temp_dir("header");
+        drop(users_in(Database::open(&dir).unwrap()));
+        let path = dir.join(DATABASE_FILE);
+        let good = fs::read(&path).unwrap();
+        let mut bytes = good.clone();
+        bytes[0..8].copy_from_slice(b"NOTADB!!");
+        fs::write(&path, &bytes).unwrap();
+        assert!(matches!(Database::open(&dir), Err(PlanckError::UnsupportedFormat(_))));
+        bytes = good.clone();
+        bytes[8..10].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        fs::write(&path, &bytes).unwrap();
+        assert!(matches!(Database::open(&dir), Err(PlanckError::UnsupportedFormat(_))));
+        // A reserved byte that no longer matches the header checksum.
+        bytes = good.clone();
+        bytes[20] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        assert!(matches!(Database::open(&dir), Err(PlanckError::Corruption(_))));
+        fs::write(&path, &good).unwrap();
+        assert_eq!(Database::open(&dir).unwrap().table("users").unwrap().column_names, vec!["id".to_string(), "name".to_string()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pages_with_a_bad_checksum_are_refused() {
+        let dir = temp_dir("page_checksum");
+        let mut db = users_in(Database::open(&dir).unwrap());
+        db.insert("users", user(1, 5)).unwrap();
+        let root = db.table("users").unwrap().root_node_offset;
+        drop(db);
+        let path = dir.join(DATABASE_FILE);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[HEADER_SIZE + root as usize * PAGE_SIZE + PAGE_SIZE - 1] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        let mut db = Database::open(&dir).unwrap();
+        match db.read("users", Value::Number(1)) {
+            Err(PlanckError::ChecksumMismatch { table, page_id }) => assert_eq!((table.as_str(), page_id), ("users", root)),
+            other => panic!("expected a checksum mismatch, got {:?}", other),
+        }
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn freed_pages_are_reused_before_the_file_grows() {
+        let dir = temp_dir("free_list");
+        let mut db = users_in(Database::open(&dir).unwrap());
+        for id in 0..5000 {
+            db.insert("users", user(id, 100)).unwrap();
+        }
+        for id in 0..5000 {
+            if id % 100 != 0 {
+                db.delete("users", Value::Number(id)).unwrap();
+            }
+        }
+        db.flush().unwrap();
+        let path = dir.join(DATABASE_FILE);
+        let len = fs::metadata(&path).unwrap().len();
+        let free = free_pages(&db);
+        // More than the 100 pages the header had room for.
+        assert!(free > 100);
+        for id in 1..2500 {
+            if id % 100 != 0 {
+                db.insert("users", user(id, 100)).unwrap();
+            }
+        }
+        db.flush().unwrap();
+        assert!(free_pages(&db) < free);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        drop(db);
+        assert!(check_database(&dir).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tables_share_one_file() {
+        let dir = temp_dir("one_file");
+        let mut db = users_in(Database::open(&dir).unwrap());
+        db.create_table("items", 0, vec!["id".to_string(), "label".to_string()], vec![2, 7]).unwrap();
+        for id in 0..300 {
+            db.insert("users", user(id, 50)).unwrap();
+            db.insert("items", vec![Value::Number(id), Value::string(format!("item{}", id))]).unwrap();
+        }
+        drop(db);
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(files, vec![DATABASE_FILE]);
+        let mut db = Database::open(&dir).unwrap();
+        for id in 0..300 {
+            assert_eq!(db.read("users", Value::Number(id)).unwrap().row, user(id, 50));
+            assert_eq!(db.read("items", Value::Number(id)).unwrap().row[1], Value::string(format!("item{}", id)));
+        }
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tables_are_renamed_described_and_dropped() {
+        let dir = temp_dir("catalog");
+        let mut db = users_in(Database::open(&dir).unwrap());
+        for id in 0..3 {
+            db.insert("users", user(id, 5)).unwrap();
+        }
+        db.rename_table("users", "people").unwrap();
+        assert!(matches!(db.read("users", Value::Number(1)), Err(PlanckError::NotFound(_))));
+        assert_eq!(db.read("people", Value::Number(1)).unwrap().row, user(1, 5));
+        drop(db);
+
+        let mut db = Database::open(&dir).unwrap();
+        let people = db.table("people").unwrap();
+        assert_eq!((people.column_names.clone(), people.column_types.clone()), (vec!["id".to_string(), "name".to_string()], vec![2, 7]));
+        assert_eq!((people.pk_column, people.row_count), (0, 3));
+        assert_eq!(db.page_count("people").unwrap(), 1);
+        assert_eq!(db.tables().unwrap().iter().map(|table| table.name.clone()).collect::<Vec<String>>(), vec!["people".to_string()]);
+        let free = free_pages(&db);
+        db.drop_table("people").unwrap();
+        assert!(!db.exists("people").unwrap());
+        assert!(free_pages(&db) > free);
+        drop(db);
+        assert!(Database::open(&dir).unwrap().tables().unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // The page counts and height kept up to date by splits and merges match a walk of the tree.
+    fn assert_shape_kept(db: &mut Database) {
+        let kept = db.table("users").unwrap().tree_stats;
+        let walked = db.stats("users").unwrap().tree;
+        assert_eq!((kept.leaf_pages, kept.internal_pages, kept.height), (walked.leaf_pages, walked.internal_pages, walked.height));
+    }
+
+    #[test]
+    fn pages_split_and_merge() {
+        let mut db = users();
+        // 7919 is prime, the keys arrive out of order and most inserts split a page in the middle of the tree.
+        for index in 0..2000 {
+            db.insert("users", user(index * 7919 % 2000, 100)).unwrap();
+        }
+        let stats = db.stats("users").unwrap();
+        assert_eq!(stats.tree.height, 2);
+        assert!(stats.tree.leaf_pages > 50);
+        assert_shape_kept(&mut db);
+        assert_eq!(ids(&mut db), (0..2000).collect::<Vec<u32>>());
+
+        for index in 0..2000 {
+            let id = index * 7919 % 2000;
+            if id % 50 != 0 {
+                db.delete("users", Value::Number(id)).unwrap();
+            }
+        }
+        let merged = db.stats("users").unwrap();
+        assert!(merged.tree.leaf_pages < stats.tree.leaf_pages / 10);
+        assert_shape_kept(&mut db);
+        assert_eq!(ids(&mut db), (0..2000).step_by(50).collect::<Vec<u32>>());
+        for id in (0..2000).step_by(50) {
+            assert_eq!(db.read("users", Value::Number(id)).unwrap().row, user(id, 100));
+        }
+
+        for id in (0..2000).step_by(50) {
+            db.delete("users", Value::Number(id)).unwrap();
+        }
+        let empty = db.stats("users").unwrap();
+        assert_eq!((empty.tree.height, empty.tree.leaf_pages, empty.row_count), (1, 1, 0));
+        assert_shape_kept(&mut db);
+    }
+
+    #[test]
+    fn stats_are_kept_in_the_catalog_but_not_written_by_reading_them() {
+        let dir = temp_dir("stats");
+        let mut db = users_in(Database::open(&dir).unwrap());
+        for id in 0..500 {
+            db.insert("users", user(id, 100)).unwrap();
+        }
+        // Growing rows split their leaves too.
+        for id in 0..500 {
+            db.update("users", user(id, 300)).unwrap();
+        }
+        let walked = db.stats("users").unwrap().tree;
+        drop(db);
+
+        let mut db = Database::open(&dir).unwrap();
+        let kept = db.table("users").unwrap().tree_stats;
+        assert_eq!((kept.leaf_pages, kept.internal_pages, kept.height), (walked.leaf_pages, walked.internal_pages, walked.height));
+        // The byte counts are the snapshot of the new table, the stats call above did not save the walked ones.
+        assert_eq!(kept.bytes_used, TreeStats::single_leaf().bytes_used);
+        db.rewrite_table("users").unwrap();
+        assert_eq!(db.table("users").unwrap().tree_stats, walked);
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn in_memory_databases_are_separate() {
+        let mut db = users();
+        db.insert("users", user(1, 5)).unwrap();
+        let mut other = Database::open_in_memory().unwrap();
+        assert!(!other.exists("users").unwrap());
+    }
+
+    #[test]
+    fn mmap_storage_reads_what_was_written() {
+        let dir = temp_dir("mmap_db");
+        let mut db = users_in(Database::open_with(&dir, Storage::Mmap).unwrap());
+        for id in 0..3000 {
+            db.insert("users", user(id, 60)).unwrap();
+        }
+        drop(db);
+        let mut db = Database::open_with(&dir, Storage::Mmap).unwrap();
+        for id in 0..3000 {
+            assert_eq!(db.read("users", Value::Number(id)).unwrap().row, user(id, 60));
+        }
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bulk_load_builds_the_tree_of_sorted_rows() {
+        let dir = temp_dir("bulk_load");
+        let mut db = users_in(Database::open(&dir).unwrap());
+        let count = db.bulk_load("users", (0..3000).map(|id| Ok(user(id * 2, 60)))).unwrap();
+        assert_eq!(count, 3000);
+        assert_eq!(db.table("users").unwrap().row_count, 3000);
+        assert_eq!(ids(&mut db), (0..3000).map(|id| id * 2).collect::<Vec<u32>>());
+        let stats = db.stats("users").unwrap().tree;
+        assert!(stats.height > 1);
+        // The loaded tree takes inserts and deletes like any other.
+        db.insert("users", user(4001, 60)).unwrap();
+        db.delete("users", Value::Number(0)).unwrap();
+        assert_eq!(db.read("users", Value::Number(4001)).unwrap().row, user(4001, 60));
+        assert!(db.read("users", Value::Number(0)).is_err());
+        // A table that is not empty cannot be loaded.
+        assert!(matches!(db.bulk_load("users", vec![Ok(user(1, 60))]), Err(PlanckError::SchemaMismatch(_))));
+        drop(db);
+        assert!(check_database(&dir).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bulk_load_fill_factor_leaves_room_in_the_leaves() {
+        let mut db = users();
+        db.create_table("half", 0, vec!["id".to_string(), "name".to_string()], vec![NUMBER_TYPE, STRING_TYPE]).unwrap();
+        let rows = || (0..2000).map(|id| Ok(KeyRow { key: Value::Number(id), row: user(id, 60) }));
+        db.tree("users").unwrap().bulk_load(rows(), 1.0).unwrap();
+        db.tree("half").unwrap().bulk_load(rows(), 0.5).unwrap();
+        let full_leaves = db.tree("users").unwrap().buffer_pool.file.tree_stats.leaf_pages;
+        let half_leaves = db.tree("half").unwrap().buffer_pool.file.tree_stats.leaf_pages;
+        assert!(half_leaves >= full_leaves * 2 - 1, "{} leaves at 0.5, {} at 1", half_leaves, full_leaves);
+        assert!(matches!(db.tree("half").unwrap().bulk_load(Vec::new(), 0.3), Err(PlanckError::Syntax(_))));
+    }
+
+    #[test]
+    fn bulk_load_rejects_unsorted_and_repeated_keys() {
+        let dir = temp_dir("bulk_load_errors");
+        let mut db = users_in(Database::open(&dir).unwrap());
+        // Errors far enough in to have written leaves, and rows on overflow pages, that must all be freed again.
+        let sorted = |id: u32| Ok(user(id, if id.is_multiple_of(100) { 6000 } else { 60 }));
+        let out_of_order = (0..1000).chain([500]).chain(1000..1100).map(sorted);
+        assert!(matches!(db.bulk_load("users", out_of_order), Err(PlanckError::SchemaMismatch(_))));
+        let repeated = (0..1000).chain(999..1100).map(sorted);
+        assert!(matches!(db.bulk_load("users", repeated), Err(PlanckError::DuplicateKey(_))));
+        let failing = (0..1000).map(sorted).chain([Err(PlanckError::Syntax("line 1001".to_string()))]);
+        assert!(matches!(db.bulk_load("users", failing), Err(PlanckError::Syntax(_))));
+        assert!(matches!(db.bulk_load("users", vec![Ok(vec![Value::Number(1)])]), Err(PlanckError::SchemaMismatch(_))));
+        assert_eq!(db.table("users").unwrap().row_count, 0);
+        assert!(ids(&mut db).is_empty());
+        assert_eq!(db.bulk_load("users", (0..1100).map(sorted)).unwrap(), 1100);
+        drop(db);
+        assert!(check_database(&dir).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_bulk_load_keeps_the_overflow_chains_of_its_input() {
+        let mut db = users();
+        for id in 0..20 {
+            db.insert("users", user(id, 6000)).unwrap();
+        }
+        let stubs = db.tree("users").unwrap().scan_stubs().unwrap();
+        db.create_table("copy", 0, vec!["id".to_string(), "name".to_string()], vec![NUMBER_TYPE, STRING_TYPE]).unwrap();
+        let free_before = lock(&db.pager).free_pages();
+        // The stubs point to the chains of users, a key out of order stops the load halfway.
+        let mut rows: Vec<_> = stubs.into_iter().map(Ok).collect();
+        rows.push(Ok(KeyRow { key: Value::Number(0), row: user(0, 10) }));
+        let err = db.tree("copy").unwrap().bulk_load(rows, 1.0).unwrap_err();
+        assert!(matches!(err, PlanckError::SchemaMismatch(_)));
+        assert_eq!(lock(&db.pager).free_pages(), free_before);
+        assert_eq!(ids(&mut db), (0..20).collect::<Vec<u32>>());
+        assert_eq!(db.read("users", Value::Number(7)).unwrap().row, user(7, 6000));
+    }
+
+    #[test]
+    fn altered_tables_upgrade_their_rows_when_read() {
+        let dir = temp_dir("alter");
+        let mut db = users_in(Database::open(&dir).unwrap());
+        for id in 0..50 {
+            db.insert("users", user(id, 10)).unwrap();
+        }
+        db.alter_table("users", AlterTable::AddColumn("age".to_string(), 2)).unwrap();
+        db.alter_table("users", AlterTable::DropColumn("name".to_string())).unwrap();
+        db.alter_table("users", AlterTable::RenameColumn("age".to_string(), "years".to_string())).unwrap();
+        db.insert("users", vec![Value::Number(50), Value::Number(3)]).unwrap();
+        drop(db);
+
+        let mut db = Database::open(&dir).unwrap();
+        assert_eq!(db.table("users").unwrap().column_names, vec!["id".to_string(), "years".to_string()]);
+        assert_eq!(db.read("users", Value::Number(7)).unwrap().row, vec![Value::Number(7), Value::Number(0)]);
+        assert_eq!(db.read("users", Value::Number(50)).unwrap().row, vec![Value::Number(50), Value::Number(3)]);
+        db.rewrite_table("users").unwrap();
+        drop(db);
+        let mut db = Database::open(&dir).unwrap();
+        for id in 0..50 {
+            assert_eq!(db.read("users", Value::Number(id)).unwrap().row, vec![Value::Number(id), Value::Number(0)]);
+        }
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn vacuum_shrinks_the_file() {
+        let dir = temp_dir("vacuum");
+        let mut db = users_in(Database::open(&dir).unwrap());
+        for id in 0..2000 {
+            db.insert("users", user(id, 100)).unwrap();
+        }
+        for id in 0..2000 {
+            if id % 10 != 0 {
+                db.delete("users", Value::Number(id)).unwrap();
+            }
+        }
+        db.flush().unwrap();
+        let len = fs::metadata(dir.join(DATABASE_FILE)).unwrap().len();
+        db.vacuum().unwrap();
+        assert!(fs::metadata(dir.join(DATABASE_FILE)).unwrap().len() < len / 4);
+        assert_eq!(free_pages(&db), 0);
+        for id in 0..2000 {
+            match id % 10 {
+                0 => assert_eq!(db.read("users", Value::Number(id)).unwrap().row, user(id, 100)),
+                _ => assert!(matches!(db.read("users", Value::Number(id)), Err(PlanckError::NotFound(_)))),
+            }
+        }
+        drop(db);
+        assert!(check_database(&dir).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Keys at the ends of the range of their type are ordinary keys, also between pages of a tree with internal
+    // pages. The keys are given in order.
+    fn assert_ordinary_keys(types: u8, keys: Vec<Value>) {
+        let mut db = Database::open_in_memory().unwrap();
+        db.create_table("keys", 0, vec!["key".to_string(), "note".to_string()], vec![types, STRING_TYPE]).unwrap();
+        let row = |key: &Value| vec![key.clone(), Value::string("n".repeat(100))];
+        // From both ends towards the middle, so that the extreme keys are inserted first.
+        let order = (0..keys.len()).map(|index| if index % 2 == 0 { index / 2 } else { keys.len() - 1 - index / 2 });
+        for index in order {
+            db.insert("keys", row(&keys[index])).unwrap();
+        }
+        assert!(db.stats("keys").unwrap().tree.height >= 2);
+        for key in &keys {
+            assert_eq!(&db.read("keys", key.clone()).unwrap().key, key);
+        }
+        assert!(matches!(db.insert("keys", row(&keys[keys.len() - 1])), Err(PlanckError::DuplicateKey(_))));
+        for key in keys.iter().skip(1).step_by(2) {
+            db.delete("keys", key.clone()).unwrap();
+        }
+        for (index, key) in keys.iter().enumerate() {
+            match index % 2 {
+                0 => assert_eq!(&db.read("keys", key.clone()).unwrap().key, key),
+                _ => assert!(matches!(db.read("keys", key.clone()), Err(PlanckError::NotFound(_)))),
+            }
+        }
+        for key in keys.iter().skip(1).step_by(2).rev() {
+            db.insert("keys", row(key)).unwrap();
+        }
+        for key in &keys {
+            assert_eq!(&db.read("keys", key.clone()).unwrap().key, key);
+        }
+        assert_eq!(db.table("keys").unwrap().row_count as usize, keys.len());
+    }
+
+    #[test]
+    fn int64_and_float64_extremes_are_ordinary_keys() {
+        let mut ints = vec![i64::MIN, i64::MIN + 1];
+        ints.extend(-150..150);
+        ints.extend([i64::MAX - 1, i64::MAX]);
+        assert_ordinary_keys(INT64_TYPE, ints.into_iter().map(Value::Int64).collect());
+
+        let mut floats = vec![f64::NEG_INFINITY, f64::MIN];
+        floats.extend((-150..150).map(|x| x as f64 / 4.0));
+        floats.extend([f64::MAX, f64::INFINITY]);
+        assert_ordinary_keys(FLOAT64_TYPE, floats.into_iter().map(Value::Float64).collect());
+    }
+
+    #[test]
+    fn negative_zero_and_zero_are_the_same_key() {
+        let mut db = Database::open_in_memory().unwrap();
+        db.create_table("floats", 0, vec!["key".to_string(), "note".to_string()], vec![FLOAT64_TYPE, STRING_TYPE]).unwrap();
+        // Built by hand, not parsed: the encoder stores it as 0.0.
+        db.insert("floats", vec![Value::Float64(-0.0), Value::String(1, "zero".to_string())]).unwrap();
+        let again = vec![Value::Float64(0.0), Value::string("again".to_string())];
+        assert!(matches!(db.insert("floats", again), Err(PlanckError::DuplicateKey(_))));
+        assert!(matches!(&db.read("floats", Value::Float64(0.0)).unwrap().row[1], Value::String(_, x) if x == "zero"));
+        db.delete("floats", Value::Float64(0.0)).unwrap();
+        assert_eq!(db.table("floats").unwrap().row_count, 0);
+    }
+
+    #[test]
+    fn date_and_timestamp_extremes_are_ordinary_keys() {
+        let mut dates = vec![i32::MIN, i32::MIN + 1];
+        dates.extend(-150..150);
+        dates.extend([i32::MAX - 1, i32::MAX]);
+        assert_ordinary_keys(DATE_TYPE, dates.into_iter().map(Value::Date).collect());
+
+        let mut timestamps = vec![i64::MIN, i64::MIN + 1];
+        timestamps.extend((-150..150).map(|x| x * 86_400_000));
+        timestamps.extend([i64::MAX - 1, i64::MAX]);
+        assert_ordinary_keys(TIMESTAMP_TYPE, timestamps.into_iter().map(Value::Timestamp).collect());
+    }
+
+    #[test]
+    fn nulls_round_trip_in_nullable_columns_only() {
+        let dir = temp_dir("nulls");
+        let mut db = Database::open(&dir).unwrap();
+        let names = vec!["id".to_string(), "name".to_string(), "note".to_string(), "score".to_string()];
+        db.create_table("notes", 0, names, vec![NUMBER_TYPE, STRING_TYPE, STRING_TYPE | NULLABLE, INT64_TYPE | NULLABLE]).unwrap();
+        let rows = vec![
+            vec![Value::Number(1), Value::string("a".to_string()), Value::Null, Value::Int64(5)],
+            vec![Value::Number(2), Value::string("b".to_string()), Value::string("x".to_string()), Value::Null],
+            vec![Value::Number(3), Value::string("c".to_string()), Value::Null, Value::Null],
+        ];
+        for row in &rows {
+            db.insert("notes", row.clone()).unwrap();
+        }
+        let null_name = vec![Value::Number(4), Value::Null, Value::Null, Value::Null];
+        assert!(matches!(db.insert("notes", null_name), Err(PlanckError::SchemaMismatch(_))));
+        let null_name = vec![Value::Number(1), Value::Null, Value::Null, Value::Null];
+        assert!(matches!(db.update("notes", null_name), Err(PlanckError::SchemaMismatch(_))));
+        drop(db);
+        let mut db = Database::open(&dir).unwrap();
+        for row in &rows {
+            assert_eq!(&db.read("notes", row[0].clone()).unwrap().row, row);
+        }
+        let notes = db.table("notes").unwrap();
+        assert!(notes.check_row(&rows[2]).is_ok());
+        assert!(matches!(notes.check_row(&[Value::Null, Value::string("d".to_string()), Value::Null, Value::Null]), Err(PlanckError::SchemaMismatch(_))));
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // A table of documents whose body and data take a chain of overflow pages each.
+    fn docs_in(mut db: Database) -> Database {
+        db.create_table("docs", 0, vec!["id".to_string(), "body".to_string(), "data".to_string()], vec![NUMBER_TYPE, STRING_TYPE, BYTES_TYPE]).unwrap();
+        db
+    }
+
+    fn doc(id: u32, len: usize) -> Vec<Value> {
+        vec![Value::Number(id), Value::string("b".repeat(len)), Value::Bytes(vec![id as u8; len])]
+    }
+
+    #[test]
+    fn overflow_values_round_trip_and_their_chains_are_freed() {
+        let dir = temp_dir("overflow");
+        let mut db = docs_in(Database::open(&dir).unwrap());
+        for id in 0..10 {
+            db.insert("docs", doc(id, 10000)).unwrap();
+        }
+        drop(db);
+        let mut db = Database::open(&dir).unwrap();
+        for id in 0..10 {
+            assert_eq!(db.read("docs", Value::Number(id)).unwrap().row, doc(id, 10000));
+        }
+        // Every change that lets go of a value frees its chain.
+        let mut free = free_pages(&db);
+        db.update("docs", doc(1, 10)).unwrap();
+        assert!(free_pages(&db) > free);
+        assert_eq!(db.read("docs", Value::Number(1)).unwrap().row, doc(1, 10));
+        free = free_pages(&db);
+        db.delete("docs", Value::Number(2)).unwrap();
+        assert!(free_pages(&db) > free);
+        free = free_pages(&db);
+        db.drop_table("docs").unwrap();
+        assert!(free_pages(&db) > free + 8 * 2);
+        drop(db);
+        assert!(check_database(&dir).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dropped_columns_free_their_chains_once_the_upgraded_page_is_written() {
+        let dir = temp_dir("drop_column_chains");
+        let mut db = docs_in(Database::open(&dir).unwrap());
+        for id in 0..3 {
+            db.insert("docs", doc(id, 10000)).unwrap();
+        }
+        db.alter_table("docs", AlterTable::DropColumn("body".to_string())).unwrap();
+        let free = free_pages(&db);
+        // Reading upgrades the leaf in the pool only, the leaf on disk still points to the chains of the bodies.
+        assert_eq!(db.read("docs", Value::Number(1)).unwrap().row, vec![Value::Number(1), Value::Bytes(vec![1; 10000])]);
+        assert_eq!(free_pages(&db), free);
+        // As if the process stopped here.
+        drop(db);
+        assert!(check_database(&dir).unwrap().is_empty());
+        let mut db = Database::open(&dir).unwrap();
+        db.insert("docs", vec![Value::Number(3), Value::Bytes(vec![3])]).unwrap();
+        assert!(free_pages(&db) >= free + 3 * 3);
+        assert_eq!(db.read("docs", Value::Number(2)).unwrap().row, vec![Value::Number(2), Value::Bytes(vec![2; 10000])]);
+        drop(db);
+        assert!(check_database(&dir).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn composite_keys_and_prefix_reads() {
+        let mut db = Database::open_in_memory().unwrap();
+        let columns = vec!["tenant".to_string(), "user".to_string(), "note".to_string()];
+        db.create_table_with_key("accounts", vec![0, 1], columns, vec![NUMBER_TYPE, STRING_TYPE, STRING_TYPE]).unwrap();
+        let row = |tenant: u32, user: u32| vec![Value::Number(tenant), Value::string(format!("u{}", user)), Value::string("n".repeat(100))];
+        for user in 0..100 {
+            for tenant in (0..50).rev() {
+                db.insert("accounts", row(tenant, user)).unwrap();
+            }
+        }
+        assert!(db.stats("accounts").unwrap().tree.height >= 2);
+        assert!(matches!(db.insert("accounts", row(7, 7)), Err(PlanckError::DuplicateKey(_))));
+
+        // User names compare as strings, u10 comes before u2.
+        let mut users: Vec<String> = (0..100).map(|user| format!("u{}", user)).collect();
+        users.sort();
+        let tenant_42: Vec<Value> = db.read_prefix("accounts", &[Value::Number(42)]).unwrap().into_iter().map(|kr| kr.row[1].clone()).collect();
+        assert_eq!(tenant_42, users.into_iter().map(Value::string).collect::<Vec<Value>>());
+        assert_eq!(db.read_prefix("accounts", &[Value::Number(42), Value::string("u7".to_string())]).unwrap().len(), 1);
+        assert!(db.read_prefix("accounts", &[Value::Number(50)]).unwrap().is_empty());
+        assert!(db.read_prefix("accounts", &[Value::string("42".to_string())]).is_err());
+
+        let key = Value::composite(&[Value::Number(3), Value::string("u5".to_string())]).unwrap();
+        assert_eq!(db.read("accounts", key.clone()).unwrap().row, row(3, 5));
+        db.delete("accounts", key.clone()).unwrap();
+        assert!(matches!(db.read("accounts", key), Err(PlanckError::NotFound(_))));
+        assert_eq!(db.read_prefix("accounts", &[Value::Number(3)]).unwrap().len(), 99);
+    }
+
+    #[test]
+    fn long_string_keys_split_and_merge() {
+        // Keys far longer than the placeholder keys the last slot of an internal page used to hold.
+        let key = |id: u32| Value::string(format!("{}{:06}", "k".repeat(40), id));
+        let names = |db: &mut Database| -> Vec<Value> {
+            db.tree("names").unwrap().scan().unwrap().into_iter().map(|kr| kr.key).collect()
+        };
+        let dir = temp_dir("long_keys");
+        let mut db = Database::open(&dir).unwrap();
+        db.create_table("names", 0, vec!["name".to_string(), "note".to_string()], vec![STRING_TYPE, STRING_TYPE]).unwrap();
+        for index in 0..6000 {
+            db.insert("names", vec![key(index * 7919 % 6000), Value::string("n".repeat(100))]).unwrap();
+        }
+        assert_eq!(db.stats("names").unwrap().tree.height, 3);
+        assert_eq!(names(&mut db), (0..6000).map(key).collect::<Vec<Value>>());
+
+        for index in 0..6000 {
+            let id = index * 7919 % 6000;
+            if id % 20 != 0 {
+                db.delete("names", key(id)).unwrap();
+            }
+        }
+        assert_eq!(names(&mut db), (0..6000).step_by(20).map(key).collect::<Vec<Value>>());
+        for id in (0..6000).step_by(20) {
+            assert_eq!(db.read("names", key(id)).unwrap().key, key(id));
+        }
+        drop(db);
+        assert!(check_database(&dir).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // testdata/vNN/planck.db were written by PlanckDB with format version NN: table n holds the keys 0..1500 that
+    // are not a multiple of 3 with the row [k, k * 3], table s 60 rows [s<i>, <i * 37 % 700 times x>, -i] and
+    // table c, keyed by its first two columns, 300 rows [i % 7, u<i>, <i % 5 bytes of i>].
+    fn old_database(version: u16) -> PathBuf {
+        let dir = temp_dir(&format!("v{}", version));
+        fs::create_dir_all(&dir).unwrap();
+        let file = Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("testdata/v{}", version)).join(DATABASE_FILE);
+        fs::copy(file, dir.join(DATABASE_FILE)).unwrap();
+        dir
+    }
+
+    fn assert_old_rows(db: &mut Database) {
+        let n: Vec<Vec<Value>> = db.read_range("n", Bound::Unbounded, Bound::Unbounded).unwrap().into_iter().map(|kr| kr.row).collect();
+        let expected: Vec<Vec<Value>> = (0..1500).filter(|k| k % 3 != 0).map(|k| vec![Value::Number(k), Value::Number(k * 3)]).collect();
+        assert_eq!(n, expected);
+        for i in 0..60 {
+            let row = vec![Value::string(format!("s{}", i)), Value::string("x".repeat(i * 37 % 700)), Value::Int64(-(i as i64))];
+            assert_eq!(db.read("s", row[0].clone()).unwrap().row, row);
+        }
+        for i in 0..300u32 {
+            let row = vec![Value::Number(i % 7), Value::string(format!("u{}", i)), Value::Bytes(vec![i as u8; (i % 5) as usize])];
+            assert_eq!(db.read("c", Value::composite(&row[..2]).unwrap()).unwrap().row, row);
+        }
+        assert_eq!(db.read_prefix("c", &[Value::Number(3)]).unwrap().len(), 43);
+        // Older formats put s2 before s10, the rebuilt trees follow the order of the strings.
+        let mut names: Vec<String> = (0..60).map(|i| format!("s{}", i)).collect();
+        names.sort();
+        let keys: Vec<Value> = db.read_range("s", Bound::Unbounded, Bound::Unbounded).unwrap().into_iter().map(|kr| kr.key).collect();
+        assert_eq!(keys, names.into_iter().map(Value::string).collect::<Vec<Value>>());
+        for (name, rows) in [("n", 1000), ("s", 60), ("c", 300)] {
+            assert_eq!(db.table(name).unwrap().row_count, rows);
+        }
+    }
+
+    #[test]
+    fn files_of_older_formats_are_migrated() {
+        for version in [11, 12, 13] {
+            let dir = old_database(version);
+            let mut db = Database::open(&dir).unwrap();
+            assert_old_rows(&mut db);
+            // Pages of the new format hold more than the 255 slots of the old ones.
+            db.create_table("wide", 0, vec!["k".to_string()], vec![NUMBER_TYPE]).unwrap();
+            for k in 0..280 {
+                db.insert("wide", vec![Value::Number(k)]).unwrap();
+            }
+            assert_eq!(db.stats("wide").unwrap().tree.leaf_pages, 1);
+            drop(db);
+
+            assert_eq!(FileStorage::open(&dir.join(DATABASE_FILE)).unwrap().format_version, FORMAT_VERSION);
+            assert!(!dir.join("rebuild").exists());
+            assert!(check_database(&dir).unwrap().is_empty());
+            let mut db = Database::open(&dir).unwrap();
+            assert_old_rows(&mut db);
+            assert_eq!(db.read_range("wide", Bound::Unbounded, Bound::Unbounded).unwrap().len(), 280);
+            drop(db);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    // Every word of one to three letters from a to e.
+    fn word_list() -> Vec<String> {
+        let mut words = Vec::new();
+        let mut longest = vec![String::new()];
+        for _ in 0..3 {
+            longest = longest.iter().flat_map(|word| "abcde".chars().map(move |letter| format!("{}{}", word, letter))).collect();
+            words.extend(longest.iter().cloned());
+        }
+        words
+    }
+
+    // A table of the words, with rows long enough to fill a few leaves.
+    fn words() -> Database {
+        let mut db = Database::open_in_memory().unwrap();
+        db.create_table("words", 0, vec!["word".to_string(), "note".to_string()], vec![STRING_TYPE, STRING_TYPE]).unwrap();
+        let mut words = word_list();
+        // The longest words first, most splits happen between words of different lengths.
+        words.sort_by_key(|word| std::cmp::Reverse(word.len()));
+        for word in words {
+            db.insert("words", vec![Value::string(word), Value::string("n".repeat(100))]).unwrap();
+        }
+        assert!(db.stats("words").unwrap().tree.leaf_pages > 3);
+        db
+    }
+
+    fn words_of(rows: Vec<KeyRow>) -> Vec<String> {
+        rows.into_iter().map(|kr| kr.key.to_string()).collect()
+    }
+
+    // The words between the bounds in the order of Rust strings, and the bounds as values.
+    fn expected_words(start: Bound<&str>, end: Bound<&str>) -> (Vec<String>, Bound<Value>, Bound<Value>) {
+        let mut words = word_list();
+        words.sort();
+        words.retain(|word| (start, end).contains(&word.as_str()));
+        let value = |word: &str| Value::string(word.to_string());
+        (words, start.map(value), end.map(value))
+    }
+
+    const WORD_RANGES: [(Bound<&str>, Bound<&str>); 7] = [
+        (Bound::Included("b"), Bound::Excluded("c")),
+        (Bound::Excluded("b"), Bound::Included("c")),
+        (Bound::Included("abc"), Bound::Excluded("ad")),
+        (Bound::Excluded("dee"), Bound::Unbounded),
+        (Bound::Unbounded, Bound::Included("aa")),
+        (Bound::Included("cab"), Bound::Included("cab")),
+        (Bound::Unbounded, Bound::Unbounded),
+    ];
+
+    #[test]
+    fn string_ranges_follow_the_order_of_the_strings() {
+        let mut db = words();
+        let (b, start, end) = expected_words(Bound::Included("b"), Bound::Excluded("c"));
+        assert_eq!(b.len(), 31);
+        assert_eq!(b[..3], ["b", "ba", "baa"]);
+        assert_eq!(words_of(db.read_range("words", start, end).unwrap()), b);
+        for (start, end) in WORD_RANGES {
+            let (expected, start, end) = expected_words(start, end);
+            assert_eq!(words_of(db.read_range("words", start, end).unwrap()), expected);
+        }
+        assert!(words_of(db.read_range("words", Bound::Included(Value::string("ca".to_string())), Bound::Excluded(Value::string("c".to_string()))).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn descending_string_ranges_follow_the_order_of_the_strings() {
+        let mut db = words();
+        for (start, end) in WORD_RANGES {
+            let (mut expected, start, end) = expected_words(start, end);
+            expected.reverse();
+            assert_eq!(words_of(db.read_range_desc("words", start, end).unwrap()), expected);
+        }
+        assert!(words_of(db.read_range_desc("words", Bound::Included(Value::string("ca".to_string())), Bound::Excluded(Value::string("c".to_string()))).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn cursors_stop_where_they_are_asked_to() {
+        let mut db = words();
+        // Forty rows either way from the middle of the table, past the end of a leaf.
+        let (expected, start, end) = expected_words(Bound::Included("c"), Bound::Unbounded);
+        let rows: Result<Vec<KeyRow>, PlanckError> = db.tree("words").unwrap().range(start, end).unwrap().take(40).collect();
+        assert_eq!(words_of(rows.unwrap()), expected[..40]);
+        let (mut expected, start, end) = expected_words(Bound::Unbounded, Bound::Excluded("c"));
+        expected.reverse();
+        let rows: Result<Vec<KeyRow>, PlanckError> = db.tree("words").unwrap().range_desc(start, end).unwrap().take(40).collect();
+        assert_eq!(words_of(rows.unwrap()), expected[..40]);
+        let (mut all, _, _) = expected_words(Bound::Unbounded, Bound::Unbounded);
+        let rows: Result<Vec<KeyRow>, PlanckError> = db.tree("words").unwrap().cursor().unwrap().collect();
+        assert_eq!(words_of(rows.unwrap()), all);
+        all.reverse();
+        let rows: Result<Vec<KeyRow>, PlanckError> = db.tree("words").unwrap().cursor_desc().unwrap().collect();
+        assert_eq!(words_of(rows.unwrap()), all);
+    }
+}
@@ -1,21 +1,22 @@
-use std::{collections::HashMap, u32};
+use std::collections::HashMap;
 use std::mem;
+use std::ops::Bound;
+use std::vec;
 
 
 use crate::dbengine::buffer_manager::*;
 use crate::dbengine::pages::*;
-use crate::dbengine::engine::Table;
+use crate::dbengine::engine::{Table, TreeStats, NO_PAGE, PAGE_SIZE};
+use crate::dbengine::storage::SharedPager;
+use crate::dbengine::error::PlanckError;
 #[derive(Clone, Debug)]
 pub enum  NodeType {
-    Internal(Vec<u32>),
-    Leaf(Vec<u32>)
+    Internal,
+    Leaf
 }
-const BTREE_MAX: usize = 4;
-const MIN_KEY: usize = BTREE_MAX/2;
-const MAX_KEY: usize = BTREE_MAX;
-const MIN_CHILD: usize = (BTREE_MAX+1)/2;
-const MAX_CHILD: usize =BTREE_MAX+1;
-const MAX_VALUE: u32 = u32::MAX;
+// Pages are split when their entries no longer fit in PAGE_SIZE and rebalanced with a sibling when they
+// use less than a quarter of it, so how many keys a page holds depends on the size of its keys and rows.
+const MIN_PAGE_BYTES: usize = PAGE_SIZE / 4;
 
 #[derive(Clone, Debug)]
 pub struct Siblings {
@@ -29,756 +30,1163 @@ pub struct BPlusTree {
     pub buffer_pool: BufferPool,
 }
 
+fn not_internal(page_id: u32) -> PlanckError {
+    PlanckError::Corruption(format!("page {} was expected to be an internal page", page_id))
+}
+
+fn not_leaf(page_id: u32) -> PlanckError {
+    PlanckError::Corruption(format!("page {} was expected to be a leaf page", page_id))
+}
+
+// The child a slot of an internal page points to.
+fn child_of(page: &Page, page_id: u32, slot: &Slot) -> Result<u32, PlanckError> {
+    match page.cells.get(&slot.pointer) {
+        Some(row) => row.values[0].extract_pointer(),
+        None => Err(PlanckError::Corruption(format!("slot {} of page {} points to no cell", slot.value, page_id)))
+    }
+}
+
+// The child of an internal page whose keys include the given one: the first child whose divider is bigger
+// than the key, otherwise the last child. A page without children comes from a corrupted file.
+// The key of the last slot is never compared. Files written when that slot held the biggest value of the key
+// type read the same way, a key past the other dividers went to the last child then too, so the format version
+// did not change. Splits never move the last slot's key up, an old page keeps it until the page is rebuilt.
+fn child_for(page: &Page, page_id: u32, key: &Value) -> Result<u32, PlanckError> {
+    let (last, dividers) = match page.slots.split_last() {
+        Some(split) => split,
+        None => return Err(PlanckError::Corruption(format!("internal page {} has no children", page_id)))
+    };
+    child_of(page, page_id, dividers.iter().find(|slot| key < &slot.value).unwrap_or(last))
+}
+
 impl BPlusTree {
-    pub fn new(file: Table) -> Self {
-        BPlusTree{buffer_pool: BufferPool::new(file)}
+    pub fn new(pager: SharedPager, file: Table) -> Self {
+        BPlusTree{buffer_pool: BufferPool::new(pager, file)}
     }
-    fn print_tree(&mut self, node_key: u32, level: usize) {
-        if self.is_leaf_root() {
-            println!("-> {:?}", self.buffer_pool.get(0))
+
+    // Every table lives in the same file, the root page is whatever the catalog says it is.
+    pub fn root(&self) -> u32 {
+        self.buffer_pool.file.root_node_offset
+    }
+    pub fn print_tree(&mut self, node_key: u32, level: usize) -> Result<(), PlanckError> {
+        if self.is_leaf_root()? {
+            println!("-> {:?}", self.buffer_pool.get(self.root())?)
         }
         else {
-            if let page = self.buffer_pool.get(node_key) {
-                // Print the current node with indentation
-                let indent = "    ".repeat(level);
-                match &page.page_type {
-                    NodeType::Internal(kvs) => {
-                        println!("{}Internal Node (Page_id: {}):", indent, node_key);
-                        let slots = page.slots.clone();
-                        let cells = page.cells.clone();
-                        for slot in slots {
-                            println!("{}  - Primary_index: {:?}, Points to Node: {:?}", indent, slot.value, cells.get(&slot.pointer).unwrap().values[0].extract_pointer());
-                            // Recursively print the child nodes
-                            self.print_tree(cells.get(&slot.pointer).unwrap().values[0].extract_pointer(), level + 1);
-                        }
+            let page = self.buffer_pool.get(node_key)?;
+            // Print the current node with indentation
+            let indent = "    ".repeat(level);
+            match &page.page_type {
+                NodeType::Internal => {
+                    println!("{}Internal Node (Page_id: {}):", indent, node_key);
+                    let slots = page.slots.clone();
+                    let cells = page.cells.clone();
+                    for slot in slots {
+                        let child = cells.get(&slot.pointer).unwrap().values[0].extract_pointer()?;
+                        println!("{}  - Primary_index: {:?}, Points to Node: {:?}", indent, slot.value, child);
+                        // Recursively print the child nodes
+                        self.print_tree(child, level + 1)?;
                     }
-                    NodeType::Leaf(kvs) => {
-                        println!("{}Leaf Node (Page_id: {}):", indent, node_key);
-                        let slots = page.slots.clone();
-                        let cells = page.cells.clone();
-                        for slot in slots {
-                            println!("{}  - Primary_index: {:?}, Rows: {:?}", indent, slot.value, cells.get(&slot.pointer).unwrap().values); 
-                        }
+                }
+                NodeType::Leaf => {
+                    println!("{}Leaf Node (Page_id: {}):", indent, node_key);
+                    let slots = page.slots.clone();
+                    let cells = page.cells.clone();
+                    for slot in slots {
+                        println!("{}  - Primary_index: {:?}, Rows: {:?}", indent, slot.value, cells.get(&slot.pointer).unwrap().values);
                     }
                 }
             }
-         }
+        }
+        Ok(())
     }
-    
-    
-    pub fn search(&mut self, k: &Value) -> (u32, u32){
-        let root = self.buffer_pool.get(0).clone();
-        self.search_tree(root, k, Some(0), Some(0))
+
+
+    pub fn search(&mut self, k: &Value) -> Result<(u32, u32), PlanckError> {
+        let root = self.buffer_pool.get(self.root())?.clone();
+        self.search_tree(root, k, Some(self.root()), Some(self.root()))
     }
-    fn search_tree(&mut self, node:Page, key: &Value, leaf_id: Option<u32>,parent_id: Option<u32>) -> (u32, u32){
+    fn search_tree(&mut self, node:Page, key: &Value, leaf_id: Option<u32>,parent_id: Option<u32>) -> Result<(u32, u32), PlanckError> {
        match node.page_type {
-          NodeType::Leaf(keyvalue) => {
-               return (leaf_id.unwrap(), parent_id.unwrap())
+          NodeType::Leaf => {
+               Ok((leaf_id.unwrap(), parent_id.unwrap()))
           },
-          NodeType::Internal(keys) => {
-                  let slots = &node.slots;
-                  let mut internal_pointer: u16 = slots[node.slots.len() -1].pointer;
-                  for i in 0..slots.len(){
-                      if key < &slots[i].value {
-                        internal_pointer = slots[i].pointer;
-                        break;
-                      }
+          NodeType::Internal => {
+                  let pointer = child_for(&node, leaf_id.unwrap_or(self.root()), key)?;
+
+                  let child = self.buffer_pool.get(pointer)?.clone();
+                  let parent = match &child.page_type {
+                      NodeType::Internal => pointer,
+                      NodeType::Leaf => parent_id.unwrap_or(self.root())
                   };
-                  let mut pointer = 0;
-                  match node.cells.get(&internal_pointer).unwrap().values[0] {
-                      Value::Number(x) => pointer = x,
-                      _ => panic!()
-
-                  }
-                  
-                  let child = self.buffer_pool.get(pointer).clone();
-                  let mut parent:u32;
-                  match &child.page_type {
-                      NodeType::Internal(_) => parent = pointer,
-                      NodeType::Leaf(_) => {
-                        if parent_id != None {
-                            parent = parent_id.unwrap();
-                        } else {
-                            parent = 0;
-                        }
-                      }
-                  }
                   self.search_tree(child, key, Some(pointer), Some(parent))
 
           },
-          _ => panic!("Undefined structure")
        }
     }
 
-    fn is_underflow(&self, node:&Page) -> bool{
-        match &node.page_type {
-            NodeType::Internal(keys) => {
-                if node.slots.len() < MIN_CHILD + 1 {
-                    
-                    return true
-                } else {
-                    return false 
+    // Returns the row stored under the key, if there is one.
+    pub fn get(&mut self, key: &Value) -> Result<Option<KeyRow>, PlanckError> {
+        match self.find(key)? {
+            Some(kr) => Ok(Some(KeyRow { key: kr.key, row: self.buffer_pool.resolve_row(kr.row)? })),
+            None => Ok(None),
+        }
+    }
+
+    // Like get, but values on overflow pages are left as stubs.
+    fn find(&mut self, key: &Value) -> Result<Option<KeyRow>, PlanckError> {
+        let (id, _) = self.search(key)?;
+        let node = self.buffer_pool.get(id)?;
+        for slot in &node.slots {
+            if &slot.value == key {
+                return match node.cells.get(&slot.pointer) {
+                    Some(row) => Ok(Some(KeyRow{key: key.clone(), row: row.values.clone()})),
+                    None => Err(PlanckError::Corruption(format!("slot {} of page {} points to no cell", key, id)))
+                };
+            }
+        };
+        Ok(None)
+    }
+
+    // Ids of every page of the tree, parents before their children.
+    pub fn page_ids(&mut self) -> Result<Vec<u32>, PlanckError> {
+        let mut ids = Vec::new();
+        let mut stack = vec![self.root()];
+        while let Some(page_id) = stack.pop() {
+            ids.push(page_id);
+            let page = self.buffer_pool.get(page_id)?;
+            if let NodeType::Internal = page.page_type {
+                for slot in page.slots.iter().rev() {
+                    match page.cells.get(&slot.pointer) {
+                        Some(row) => stack.push(row.values[0].extract_pointer()?),
+                        None => return Err(PlanckError::Corruption(format!("slot {} of page {} points to no cell", slot.value, page_id)))
+                    }
                 }
-            },
-            NodeType::Leaf(KeyValue) => {
-                if node.slots.len() < MIN_KEY {
-                    return true
-                } else {
-                    return false
+            }
+        }
+        Ok(ids)
+    }
+
+    // Every row of the tree in key order.
+    pub fn scan(&mut self) -> Result<Vec<KeyRow>, PlanckError> {
+        let rows = self.scan_stubs()?;
+        rows.into_iter().map(|kr| Ok(KeyRow { key: kr.key, row: self.buffer_pool.resolve_row(kr.row)? })).collect()
+    }
+
+    // Like scan, but values on overflow pages are left as stubs.
+    pub fn scan_stubs(&mut self) -> Result<Vec<KeyRow>, PlanckError> {
+        let mut rows = Vec::new();
+        for page_id in self.page_ids()? {
+            let page = self.buffer_pool.get(page_id)?;
+            if let NodeType::Leaf = page.page_type {
+                for slot in &page.slots {
+                    match page.cells.get(&slot.pointer) {
+                        Some(row) => rows.push(KeyRow { key: slot.value.clone(), row: row.values.clone() }),
+                        None => return Err(PlanckError::Corruption(format!("slot {} of page {} points to no cell", slot.value, page_id)))
+                    }
                 }
             }
         }
+        Ok(rows)
     }
 
-    fn is_overflow(&self, node:&Page) -> bool{
-        match &node.page_type {
-            NodeType::Internal(keys) => {
-                if node.slots.len() > MAX_CHILD {
-                    return true
-                } else {
-                    return false 
+    // A cursor on the first row of the tree.
+    pub fn cursor(&mut self) -> Result<Cursor<'_>, PlanckError> {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    // A cursor on the last row of the tree, going down.
+    pub fn cursor_desc(&mut self) -> Result<Cursor<'_>, PlanckError> {
+        self.range_desc(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    // A cursor over the rows whose key lies between the bounds, in key order.
+    pub fn range(&mut self, start: Bound<Value>, end: Bound<Value>) -> Result<Cursor<'_>, PlanckError> {
+        Cursor::new(self, start, end, false)
+    }
+
+    // The rows of range(start, end) from the largest key down, e.g. the latest events first.
+    pub fn range_desc(&mut self, start: Bound<Value>, end: Bound<Value>) -> Result<Cursor<'_>, PlanckError> {
+        Cursor::new(self, end, start, true)
+    }
+
+    // The leaf holding the smallest keys or the one holding the largest, an end of the chain of leaves.
+    fn edge_leaf(&mut self, last: bool) -> Result<u32, PlanckError> {
+        let mut page_id = self.root();
+        loop {
+            let page = self.buffer_pool.get(page_id)?;
+            match page.page_type {
+                NodeType::Leaf => return Ok(page_id),
+                NodeType::Internal => {
+                    let slot = if last { page.slots.last() } else { page.slots.first() };
+                    let child = match slot.and_then(|slot| page.cells.get(&slot.pointer)) {
+                        Some(row) => row,
+                        None => return Err(PlanckError::Corruption(format!("internal page {} has no children", page_id)))
+                    };
+                    page_id = child.values[0].extract_pointer()?;
                 }
-            },
-            NodeType::Leaf(KeyValue) => {
-                if node.slots.len() > MAX_KEY {
-                    return true
-                } else {
-                    return false
+            }
+        }
+    }
+
+    // Rows whose composite key starts with the prefix, in key order. Those keys are next to each other in the tree,
+    // so only the children whose key range can hold one of them are visited.
+    pub fn scan_prefix(&mut self, prefix: &Value) -> Result<Vec<KeyRow>, PlanckError> {
+        let mut rows = Vec::new();
+        let mut stack = vec![self.root()];
+        while let Some(page_id) = stack.pop() {
+            let page = self.buffer_pool.get(page_id)?;
+            match page.page_type {
+                NodeType::Leaf => {
+                    for slot in page.slots.iter().filter(|slot| slot.value.has_prefix(prefix)) {
+                        match page.cells.get(&slot.pointer) {
+                            Some(row) => rows.push(KeyRow { key: slot.value.clone(), row: row.values.clone() }),
+                            None => return Err(PlanckError::Corruption(format!("slot {} of page {} points to no cell", slot.value, page_id)))
+                        }
+                    }
+                }
+                NodeType::Internal => {
+                    // A child holds the keys from the divider before its slot up to its own divider, the last one
+                    // every key from the divider before it on.
+                    let mut children = Vec::new();
+                    let mut lower: Option<&Value> = None;
+                    let last = page.slots.len().saturating_sub(1);
+                    for (index, slot) in page.slots.iter().enumerate() {
+                        let past_prefix = matches!(lower, Some(lower) if lower > prefix && !lower.has_prefix(prefix));
+                        if past_prefix {
+                            break;
+                        }
+                        if index == last || &slot.value > prefix {
+                            match page.cells.get(&slot.pointer) {
+                                Some(row) => children.push(row.values[0].extract_pointer()?),
+                                None => return Err(PlanckError::Corruption(format!("slot {} of page {} points to no cell", slot.value, page_id)))
+                            }
+                        }
+                        lower = Some(&slot.value);
+                    }
+                    stack.extend(children.into_iter().rev());
                 }
             }
         }
+        rows.into_iter().map(|kr| Ok(KeyRow { key: kr.key, row: self.buffer_pool.resolve_row(kr.row)? })).collect()
     }
-    
-    fn is_leaf_root(&mut self) -> bool {
-        let root = self.buffer_pool.get(0);
-        match &root.page_type {
-            NodeType::Leaf(kvs) => {
 
-                root.slots.len() < MAX_KEY + 1
-            },
-            _ => false,
+    // Ids of the overflow pages the rows of the tree point to.
+    pub fn overflow_page_ids(&mut self) -> Result<Vec<u32>, PlanckError> {
+        let mut firsts = Vec::new();
+        for page_id in self.page_ids()? {
+            let page = self.buffer_pool.get(page_id)?;
+            if let NodeType::Leaf = page.page_type {
+                for row in page.cells.values() {
+                    firsts.extend(overflow_pages(&row.values));
+                }
+            }
+        }
+        let mut ids = Vec::new();
+        for first in firsts {
+            ids.extend(self.buffer_pool.read_overflow(first)?.0);
         }
+        Ok(ids)
     }
 
-    pub fn update(&mut self, new_kr: KeyRow) {
-        let (node_id, _) = self.search(&new_kr.key);
-        let node = self.buffer_pool.get_mut(node_id);
+    // Walks the whole tree, returns its shape and how many pages fall in each 10% fill bucket.
+    pub fn stats(&mut self) -> Result<(TreeStats, [u32; 10]), PlanckError> {
+        let mut stats = TreeStats { min_page_bytes: PAGE_SIZE as u32, ..TreeStats::default() };
+        let mut histogram = [0u32; 10];
+        let mut stack = vec![(self.root(), 1)];
+        while let Some((page_id, depth)) = stack.pop() {
+            let page = self.buffer_pool.get(page_id)?;
+            let used = page.used_bytes() as u32;
+            stats.bytes_used += used;
+            stats.min_page_bytes = stats.min_page_bytes.min(used);
+            stats.height = stats.height.max(depth);
+            histogram[((used as usize * 10) / PAGE_SIZE).min(9)] += 1;
+            match page.page_type {
+                NodeType::Leaf => stats.leaf_pages += 1,
+                NodeType::Internal => {
+                    stats.internal_pages += 1;
+                    for slot in &page.slots {
+                        match page.cells.get(&slot.pointer) {
+                            Some(row) => stack.push((row.values[0].extract_pointer()?, depth + 1)),
+                            None => return Err(PlanckError::Corruption(format!("slot {} of page {} points to no cell", slot.value, page_id)))
+                        }
+                    }
+                }
+            }
+        }
+        Ok((stats, histogram))
+    }
+
+    // Reads every page once, so that pages written with an older schema are upgraded, and writes them back.
+    pub fn rewrite(&mut self) -> Result<(), PlanckError> {
+        self.page_ids()?;
+        self.buffer_pool.flush_all()
+    }
+
+    fn is_underflow(node:&Page) -> bool{
+        match &node.page_type {
+            NodeType::Internal => node.slots.len() < 2 || node.used_bytes() < MIN_PAGE_BYTES,
+            NodeType::Leaf => node.used_bytes() < MIN_PAGE_BYTES
+        }
+    }
+
+    fn is_overflow(node:&Page) -> bool{
+        !node.fits()
+    }
+
+    // Whether a page holding these entries could be written.
+    fn entries_fit(entries: &[KeyRow]) -> bool {
+        let bytes: usize = entries.iter().map(|kr| slot_size(&kr.key) + cell_size(&kr.row)).sum();
+        PAGE_HEADER_SIZE + bytes <= PAGE_SIZE
+    }
+
+    fn is_leaf_root(&mut self) -> Result<bool, PlanckError> {
+        let root = self.buffer_pool.get(self.root())?;
+        match &root.page_type {
+            NodeType::Leaf => Ok(root.fits()),
+            _ => Ok(false),
+        }
+    }
+
+    pub fn update(&mut self, new_kr: KeyRow) -> Result<(), PlanckError> {
+        let old = match self.find(&new_kr.key)? {
+            Some(old) => old,
+            None => return Err(PlanckError::NotFound(format!("key {}", new_kr.key)))
+        };
+        let new_kr = KeyRow { key: new_kr.key, row: self.buffer_pool.spill_row(new_kr.row)? };
+        let (node_id, _) = self.search(&new_kr.key)?;
+        let node = self.buffer_pool.get_mut(node_id)?;
         node.delete(new_kr.key.clone());
-        node.insert(new_kr);
+        node.vacuum();
+        if node.used_bytes() + slot_size(&new_kr.key) + cell_size(&new_kr.row) <= PAGE_SIZE {
+            node.insert(new_kr)?;
+        } else {
+            // The longer row does not fit in its page, it goes in again like a new one and the page is split.
+            // The page was nearly full, without the old row it is still far from an underflow.
+            let new_row = new_kr.row.clone();
+            if let Err(err) = self.insert_spilled(new_kr) {
+                // E.g. no page for the split, the old row goes back where it was and the new one is let go.
+                self.restore(old)?;
+                self.buffer_pool.remove_row_overflow(&new_row)?;
+                return Err(err);
+            }
+        }
+        self.buffer_pool.remove_row_overflow(&old.row)
     }
 
-    fn insert_leaf_tree(&mut self, new_kr: KeyRow) {
-        let root = self.buffer_pool.get_mut(0);
-        root.insert(new_kr);
+    // Puts back a row whose update failed halfway, in place of the new row if that one made it into a leaf.
+    fn restore(&mut self, old: KeyRow) -> Result<(), PlanckError> {
+        let (node_id, _) = self.search(&old.key)?;
+        let node = self.buffer_pool.get_mut(node_id)?;
+        node.delete(old.key.clone());
+        node.vacuum();
+        node.insert(old)
+    }
+
+    fn insert_leaf_tree(&mut self, new_kr: KeyRow) -> Result<(), PlanckError> {
+        let root = self.buffer_pool.get_mut(self.root())?;
+        root.insert(new_kr)?;
         let copy_cells = root.cells.clone();
         let copy_slots =  root.slots.clone();
-        if !self.is_leaf_root() {
-            
+        if !self.is_leaf_root()? {
+
             let mut new_root = Page::new_internal();
             let mut new_page = Page::new_leaf();
             let str_num_spr = copy_slots[0].clone();
             new_page.slots = copy_slots;
             new_page.cells = copy_cells;
-            let new_id = self.buffer_pool.create_page(new_page);
-            match str_num_spr.value {
-                Value::Number(_) =>  new_root.insert(KeyRow { key: Value::Number(MAX_VALUE), row: vec![Value::Number(new_id)] }),
-                Value::String(_, _) => new_root.insert(KeyRow { key: Value::string("zzzzzzzz".to_string()), row: vec![Value::Number(new_id)] }),
-            }
-            self.buffer_pool.update_page(new_root, 0);
-            self.split(new_id, 0);
+            new_page.free_space_pointer = self.buffer_pool.get(self.root())?.free_space_pointer;
+            let new_id = self.buffer_pool.create_page(new_page)?;
+            self.grow();
+            new_root.push(KeyRow { key: str_num_spr.value, row: vec![Value::Number(new_id)] });
+            self.buffer_pool.update_page(new_root, self.root())?;
+            self.split(new_id, self.root())?;
         }
+        Ok(())
     }
-    
-    pub fn insert(&mut self, new_kr: KeyRow) -> bool {
-        if self.is_leaf_root() {
-            self.insert_leaf_tree(new_kr);
-            return true;
+
+    fn check_new_key(key: &Value) -> Result<(), PlanckError> {
+        if matches!(key, Value::Float64(x) if x.is_nan()) {
+            return Err(PlanckError::SchemaMismatch("NaN cannot be used as a key".to_string()));
         }
-        let root = &self.buffer_pool.get(0).clone();
-        let mut parents = vec![0];
-        let mut next_node_pointer;
-        match &root.page_type {
-            NodeType::Internal(pkvs) => {
-              let slots = &root.slots;
-              next_node_pointer = slots[slots.len() -1].pointer;
-              for i in 0..slots.len() - 1 {
-                  if  new_kr.key < slots[i].value {
-                      next_node_pointer = slots[i].pointer;
-                      break;
-                  }
-              }
-            },
-            _ => panic!("__")
+        if matches!(key, Value::Null | Value::Overflow { .. }) {
+            return Err(PlanckError::SchemaMismatch(format!("{} cannot be used as a key", key)));
+        }
+        if key.encoded_size() > MAX_KEY_SIZE {
+            return Err(PlanckError::SchemaMismatch(format!("keys take at most {} bytes", MAX_KEY_SIZE)));
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, new_kr: KeyRow) -> Result<(), PlanckError> {
+        Self::check_new_key(&new_kr.key)?;
+        if self.find(&new_kr.key)?.is_some() {
+            return Err(PlanckError::DuplicateKey(format!("key {}", new_kr.key)));
+        }
+        let new_kr = KeyRow { key: new_kr.key, row: self.buffer_pool.spill_row(new_kr.row)? };
+        self.insert_spilled(new_kr)
+    }
+
+    // Inserts a row whose values were already moved to overflow pages where needed.
+    fn insert_spilled(&mut self, new_kr: KeyRow) -> Result<(), PlanckError> {
+        if self.is_leaf_root()? {
+            return self.insert_leaf_tree(new_kr);
         }
-        let next_node_id = &root.cells.get(&next_node_pointer).unwrap().values[0];
-        self.insert_recursive(new_kr, next_node_id.extract_pointer(), &mut parents);
+        let root = &self.buffer_pool.get(self.root())?.clone();
+        let mut parents = vec![self.root()];
+        let next_node_id = match &root.page_type {
+            NodeType::Internal => child_for(root, self.root(), &new_kr.key)?,
+            _ => return Err(not_internal(self.root()))
+        };
+        self.insert_recursive(new_kr, next_node_id, &mut parents)?;
         while parents.len() > 1 {
             let node_id = parents.pop().unwrap();
-            if self.is_overflow(self.buffer_pool.clone().get(node_id)) {
-                self.split(node_id, parents[parents.len() -1]);
-            } 
+            if Self::is_overflow(self.buffer_pool.get(node_id)?) {
+                self.split(node_id, parents[parents.len() -1])?;
+            }
         }
         let root_id = parents.pop().unwrap();
-        if self.is_overflow(self.buffer_pool.clone().get(root_id)) {
-            self.split_root(root_id);
+        if Self::is_overflow(self.buffer_pool.get(root_id)?) {
+            self.split_root(root_id)?;
         }
-        
-        return true
-        
+
+        Ok(())
+
     }
 
-    fn insert_recursive(&mut self,new_kr: KeyRow,current: u32, parents: &mut Vec<u32>){
-       let mut node = self.buffer_pool.get_mut(current);
+    fn insert_recursive(&mut self,new_kr: KeyRow,current: u32, parents: &mut Vec<u32>) -> Result<(), PlanckError> {
+       let node = self.buffer_pool.get_mut(current)?;
        match &mut node.page_type {
-        NodeType::Leaf(kvs) => {
-            node.insert(new_kr);
+        NodeType::Leaf => {
+            node.insert(new_kr)?;
             parents.push(current);
-            
+            Ok(())
         },
-        NodeType::Internal(kvs) => {
-            let slots = &mut node.slots;
+        NodeType::Internal => {
             parents.push(current);
-            let mut next_node_id;
-            next_node_id = slots[slots.len() -1].pointer;
-            for i in 0..slots.len() - 1 {
-                if  new_kr.key < slots[i].value {
-                    next_node_id = slots[i].pointer;
-                    break;
-                }
-            }
-            let child = node.cells.get(&next_node_id).unwrap().values[0].clone();
-            self.insert_recursive(new_kr, child.extract_pointer(), parents)
+            let child = child_for(node, current, &new_kr.key)?;
+            self.insert_recursive(new_kr, child, parents)
         }
        }
 
     }
 
-    fn split_root(&mut self, root_id: u32) {
-        let new_node_id = self.clone().buffer_pool.file.page_id_count;
-        let root = self.buffer_pool.get_mut(root_id);
-        let mut slots = &mut root.slots;
-        let str_num_divider = slots[0].clone();
-        let mut cells = &mut root.cells;
+    fn split_root(&mut self, root_id: u32) -> Result<(), PlanckError> {
+        let root = self.buffer_pool.get_mut(root_id)?;
+        let str_num_divider = root.slots[0].clone();
         let mut new_page = Page::new_internal();
-        new_page.slots = mem::replace(&mut slots, Vec::new());
-        new_page.cells = mem::replace(&mut cells, HashMap::new());
-        
-        let key = match str_num_divider.value {
-            Value::Number(_) => Value::Number(MAX_VALUE),
-            Value::String(_, _) => Value::string("zzzzzzzz".to_string()),
-        };
-        root.insert(KeyRow { key: key, row: vec![Value::Number(new_node_id)] });
-        self.buffer_pool.create_page(new_page);
-        self.split(new_node_id, root_id);
+        new_page.slots = mem::take(&mut root.slots);
+        new_page.cells = mem::take(&mut root.cells);
+        new_page.free_space_pointer = root.free_space_pointer;
+        root.clean_page();
+        let new_node_id = self.buffer_pool.create_page(new_page)?;
+        self.grow();
+
+        let root = self.buffer_pool.get_mut(root_id)?;
+        root.push(KeyRow { key: str_num_divider.value, row: vec![Value::Number(new_node_id)] });
+        self.split(new_node_id, root_id)?;
+        Ok(())
     }
-    
-    fn split(&mut self, current: u32, parent: u32) -> bool {
-        let node = self.buffer_pool.get(current);
+
+    fn split(&mut self, current: u32, parent: u32) -> Result<(), PlanckError> {
+        let node = self.buffer_pool.get(current)?;
         let mut new_kr: Vec<KeyRow> = Vec::new();
-        let mut is_internal = false;
-        match &node.page_type {
-          NodeType::Internal(kvs) => {
-            let slots = &node.slots;
-            for slot in slots {
-                new_kr.push(KeyRow { key: slot.value.clone(), row: node.cells.get(&slot.pointer).unwrap().values.clone() })
-            }
-            is_internal = true;
-          },
-          NodeType::Leaf(kvs) => {
-            let slots = &node.slots;
-            for slot in slots {
-                new_kr.push(KeyRow { key: slot.value.clone(), row: node.cells.get(&slot.pointer).unwrap().values.clone() })
-            }
-          }
+        let is_internal = matches!(&node.page_type, NodeType::Internal);
+        for slot in &node.slots {
+            new_kr.push(KeyRow { key: slot.value.clone(), row: node.cells.get(&slot.pointer).unwrap().values.clone() })
+        }
+        // The current page keeps the first half of the bytes, at least one entry and never all of them.
+        let total: usize = new_kr.iter().map(|kr| slot_size(&kr.key) + cell_size(&kr.row)).sum();
+        let mut middle_index = 0;
+        let mut left_bytes = 0;
+        while middle_index < new_kr.len() - 1 && left_bytes < total / 2 {
+            left_bytes += slot_size(&new_kr[middle_index].key) + cell_size(&new_kr[middle_index].row);
+            middle_index += 1;
+        }
+        let middle_index = middle_index.max(1);
+
+        let divider = match is_internal {
+            true => new_kr[middle_index - 1].key.clone(),
+            false => new_kr[middle_index].key.clone(),
         };
-        let middle_index = new_kr.len() / 2;
+        let node = self.buffer_pool.get_mut(current)?;
+        // The last slot of an internal page keeps its key, which is the divider moving up, but is never compared.
+        for slot in node.slots.split_off(middle_index) {
+            node.cells.remove(&slot.pointer);
+        }
+        node.vacuum();
+        // The new page comes right after the current one, the leaf before the current one keeps its link.
+        let mut new_page = match &node.page_type {
+            NodeType::Internal => Page::new_internal(),
+            NodeType::Leaf => Page::new_leaf(),
+        };
+        let next_leaf = node.next_leaf;
+        new_page.next_leaf = next_leaf;
+        for kr in new_kr.drain(middle_index..) {
+            new_page.push(kr);
+        }
+        if !is_internal {
+            new_page.prev_leaf = current;
+        }
+        let new_node_id = self.buffer_pool.create_page(new_page)?;
+        self.count_page(is_internal, true);
+        if !is_internal {
+            self.buffer_pool.get_mut(current)?.next_leaf = new_node_id;
+            if next_leaf != NO_PAGE {
+                self.buffer_pool.get_mut(next_leaf)?.prev_leaf = new_node_id;
+            }
+        }
 
-        let mut new_node_vec: Vec<KeyRow> = Vec::new();
-        for i in 0..middle_index {
-            new_node_vec.push(new_kr[i].clone())
+        // The bound of the current page now bounds the new one, the current page is bounded by the divider.
+        let parent_node = self.buffer_pool.get_mut(parent)?;
+        match parent_node.cells.values_mut().find(|row| matches!(row.values[0], Value::Number(child) if child == current)) {
+            Some(row) => row.values[0] = Value::Number(new_node_id),
+            None => return Err(PlanckError::Corruption(format!("page {} is not a child of page {}", current, parent)))
         }
-        let mut divider = new_kr[middle_index].key.clone();
-        if is_internal {
-            divider = new_node_vec[middle_index-1].key.clone()
+        parent_node.insert(KeyRow { key: divider, row: vec![Value::Number(current)] })?;
+
+        Ok(())
+    }
+
+    // Bulk loading
+
+    // Builds the tree of an empty table from rows sorted by key, without the splits of inserting them one by one:
+    // leaves are filled up to fill_factor of a page and written in key order, then every level of internal pages
+    // is built from the one below it, up to the root. A fill factor under 1 leaves room for later inserts.
+    // Returns how many rows were loaded, after an error the table is still empty. The table gets the shape of the new tree.
+    pub fn bulk_load<I: IntoIterator<Item = Result<KeyRow, PlanckError>>>(&mut self, rows: I, fill_factor: f64) -> Result<u32, PlanckError> {
+        if !(0.5..=1.0).contains(&fill_factor) {
+            return Err(PlanckError::Syntax(format!("the fill factor must be between 0.5 and 1, not {}", fill_factor)));
         }
-        if is_internal{
-            new_node_vec[middle_index-1].key = match new_node_vec[middle_index - 1].key {
-                Value::Number(_) => Value::Number(MAX_VALUE),
-                Value::String(_, _) => Value::string("zzzzzzzz".to_string()),
-            };
+        let root = self.buffer_pool.get(self.root())?;
+        if !matches!(root.page_type, NodeType::Leaf) || !root.slots.is_empty() {
+            return Err(PlanckError::SchemaMismatch(format!("table {} must be empty to be bulk loaded", self.buffer_pool.file.name)));
         }
-        let mut new_node_id = 0;
-        let node = self.buffer_pool.get_mut(current);
-        match &mut node.page_type {
-            NodeType::Internal(kvs) => {
-                for i in 0..middle_index {
-                    let slot = node.slots.remove(0);
-                    node.cells.remove(&slot.pointer);
+        let target = (PAGE_SIZE as f64 * fill_factor) as usize;
+        let mut created = Vec::new();
+        let mut chains = Vec::new();
+        match self.load_sorted(rows.into_iter(), target, &mut created, &mut chains) {
+            Ok(count) => {
+                self.buffer_pool.file.tree_stats = self.stats()?.0;
+                Ok(count)
+            }
+            Err(err) => {
+                // The root is written last, the pages written before the error belong to no tree.
+                for first in chains {
+                    self.buffer_pool.remove_overflow(first)?;
                 }
-                let mut new_page = Page::new_internal();
-                for i in 0..middle_index {
-                    new_page.insert(new_node_vec.remove(0));
+                for page_id in created {
+                    self.buffer_pool.remove_page(page_id)?;
                 }
-                new_node_id = self.buffer_pool.create_page(new_page);
-            },
-            NodeType::Leaf(kvs) => {
-                for i in 0..middle_index {
-                    let slot = node.slots.remove(0);
-                    node.cells.remove(&slot.pointer);
+                Err(err)
+            }
+        }
+    }
+
+    fn load_sorted<I: Iterator<Item = Result<KeyRow, PlanckError>>>(&mut self, rows: I, target: usize, created: &mut Vec<u32>, chains: &mut Vec<u32>) -> Result<u32, PlanckError> {
+        let mut count = 0;
+        let mut previous_key: Option<Value> = None;
+        // The first key and page id of every leaf written so far, the level above is built from them.
+        let mut leaves = Vec::new();
+        // A full leaf is only written once the page of the next one is known, for its link.
+        let mut full: Option<(u32, Vec<KeyRow>)> = None;
+        let mut current = Vec::new();
+        let mut current_bytes = PAGE_HEADER_SIZE;
+        for kr in rows {
+            let kr = kr?;
+            Self::check_new_key(&kr.key)?;
+            match &previous_key {
+                Some(previous) if kr.key == *previous => return Err(PlanckError::DuplicateKey(format!("key {}", kr.key))),
+                Some(previous) if kr.key < *previous => {
+                    return Err(PlanckError::SchemaMismatch(format!("rows must be sorted by key, {} comes after {}", kr.key, previous)));
                 }
-                let mut new_page = Page::new_leaf();
-                for i in 0..middle_index {
-                    new_page.insert(new_node_vec.remove(0));
+                _ => {}
+            }
+            previous_key = Some(kr.key.clone());
+            // Only the chains spilled here are freed after an error, the input may already point to chains of another tree.
+            let existing: Vec<u32> = overflow_pages(&kr.row).collect();
+            let row = self.buffer_pool.spill_row(kr.row)?;
+            chains.extend(overflow_pages(&row).filter(|page| !existing.contains(page)));
+            let kr = KeyRow { key: kr.key, row };
+            let bytes = slot_size(&kr.key) + cell_size(&kr.row);
+            if !current.is_empty() && current_bytes + bytes > target {
+                let page_id = self.buffer_pool.write_new_page(Page::new_leaf())?;
+                created.push(page_id);
+                if let Some((full_id, entries)) = full.replace((page_id, mem::take(&mut current))) {
+                    self.write_leaf(full_id, entries, page_id, &mut leaves)?;
                 }
-                new_node_id = self.buffer_pool.create_page(new_page);
+                current_bytes = PAGE_HEADER_SIZE;
+            }
+            current_bytes += bytes;
+            current.push(kr);
+            count += 1;
+        }
+
+        let (full_id, mut entries) = match full {
+            Some(full) => full,
+            None => {
+                // Every row fits in the root, which stays a leaf.
+                self.buffer_pool.update_page(Self::page_of(NodeType::Leaf, current)?, self.root())?;
+                return Ok(count);
             }
         };
+        Self::balance_last(&mut entries, &mut current, false);
+        if current.is_empty() && leaves.is_empty() {
+            // The only two leaves were merged, they fit in the root after all.
+            self.buffer_pool.remove_page(full_id)?;
+            created.retain(|page_id| *page_id != full_id);
+            self.buffer_pool.update_page(Self::page_of(NodeType::Leaf, entries)?, self.root())?;
+            return Ok(count);
+        }
+        if current.is_empty() {
+            self.write_leaf(full_id, entries, NO_PAGE, &mut leaves)?;
+        } else {
+            let page_id = self.buffer_pool.write_new_page(Page::new_leaf())?;
+            created.push(page_id);
+            self.write_leaf(full_id, entries, page_id, &mut leaves)?;
+            self.write_leaf(page_id, current, NO_PAGE, &mut leaves)?;
+        }
+        self.load_internal(leaves, target, created)?;
+        Ok(count)
+    }
 
-        
-        let mut parent_node = self.buffer_pool.get_mut(parent);
-        parent_node.insert(KeyRow { key: divider.clone(), row: vec![Value::Number(new_node_id)] });
-        
-       
+    // Builds the levels above the leaves, the root page of the table gets the single page of the top level.
+    fn load_internal(&mut self, children: Vec<(Value, u32)>, target: usize, created: &mut Vec<u32>) -> Result<(), PlanckError> {
+        // A child is bounded by the first key of the child after it. The last one has no bound, its own first
+        // key only fills the slot.
+        let mut level: Vec<KeyRow> = children.iter().enumerate().map(|(index, (first, page_id))| {
+            let key = match children.get(index + 1) {
+                Some((next, _)) => next.clone(),
+                None => first.clone(),
+            };
+            KeyRow { key, row: vec![Value::Number(*page_id)] }
+        }).collect();
+        loop {
+            let mut pages = Self::pack(level, target);
+            if pages.len() == 1 {
+                let root = Self::page_of(NodeType::Internal, pages.pop().unwrap())?;
+                return self.buffer_pool.update_page(root, self.root());
+            }
+            level = Vec::new();
+            for entries in pages {
+                // The last key of a page bounds it in its parent, in the page itself it is never compared.
+                let bound = entries[entries.len() - 1].key.clone();
+                let page_id = self.buffer_pool.write_new_page(Self::page_of(NodeType::Internal, entries)?)?;
+                created.push(page_id);
+                level.push(KeyRow { key: bound, row: vec![Value::Number(page_id)] });
+            }
+        }
+    }
 
+    // Writes a leaf of bulk_load, linked to the leaf written before it and to the next one.
+    fn write_leaf(&mut self, page_id: u32, entries: Vec<KeyRow>, next_leaf: u32, leaves: &mut Vec<(Value, u32)>) -> Result<(), PlanckError> {
+        let mut page = Self::page_of(NodeType::Leaf, entries)?;
+        page.prev_leaf = leaves.last().map_or(NO_PAGE, |(_, leaf)| *leaf);
+        page.next_leaf = next_leaf;
+        leaves.push((page.slots[0].value.clone(), page_id));
+        self.buffer_pool.update_page(page, page_id)
+    }
 
-       return  true
+    fn page_of(page_type: NodeType, entries: Vec<KeyRow>) -> Result<Page, PlanckError> {
+        let mut page = match page_type {
+            NodeType::Internal => Page::new_internal(),
+            NodeType::Leaf => Page::new_leaf(),
+        };
+        for kr in entries {
+            page.push(kr);
+        }
+        Ok(page)
+    }
+
+    // Cuts sorted entries into pages of at most target bytes.
+    fn pack(entries: Vec<KeyRow>, target: usize) -> Vec<Vec<KeyRow>> {
+        let mut pages: Vec<Vec<KeyRow>> = vec![Vec::new()];
+        let mut bytes = PAGE_HEADER_SIZE;
+        for kr in entries {
+            let size = slot_size(&kr.key) + cell_size(&kr.row);
+            if bytes + size > target && !pages[pages.len() - 1].is_empty() {
+                pages.push(Vec::new());
+                bytes = PAGE_HEADER_SIZE;
+            }
+            bytes += size;
+            pages.last_mut().unwrap().push(kr);
+        }
+        if pages.len() > 1 {
+            let mut last = pages.pop().unwrap();
+            Self::balance_last(pages.last_mut().unwrap(), &mut last, true);
+            if !last.is_empty() {
+                pages.push(last);
+            }
+        }
+        pages
+    }
+
+    // The last page of a level gets whatever is left. When that would be an underflow it shares the entries of
+    // the page before it: both go in one page if they fit, otherwise they are split in the middle of their bytes.
+    fn balance_last(previous: &mut Vec<KeyRow>, last: &mut Vec<KeyRow>, is_internal: bool) {
+        let size = |kr: &KeyRow| slot_size(&kr.key) + cell_size(&kr.row);
+        let last_bytes: usize = last.iter().map(size).sum();
+        if PAGE_HEADER_SIZE + last_bytes >= MIN_PAGE_BYTES && (!is_internal || last.len() >= 2) {
+            return;
+        }
+        previous.append(last);
+        if Self::entries_fit(previous) {
+            return;
+        }
+        let total: usize = previous.iter().map(size).sum();
+        let mut middle_index = 0;
+        let mut left_bytes = 0;
+        while middle_index < previous.len() - 1 && left_bytes < total / 2 {
+            left_bytes += size(&previous[middle_index]);
+            middle_index += 1;
+        }
+        *last = previous.split_off(middle_index.max(1));
     }
 
     // Deletion Part
 
-    pub fn delete(&mut self, key: Value) -> bool {
-        let root = self.buffer_pool.get(0).clone();
-        let mut parents = vec![0];
-        let mut next_node_id;
-        match root.page_type{
-            NodeType::Internal(pkvs) => {
-              let slots = root.slots;
-              next_node_id = slots[slots.len() -1].pointer;
-              for i in 0..slots.len() - 1 {
-                  if  key < slots[i].value {
-                      next_node_id = slots[i].pointer;
-                      break;
-                  }
-              }
-            },
-            _ => panic!("__")
+    pub fn delete(&mut self, key: Value) -> Result<(), PlanckError> {
+        let old = self.find(&key)?;
+        self.remove(key)?;
+        match old {
+            Some(old) => self.buffer_pool.remove_row_overflow(&old.row),
+            None => Ok(())
+        }
+    }
+
+    fn remove(&mut self, key: Value) -> Result<(), PlanckError> {
+        if self.buffer_pool.get(self.root())?.slots.is_empty() {
+            return Err(PlanckError::NotFound(format!("key {}", key)));
+        }
+        let root = self.buffer_pool.get(self.root())?.clone();
+        if let NodeType::Leaf = root.page_type {
+            // The whole tree is a single leaf, there is nothing to rebalance.
+            let root = self.buffer_pool.get_mut(self.root())?;
+            if !root.delete(key.clone()) {
+                return Err(PlanckError::NotFound(format!("key {}", key)));
+            }
+            root.vacuum();
+            return Ok(());
+        }
+        let mut parents = vec![self.root()];
+        let pointer = child_for(&root, self.root(), &key)?;
+        if !self.delete_recursive(key.clone(), pointer, &mut parents)? {
+            return Err(PlanckError::NotFound(format!("key {}", key)));
         }
-        let pointer = root.cells.get(&next_node_id).unwrap().values[0].extract_pointer();
-        let exists = self.delete_recursive(key, pointer, &mut parents);
 
-        if exists {
-            while parents.len() > 1 {
-                let node_id = parents.pop().unwrap();
-                if self.is_underflow(self.buffer_pool.clone().get(node_id)) {
-                    self.distribute_mini(node_id, parents[parents.len() -1]);
-                } 
+        // Borrowing changes a divider of the parent, a longer divider can make the parent overflow.
+        while parents.len() > 1 {
+            let node_id = parents.pop().unwrap();
+            let node = self.buffer_pool.get(node_id)?;
+            if Self::is_underflow(node) {
+                self.distribute_mini(node_id, parents[parents.len() -1])?;
+            } else if Self::is_overflow(node) {
+                self.split(node_id, parents[parents.len() -1])?;
             }
-            self.merge_root(parents.pop().unwrap());
         }
-        
-        return true
-        
+        let root_id = parents.pop().unwrap();
+        if Self::is_overflow(self.buffer_pool.get(root_id)?) {
+            self.split_root(root_id)?;
+        } else {
+            self.merge_root(root_id)?;
+        }
+
+        Ok(())
+
     }
 
-    
-    fn get_sibling(&mut self , current: u32, parent: u32) -> Vec<Siblings>{
-       let parent = self.buffer_pool.get(parent);
+
+    fn get_sibling(&mut self , current: u32, parent_id: u32) -> Result<Vec<Siblings>, PlanckError> {
+       let parent = self.buffer_pool.get(parent_id)?;
        match &parent.page_type {
-        NodeType::Internal(pkvs) => {
+        NodeType::Internal => {
             let slots = &parent.slots;
+            if slots.len() < 2 {
+                return Err(PlanckError::Corruption(format!("internal page {} has a single child", parent_id)));
+            }
             let mut return_vec = Vec::new();
             let mut index_current = 0;
-            for i in 0..slots.len() {
-                let pointer = parent.cells.get(&slots[i].pointer).unwrap();
-                if current ==  pointer.values[0].extract_pointer(){
+            for (i, slot) in slots.iter().enumerate() {
+                if current == child_of(parent, parent_id, slot)? {
                    index_current = i;
                    break;
                 }
             }
             return_vec.push(Siblings { page_id: current, page_index: index_current});
             if index_current == 0 {
-                return_vec.push(Siblings { page_id: parent.cells.get(&slots[1].pointer).unwrap().values[0].extract_pointer(), page_index: 1 });
+                return_vec.push(Siblings { page_id: child_of(parent, parent_id, &slots[1])?, page_index: 1 });
             } else {
-                return_vec.push(Siblings { page_id: parent.cells.get(&slots[index_current -1 ].pointer).unwrap().values[0].extract_pointer(), page_index: index_current -1  });
+                return_vec.push(Siblings { page_id: child_of(parent, parent_id, &slots[index_current - 1])?, page_index: index_current -1  });
             }
-            return return_vec;
+            Ok(return_vec)
         },
-        _ => panic!("_+_")
-       } 
+        _ => Err(not_internal(parent_id))
+       }
     }
 
-    fn delete_recursive(&mut self, key: Value, current: u32, parents: &mut Vec<u32>) -> bool {
-        let mut node = self.buffer_pool.get_mut(current);
+    fn delete_recursive(&mut self, key: Value, current: u32, parents: &mut Vec<u32>) -> Result<bool, PlanckError> {
+        let node = self.buffer_pool.get_mut(current)?;
         match &mut node.page_type {
-            NodeType::Leaf(kvs) => {
-                let mut exists = false;
-                let mut slots = &mut node.slots;
-                for i in 0..slots.len() {
-                    if key == slots[i].value {
-                       let slot = slots.remove(i);
-                       node.cells.remove(&slot.pointer);
-                       node.vacuum();
-                       parents.push(current); 
-                       exists= true;
-                       break;
-                    }
+            NodeType::Leaf => {
+                let exists = node.delete(key);
+                if exists {
+                    node.vacuum();
+                    parents.push(current);
                 }
-                if !exists {
-                    println!("No key found as {:?}", key);
-                }
-
-                return exists;
-            
+                Ok(exists)
             },
-            NodeType::Internal(kvs) => {
+            NodeType::Internal => {
                   parents.push(current);
-                  let mut next_node_id;
-                  let slots = &node.slots;
-                  next_node_id = slots[slots.len() -1].pointer;
-                  for i in 0..slots.len() - 1 {
-                     if  key < slots[i].value {
-                         next_node_id = slots[i].pointer;
-                         break;
-                     }
-                  }
-                  let pointer = node.cells.get(&next_node_id).unwrap().values[0].extract_pointer();
+                  let pointer = child_for(node, current, &key)?;
                   self.delete_recursive(key, pointer, parents)
             }
        }
     }
 
 
-    // Merging Algorithm 
-    fn distribute_mini(&mut self, current: u32, parent: u32) {
-       let siblings = self.get_sibling(current, parent);
+    // Merging Algorithm
+    fn distribute_mini(&mut self, current: u32, parent: u32) -> Result<(), PlanckError> {
+       let siblings = self.get_sibling(current, parent)?;
        let get_out = |vec: &Vec<Slot>, hash: &HashMap<u16, Rows>| -> Vec<KeyRow> {
              vec.iter().map(|item| KeyRow{key: item.value.clone(), row: hash.get(&item.pointer).unwrap().values.clone()}).collect()
         };
-        
+
        let mut total_cells: Vec<KeyRow>= Vec::new();
-       let mut reverse = false;
+       let reverse = siblings[0].page_index >= siblings[1].page_index;
        let mut internal_divider: Value  = Value::Number(0);
-       if siblings[0].page_index < siblings[1].page_index {
-          let node1 = self.buffer_pool.get(siblings[0].page_id).clone();
-          let node2 = self.buffer_pool.get(siblings[1].page_id).clone();
-          match &node1.page_type {
-            NodeType::Internal(kvs) => {
-                
-                let slots = &node1.slots;
-                let cells = &node1.cells;
-                total_cells.extend(get_out(slots, cells));
-
-                let parent = self.buffer_pool.get(parent);
-                match &parent.page_type {
-                    NodeType::Internal(pkvs) => {
-                        internal_divider = parent.slots[siblings[0].page_index].value.clone();
-                    },
-                    _ => panic!("-")
-                }
-            },
-            NodeType::Leaf(kvs) => {
-                let slots = &node1.slots;
-                let cells = &node1.cells;
-                total_cells.extend(get_out(slots, cells));
-            },  
-          };
-          match &node2.page_type {
-            NodeType::Internal(kvs) => {
-                let slots = &node2.slots;
-                let cells = &node2.cells;
-                total_cells.extend(get_out(slots, cells));
-            },
-            NodeType::Leaf(kvs) => {
-                let slots = &node2.slots;
-                let cells = &node2.cells;
-                total_cells.extend(get_out(slots, cells));
-            },
-        }
-       } else {
-            reverse = true;
-            
-            let node1 = self.buffer_pool.get(siblings[0].page_id).clone();
-            let node2 = self.buffer_pool.get(siblings[1].page_id).clone();
-            match &node2.page_type {
-                NodeType::Internal(kvs) => {
-                    let slots = &node2.slots;
-                    let cells = &node2.cells;
-                    total_cells.extend(get_out(slots, cells));
-
-
-                    let parent = self.buffer_pool.get(parent);
-                    match &parent.page_type {
-                        NodeType::Internal(pkvs) => {
-                            internal_divider = parent.slots[siblings[1].page_index].value.clone();
-                        },
-                        _ => panic!("-")
-                    }
-                    
-                },
-                NodeType::Leaf(kvs) => {
-                    let slots = &node2.slots;
-                    let cells = &node2.cells;
-                    total_cells.extend(get_out(slots, cells));
-                },  
-            };
-            match &node1.page_type {
-                NodeType::Internal(kvs) => {
-                    let slots = &node1.slots;
-                    let cells = &node1.cells;
-                    total_cells.extend(get_out(slots, cells));
-                },
-                NodeType::Leaf(kvs) => {
-                    let slots = &node1.slots;
-                    let cells = &node1.cells;
-                    total_cells.extend(get_out(slots, cells));
+       // The left node always comes first so the cells stay sorted
+       let (left, right) = if reverse { (&siblings[1], &siblings[0]) } else { (&siblings[0], &siblings[1]) };
+       let node1 = self.buffer_pool.get(left.page_id)?.clone();
+       let node2 = self.buffer_pool.get(right.page_id)?.clone();
+       total_cells.extend(get_out(&node1.slots, &node1.cells));
+       total_cells.extend(get_out(&node2.slots, &node2.cells));
+       if let NodeType::Internal = &node1.page_type {
+            let parent_page = self.buffer_pool.get(parent)?;
+            match &parent_page.page_type {
+                NodeType::Internal => {
+                    internal_divider = parent_page.slots[left.page_index].value.clone();
                 },
+                _ => return Err(not_internal(parent))
             }
+            // Merged, the last child of the left node is bounded by the divider of the parent.
+            total_cells[node1.slots.len() - 1].key = internal_divider.clone();
        }
-       
-       
+       // Both nodes are merged when their entries fit in one page, otherwise the current node borrows one entry.
+       let merge = Self::entries_fit(&total_cells);
+
+
        if reverse {
-        let node2 = self.buffer_pool.get_mut(siblings[1].page_id);
+        let node2 = self.buffer_pool.get_mut(siblings[1].page_id)?;
         match &mut node2.page_type {
-           NodeType::Leaf(kvs) => {
-            
-             if total_cells.len() <= MAX_KEY {
-                let node1 = self.buffer_pool.get_mut(siblings[0].page_id);
-                 match &mut node1.page_type {
-                     NodeType::Leaf(kvs1) => {
-                        node1.clean_page();
-                        
-                        for kr in total_cells {
-                            node1.insert(kr);
-                        }
-                     },
-                     _ => panic!("___")
-                 }
-                 self.buffer_pool.remove_page(siblings[1].page_id);
-                 let parent_node = self.buffer_pool.get_mut(parent);
-                 match &mut parent_node.page_type {
-                     NodeType::Internal(pkvs) => {
-                         let slot = parent_node.slots.remove(siblings[1].page_index);
-                         parent_node.cells.remove(&slot.pointer);
-                     },
-                     _ => panic!("___")
-                 }
+           NodeType::Leaf => {
+
+             if merge {
+                 self.merge_leaves(left, right, parent, total_cells)?;
              } else {
-                 let mut moved_slot = node2.slots.pop().unwrap();
-                 let mut moved_value = KeyRow {key: moved_slot.value, row: node2.cells.remove(&moved_slot.pointer).unwrap().values};
-                 let last_idx = &node2.slots.len() -1;
+                 let moved_slot = node2.slots.pop().unwrap();
+                 let moved_value = KeyRow {key: moved_slot.value, row: node2.cells.remove(&moved_slot.pointer).unwrap().values};
+                 node2.vacuum();
                  let new_bound = moved_value.key.clone();
-                 let node1 = self.buffer_pool.get_mut(siblings[0].page_id);
-                 node1.insert(moved_value);
-                 let parent_node = self.buffer_pool.get_mut(parent);
+                 let node1 = self.buffer_pool.get_mut(siblings[0].page_id)?;
+                 node1.insert(moved_value)?;
+                 let parent_node = self.buffer_pool.get_mut(parent)?;
                  match &mut parent_node.page_type {
-                     NodeType::Internal(pkvs) => {
+                     NodeType::Internal => {
                          parent_node.slots[siblings[1].page_index].value = new_bound;
                      },
-                     _ => panic!("___")
+                     _ => return Err(not_internal(parent))
                  }
              }
            },
-           NodeType::Internal(kvs) => {
-            
-             if total_cells.len() <= MAX_CHILD {
-                 for i in 0..total_cells.len() {
-                     match  &total_cells[i].key{
-                         Value::Number(x) => {
-                            if x.clone() == MAX_VALUE {
-                                total_cells[i].key = internal_divider;
-                                break;
-                            }
-                         },
-                         Value::String(_, x) => {
-                            if x.clone() == "zzzzzzzz".to_string(){
-                                total_cells[i].key = internal_divider;
-                                break;
-                            }
-                         }
-                     }
-                 }
-                 let node1 = self.buffer_pool.get_mut(siblings[0].page_id);
+           NodeType::Internal => {
+
+             if merge {
+                 let node1 = self.buffer_pool.get_mut(siblings[0].page_id)?;
                  node1.clean_page();
                  for kr in total_cells {
-                    node1.insert(kr)
+                    node1.push(kr);
                  }
-                 self.buffer_pool.remove_page(siblings[1].page_id);
-                 let parent_node = self.buffer_pool.get_mut(parent);
+                 self.buffer_pool.remove_page(siblings[1].page_id)?;
+                 self.count_page(true, false);
+                 let parent_node = self.buffer_pool.get_mut(parent)?;
                  match &mut parent_node.page_type {
-                     NodeType::Internal(pkvs) => {
-                         
+                     NodeType::Internal => {
+
                         let slot = parent_node.slots.remove(siblings[1].page_index);
                         parent_node.cells.remove(&slot.pointer);
+                        parent_node.vacuum();
                      },
-                     _ => panic!("___")
+                     _ => return Err(not_internal(parent))
                  }
-                 
+
              } else {
                  let slots = &mut node2.slots;
-                 let mut moved_slot = slots.pop().unwrap();
+                 let moved_slot = slots.pop().unwrap();
                  let mut moved_value = KeyRow{key: moved_slot.value, row: node2.cells.remove(&moved_slot.pointer).unwrap().values};
-                 let last_idx = &slots.len() -1;
-                 let new_bound = slots[last_idx].value.clone();
-                 match &mut slots[last_idx].value{
-                     Value::Number(_) => slots[last_idx].value = Value::Number(MAX_VALUE),
-                     Value::String(_, _) => slots[last_idx].value = Value::string("zzzzzzzz".to_string())
-                 }
+                 // The new last child keeps its divider as key, which now bounds the page in the parent.
+                 let new_bound = slots[slots.len() - 1].value.clone();
+                 node2.vacuum();
                  moved_value.key = internal_divider;
-                 let node1 = self.buffer_pool.get_mut(siblings[0].page_id);
-                 node1.insert(moved_value);
-                 let parent_node = self.buffer_pool.get_mut(parent);
+                 let node1 = self.buffer_pool.get_mut(siblings[0].page_id)?;
+                 node1.insert(moved_value)?;
+                 let parent_node = self.buffer_pool.get_mut(parent)?;
                  match &mut parent_node.page_type {
-                     NodeType::Internal(pkvs) => {
+                     NodeType::Internal => {
                          parent_node.slots[siblings[1].page_index].value = new_bound;
                      },
-                     _ => panic!("___")
+                     _ => return Err(not_internal(parent))
                  }
              }
            }
         }
        } else {
-           let node2 = self.buffer_pool.get_mut(siblings[1].page_id);
+           let node2 = self.buffer_pool.get_mut(siblings[1].page_id)?;
            match &mut node2.page_type {
-              NodeType::Leaf(kvs) => {
-                if total_cells.len() <= MAX_KEY {
-                    node2.clean_page();
-                    for kr in total_cells {
-                        node2.insert(kr);
-                    };
-
-                    self.buffer_pool.remove_page(siblings[0].page_id);
-                    let parent_node = self.buffer_pool.get_mut(parent);
-                    match &mut parent_node.page_type {
-                        NodeType::Internal(pkvs) => {
-                            let slot = parent_node.slots.remove(siblings[0].page_index);
-                            parent_node.cells.remove(&slot.pointer);
-                        },
-                        _ => panic!("___")
-                    }
+              NodeType::Leaf => {
+                if merge {
+                    self.merge_leaves(left, right, parent, total_cells)?;
                 } else {
                     let slots = &mut node2.slots;
                     let moved_slot = slots.remove(0);
                     let moved_value = KeyRow{key: moved_slot.value, row: node2.cells.remove(&moved_slot.pointer).unwrap().values};
-                    let new_bound = slots[0].value.clone();
-                    let node1 = self.buffer_pool.get_mut(siblings[0].page_id);
-                    node1.insert(moved_value);
-                    let parent_node = self.buffer_pool.get_mut(parent);
+                    let new_bound = node2.slots[0].value.clone();
+                    node2.vacuum();
+                    let node1 = self.buffer_pool.get_mut(siblings[0].page_id)?;
+                    node1.insert(moved_value)?;
+                    let parent_node = self.buffer_pool.get_mut(parent)?;
                     match &mut parent_node.page_type {
-                        NodeType::Internal(pkvs) => {
+                        NodeType::Internal => {
                             parent_node.slots[siblings[0].page_index].value = new_bound;
                         },
-                        _ => panic!("___")
+                        _ => return Err(not_internal(parent))
                     }
                 }
               },
 
-              NodeType::Internal(kvs) => {
-                if total_cells.len() <= MAX_CHILD {
-                    for i in 0..total_cells.len() {
-                        match  &total_cells[i].key {
-                            Value::Number(x) => {
-                               if x.clone() == MAX_VALUE as u32 {
-                                   total_cells[i].key = internal_divider;
-                                   break;
-                               }
-                            },
-                            Value::String(_, x) => {
-                               if x.clone() == "zzzzzzzz".to_string(){
-                                   total_cells[i].key = internal_divider;
-                                   break;
-                               }
-                            }
-                        }
-                    }
+              NodeType::Internal => {
+                if merge {
                     node2.clean_page();
                     for kr in total_cells{
-                        node2.insert(kr)
+                        node2.push(kr);
                     }
-                    self.buffer_pool.remove_page(siblings[0].page_id);
-                    let parent_node = self.buffer_pool.get_mut(parent);
+                    self.buffer_pool.remove_page(siblings[0].page_id)?;
+                    self.count_page(true, false);
+                    let parent_node = self.buffer_pool.get_mut(parent)?;
                     match &mut parent_node.page_type {
-                        NodeType::Internal(pkvs) => {
+                        NodeType::Internal => {
                             let slot = parent_node.slots.remove(siblings[0].page_index);
                             parent_node.cells.remove(&slot.pointer);
-                            
+                            parent_node.vacuum();
                         },
-                        _ => panic!("___")
+                        _ => return Err(not_internal(parent))
                     }
-                    
+
                 } else {
                     let slots = &mut node2.slots;
-                    let mut moved_slot = slots.remove(0);
-                    let mut moved_value = KeyRow{key: moved_slot.value, row: node2.cells.remove(&moved_slot.pointer).unwrap().values};
+                    let moved_slot = slots.remove(0);
+                    let moved_value = KeyRow{key: moved_slot.value, row: node2.cells.remove(&moved_slot.pointer).unwrap().values};
+                    node2.vacuum();
                     let new_bound = moved_value.key.clone();
-                    match &moved_value.key {
-                        Value::Number(_) => moved_value.key = Value::Number(MAX_VALUE),
-                        Value::String(_, _) => moved_value.key = Value::string("zzzzzzzz".to_string()),
-                    }
-                    let node1 = self.buffer_pool.get_mut(siblings[0].page_id);
+                    let node1 = self.buffer_pool.get_mut(siblings[0].page_id)?;
                     match &mut node1.page_type {
-                        NodeType::Internal(kvs1) => {
+                        NodeType::Internal => {
                             let slots = &mut node1.slots;
-                            let last_idx = &slots.len() -1;
+                            let last_idx = slots.len() -1;
                             slots[last_idx].value = internal_divider;
-                            
+
                         },
-                        _ => panic!("___")
+                        _ => return Err(not_internal(siblings[0].page_id))
                     }
-                    node1.insert(moved_value);
+                    // The moved child becomes the last one of the left node.
+                    node1.push(moved_value);
 
-                    let parent_node = self.buffer_pool.get_mut(parent);
+                    let parent_node = self.buffer_pool.get_mut(parent)?;
                     match &mut parent_node.page_type {
-                        NodeType::Internal(pkvs) => {
+                        NodeType::Internal => {
                             let slots = &mut parent_node.slots;
                             slots[siblings[0].page_index].value = new_bound;
                         },
-                        _ => panic!("___")
+                        _ => return Err(not_internal(parent))
                     }
                 }
               }
            }
        }
-       
-       
+
+       Ok(())
      }
-     
-     fn merge_root(&mut self, root_id: u32) -> bool{
-        let get_out = |vec: &Vec<Slot>, hash: &HashMap<u16, Rows>| -> Vec<KeyRow> {
-            vec.iter().map(|item| KeyRow{key: item.value.clone(), row: hash.get(&item.pointer).unwrap().values.clone()}).collect()
-        };
-        let root = self.buffer_pool.get(root_id);
-        let mut child_id: u32 = 0;
-        let mut cells: Vec<KeyRow> = Vec::new();
-        match &root.page_type {
-         NodeType::Internal(pkvs) => {
-            let slots = &root.slots;
-            if slots.len() > 1 {
-                return false
+
+     // Merged leaves end up in the left one so that the leaf before it keeps its link, the right one is removed
+     // and the leaf after it links back to the left one. The bound of the right leaf in the parent now points
+     // to the left leaf and the divider between them goes away.
+     fn merge_leaves(&mut self, left: &Siblings, right: &Siblings, parent: u32, total_cells: Vec<KeyRow>) -> Result<(), PlanckError> {
+        let right_page = self.buffer_pool.get(right.page_id)?;
+        if !matches!(right_page.page_type, NodeType::Leaf) {
+            return Err(not_leaf(right.page_id));
+        }
+        let next_leaf = right_page.next_leaf;
+        let node = self.buffer_pool.get_mut(left.page_id)?;
+        if !matches!(node.page_type, NodeType::Leaf) {
+            return Err(not_leaf(left.page_id));
+        }
+        node.clean_page();
+        for kr in total_cells {
+            node.insert(kr)?;
+        }
+        node.next_leaf = next_leaf;
+        self.buffer_pool.remove_page(right.page_id)?;
+        self.count_page(false, false);
+        if next_leaf != NO_PAGE {
+            self.buffer_pool.get_mut(next_leaf)?.prev_leaf = left.page_id;
+        }
+
+        let parent_node = self.buffer_pool.get_mut(parent)?;
+        let bound = parent_node.slots[right.page_index].pointer;
+        match parent_node.cells.get_mut(&bound) {
+            Some(row) => row.values[0] = Value::Number(left.page_id),
+            None => return Err(PlanckError::Corruption(format!("slot {} of page {} points to no cell", right.page_index, parent)))
+        }
+        let slot = parent_node.slots.remove(left.page_index);
+        parent_node.cells.remove(&slot.pointer);
+        parent_node.vacuum();
+        Ok(())
+     }
+
+     // A root left with a single child takes the child's place, the child is copied into the root page.
+     fn merge_root(&mut self, root_id: u32) -> Result<bool, PlanckError> {
+        let root = self.buffer_pool.get(root_id)?;
+        let child_id = match &root.page_type {
+         NodeType::Internal => {
+            match &root.slots[..] {
+                [only] => child_of(root, root_id, only)?,
+                [] => return Err(PlanckError::Corruption(format!("internal page {} has no children", root_id))),
+                _ => return Ok(false),
             }
-             child_id = root.cells.get(&slots[0].pointer).unwrap().values[0].extract_pointer();
          },
-         _ => panic!("__")
-        };
-        let child = self.buffer_pool.get(child_id);
-        match &child.page_type {
-          NodeType::Internal(kvs) => {
-             let output = get_out(&child.slots, &child.cells);
-             cells.extend(output.into_iter());
-          },
-          NodeType::Leaf(kvs) => {
-             return false
-          }
+         _ => return Err(not_internal(root_id))
         };
- 
-        self.buffer_pool.remove_page(child_id);
- 
-        let root = self.buffer_pool.get_mut(root_id);
-        root.clean_page();
-        for kr in cells {
-            root.insert(kr)
-        }
-        true
+        let child = self.buffer_pool.get(child_id)?.clone();
+        self.buffer_pool.remove_page(child_id)?;
+        *self.buffer_pool.get_mut(root_id)? = child;
+        let stats = &mut self.buffer_pool.file.tree_stats;
+        stats.internal_pages = stats.internal_pages.saturating_sub(1);
+        stats.height = stats.height.saturating_sub(1);
+        Ok(true)
+     }
+
+     // The root moved down into a new page under a new internal root.
+     fn grow(&mut self) {
+        let stats = &mut self.buffer_pool.file.tree_stats;
+        stats.internal_pages += 1;
+        stats.height += 1;
+     }
+
+     // Keeps the page counts of the table in step with the pages the tree gains and loses.
+     fn count_page(&mut self, is_internal: bool, added: bool) {
+        let stats = &mut self.buffer_pool.file.tree_stats;
+        let count = if is_internal { &mut stats.internal_pages } else { &mut stats.leaf_pages };
+        *count = if added { *count + 1 } else { count.saturating_sub(1) };
      }
-    
 
+
+}
+
+// Reads a tree in key order, or in reverse, one leaf at a time along the links between leaves. The cursor
+// borrows the tree, it cannot change while the cursor is in use.
+pub struct Cursor<'a> {
+    tree: &'a mut BPlusTree,
+    // Rows of the current leaf not returned yet, in the order of the cursor. Values on overflow pages are still stubs.
+    rows: vec::IntoIter<KeyRow>,
+    // Next leaf to read, the previous one in key order for a descending cursor.
+    following: u32,
+    // Where the cursor stops, the smallest key for a descending cursor.
+    stop: Bound<Value>,
+    descending: bool,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(tree: &'a mut BPlusTree, from: Bound<Value>, stop: Bound<Value>, descending: bool) -> Result<Self, PlanckError> {
+        let mut cursor = Cursor { tree, rows: Vec::new().into_iter(), following: NO_PAGE, stop, descending };
+        match from {
+            Bound::Included(key) => cursor.seek(&key)?,
+            Bound::Excluded(key) => {
+                cursor.seek(&key)?;
+                if cursor.rows.as_slice().first().is_some_and(|kr| kr.key == key) {
+                    cursor.rows.next();
+                }
+            }
+            Bound::Unbounded => {
+                let leaf = cursor.tree.edge_leaf(descending)?;
+                cursor.load(leaf)?;
+            }
+        }
+        Ok(cursor)
+    }
+
+    // Moves the cursor to the first row whose key is not smaller than the key, or not larger for a descending
+    // cursor. Where the cursor stops stays as it was.
+    pub fn seek(&mut self, key: &Value) -> Result<(), PlanckError> {
+        let (leaf, _) = self.tree.search(key)?;
+        self.load(leaf)?;
+        let descending = self.descending;
+        let rows: Vec<KeyRow> = self.rows.by_ref().skip_while(|kr| if descending { &kr.key > key } else { &kr.key < key }).collect();
+        self.rows = rows.into_iter();
+        Ok(())
+    }
+
+    fn load(&mut self, page_id: u32) -> Result<(), PlanckError> {
+        let page = self.tree.buffer_pool.get(page_id)?;
+        if !matches!(page.page_type, NodeType::Leaf) {
+            return Err(not_leaf(page_id));
+        }
+        let mut rows = Vec::with_capacity(page.slots.len());
+        for slot in &page.slots {
+            match page.cells.get(&slot.pointer) {
+                Some(row) => rows.push(KeyRow { key: slot.value.clone(), row: row.values.clone() }),
+                None => return Err(PlanckError::Corruption(format!("slot {} of page {} points to no cell", slot.value, page_id)))
+            }
+        }
+        if self.descending {
+            rows.reverse();
+            self.following = page.prev_leaf;
+        } else {
+            self.following = page.next_leaf;
+        }
+        self.rows = rows.into_iter();
+        Ok(())
+    }
+
+    fn past_stop(&self, key: &Value) -> bool {
+        match (&self.stop, self.descending) {
+            (Bound::Included(stop), false) => key > stop,
+            (Bound::Excluded(stop), false) => key >= stop,
+            (Bound::Included(stop), true) => key < stop,
+            (Bound::Excluded(stop), true) => key <= stop,
+            (Bound::Unbounded, _) => false,
+        }
+    }
+}
+
+impl Iterator for Cursor<'_> {
+    type Item = Result<KeyRow, PlanckError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(kr) = self.rows.next() {
+                if self.past_stop(&kr.key) {
+                    self.rows = Vec::new().into_iter();
+                    self.following = NO_PAGE;
+                    return None;
+                }
+                return Some(self.tree.buffer_pool.resolve_row(kr.row).map(|row| KeyRow { key: kr.key, row }));
+            }
+            if self.following == NO_PAGE {
+                return None;
+            }
+            if let Err(err) = self.load(self.following) {
+                self.following = NO_PAGE;
+                return Some(Err(err));
+            }
+        }
+    }
 }
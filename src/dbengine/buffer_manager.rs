@@ -1,6 +1,9 @@
 use crate::dbengine::pages::*;
 use crate::dbengine::engine::*;
+use crate::dbengine::storage::{SharedPager, lock};
+use crate::dbengine::error::PlanckError;
 use std::collections::HashMap;
+use std::mem;
 
 
 const POOL_SIZE: usize = 6;
@@ -11,10 +14,13 @@ pub struct Frame {
     page_id: u32,
     pin: bool,
     dirty: bool,
+    // Overflow chains of columns dropped by the upgrade of the page, freed once the page is written and synced.
+    dropped: Vec<u32>,
 }
 #[derive(Debug, Clone)]
 pub struct BufferPool {
     pub file: Table,
+    pub pager: SharedPager,
     pub pool: Vec<Frame>,
     pub table: HashMap<u32,usize>,
     clock: usize,
@@ -22,120 +28,296 @@ pub struct BufferPool {
 }
 
 impl BufferPool {
-    pub fn new(file: Table) -> Self {
-        BufferPool {file: file,pool: Vec::new(), table: HashMap::new(), clock: 0}
+    pub fn new(pager: SharedPager, file: Table) -> Self {
+        BufferPool {file, pager, pool: Vec::new(), table: HashMap::new(), clock: 0}
     }
 
-    fn get_index(&mut self, page_id: u32) -> Option<usize>{
-        let mut index = 0;
-        if self.table.contains_key(&page_id) {
-            return Some(*self.table.get(&page_id).unwrap());
+    fn get_index(&mut self, page_id: u32) -> Result<usize, PlanckError> {
+        if let Some(index) = self.table.get(&page_id) {
+            return Ok(*index);
         }
-        self.victim();
-        self.load_from_disk(page_id);
-        return Some(*self.table.get(&page_id).unwrap());
+        self.victim()?;
+        self.load_from_disk(page_id)?;
+        Ok(*self.table.get(&page_id).unwrap())
     }
 
-    pub fn get_mut(&mut self, page_id: u32) -> &mut Page{
-        let location = self.get_index(page_id).unwrap();
+    pub fn get_mut(&mut self, page_id: u32) -> Result<&mut Page, PlanckError> {
+        let location = self.get_index(page_id)?;
         let frame = &mut self.pool[location];
         frame.pin = true;
         frame.dirty = true;
-        &mut frame.page
+        Ok(&mut frame.page)
     }
 
-    pub fn get(&mut self, page_id: u32) -> &Page{
-        let location = self.get_index(page_id).unwrap();
+    pub fn get(&mut self, page_id: u32) -> Result<&Page, PlanckError> {
+        let location = self.get_index(page_id)?;
         let frame = &mut self.pool[location];
         frame.pin = true;
-        &frame.page
-    }
-
-    fn victim(&mut self) {
-        if self.pool.len() == POOL_SIZE {    
-            loop {
-                // Get the frame at the current clock position
-                let frame_index = self.clock % POOL_SIZE;
-                let frame = &mut self.pool[frame_index];
-
-                // Check if the frame is pinned
-                if frame.pin {
-                    frame.pin = false;
-                    self.clock += 1;
-                } else {
-                    // If not pinned, we can evict this frame
-                    // Remove the frame from the table
-                    self.table.remove(&frame.page_id);
-                    let frame = self.pool.remove(frame_index);
-                    if frame.dirty {
-                      self.flush_page(frame);  
-                    }
-                    // Update the clock position
-                    self.clock = (self.clock + 1) % POOL_SIZE;
-                    break;
-                }
+        Ok(&frame.page)
+    }
 
+    // A dirty page that does not fit yet (the B+ tree is about to split it) cannot be written, it is never
+    // picked. If every frame is like that the pool grows past POOL_SIZE for a moment.
+    fn victim(&mut self) -> Result<(), PlanckError> {
+        let mut tries = 2 * self.pool.len();
+        while self.pool.len() >= POOL_SIZE && tries > 0 {
+            tries -= 1;
+            // Get the frame at the current clock position
+            let frame_index = self.clock % self.pool.len();
+            let frame = &mut self.pool[frame_index];
 
+            // Check if the frame is pinned
+            if frame.pin {
+                frame.pin = false;
+                self.clock += 1;
+            } else if frame.dirty && !frame.page.fits() {
+                self.clock += 1;
+            } else {
+                // If not pinned, we can evict this frame
+                // Remove the frame from the table
+                self.table.remove(&frame.page_id);
+                let frame = self.pool.remove(frame_index);
+                self.reindex();
+                if frame.dirty {
+                  self.flush_page(frame)?;
+                }
+                // Update the clock position
+                self.clock = (self.clock + 1) % POOL_SIZE;
+                break;
             }
         }
+        Ok(())
     }
-    pub fn update_page(&mut self, page: Page,page_id: u32) {
-        let file = &mut self.file;
-        file.update_page(page.page_to_buff().unwrap(), page_id);
-        if self.table.contains_key(&page_id) {
-            self.pool[*self.table.get(&page_id).unwrap()].page = page;
+    pub fn update_page(&mut self, mut page: Page,page_id: u32) -> Result<(), PlanckError> {
+        // Every page in the pool holds rows of the current schema.
+        page.schema_version = self.file.schema_version;
+        lock(&self.pager).write_page(page.page_to_buff()?, page_id)?;
+        if let Some(index) = self.table.get(&page_id) {
+            self.pool[*index].page = page;
         }
+        Ok(())
+    }
+
+    // The new page may not fit yet, it is only written by the next flush, once it has been split.
+    pub fn create_page(&mut self, mut page: Page) -> Result<u32, PlanckError> {
+        page.schema_version = self.file.schema_version;
+        let page_id = lock(&self.pager).allocate(Page::new_leaf().page_to_buff()?)?;
+        self.victim()?;
+        self.pool.push(Frame {page, page_id, pin: true, dirty: true, dropped: Vec::new()});
+        self.table.insert(page_id, self.pool.len() - 1);
+        Ok(page_id)
     }
 
-    pub fn create_page(&mut self, page: Page) -> u32{
-        let file = &mut self.file;
-        let page_id = file.create_page(page.page_to_buff().unwrap());
-        self.get_mut(page_id);
-        return page_id;
+    // Writes a page to a new page of the file without keeping it in the pool, e.g. the pages of a bulk load.
+    pub fn write_new_page(&mut self, mut page: Page) -> Result<u32, PlanckError> {
+        page.schema_version = self.file.schema_version;
+        lock(&self.pager).allocate(page.page_to_buff()?)
     }
 
-    pub fn remove_page(&mut self, page_id: u32) {
-        if self.table.contains_key(&page_id) {
-            let idx = self.table.remove(&page_id).unwrap();
-            self.pool.remove(idx);
+    pub fn remove_page(&mut self, page_id: u32) -> Result<(), PlanckError> {
+        let mut dropped = Vec::new();
+        if let Some(idx) = self.table.remove(&page_id) {
+            dropped = self.pool.remove(idx).dropped;
         }
+        self.reindex();
+        
+        lock(&self.pager).free(page_id)?;
+        // The page is gone, nothing points to the chains of its dropped columns any more.
+        for first in dropped {
+            self.remove_overflow(first)?;
+        }
+        Ok(())
+    }
 
-        let mut index = 0;
-        for frame in &self.pool {
+    fn reindex(&mut self) {
+        for (index, frame) in self.pool.iter().enumerate() {
             *self.table.get_mut(&frame.page_id).unwrap() = index;
-            index += 1;
         }
-        
-        self.file.remove_page(page_id);
     }
 
-    fn load_from_disk(&mut self , page_id: u32) {
-       let buffer = self.file.read_page(page_id);
-       let frame_new = Frame {page: Page::buff_to_page(self.file.column_types.clone(), buffer), page_id: page_id, pin: false, dirty: false};
+    fn load_from_disk(&mut self , page_id: u32) -> Result<(), PlanckError> {
+       let pager = lock(&self.pager);
+       // The mapping of MmapStorage is decoded in place, the other storages hand out a copy of the page.
+       let copy;
+       let buffer = match pager.page_ref(page_id) {
+           Some(buffer) => buffer,
+           None => {
+               copy = pager.read_page(page_id)?;
+               &copy
+           }
+       };
+       if !verify_checksum(buffer) {
+           return Err(PlanckError::ChecksumMismatch { table: self.file.name.clone(), page_id });
+       }
+       // A file of an older format version is only read while Database rebuilds it, nothing is ever written back to it.
+       let format_version = pager.format_version();
+       let old_format = format_version != FORMAT_VERSION;
+       let version = buff_schema_version(buffer);
+       let upgrade = is_leaf_buff(buffer) && version != self.file.schema_version;
+       let types = if upgrade { self.file.types_of(version)? } else { &self.file.column_types };
+       let decoded = Page::old_buff_to_page(format_version, types, buffer)?;
+       drop(pager);
+       let frame_new = if upgrade {
+           // Written before an ALTER, the rows are upgraded now and the page is written back on the next flush.
+           let old = decoded;
+           let mut dropped = Vec::new();
+           let mut page = Page::new_leaf();
+           page.schema_version = self.file.schema_version;
+           page.next_leaf = old.next_leaf;
+           page.prev_leaf = old.prev_leaf;
+           for slot in old.slots {
+               let row = match old.cells.get(&slot.pointer) {
+                   Some(row) => row.values.clone(),
+                   None => return Err(PlanckError::Corruption(format!("slot {} of page {} points to no cell", slot.value, page_id)))
+               };
+               let chains: Vec<u32> = overflow_pages(&row).collect();
+               let row = self.file.upgrade_row(version, row)?;
+               // The page on disk points to the chains of dropped columns until the upgraded page replaces it,
+               // they are only freed after that (see free_dropped).
+               if !old_format {
+                   dropped.extend(chains.into_iter().filter(|first| !overflow_pages(&row).any(|kept| kept == *first)));
+               }
+               page.insert(KeyRow { key: slot.value, row })?;
+           }
+           Frame {page, page_id, pin: false, dirty: !old_format, dropped}
+       } else {
+           Frame {page: decoded, page_id, pin: false, dirty: false, dropped: Vec::new()}
+       };
        self.pool.push(frame_new);
-       self.table.insert(page_id, 11);
-       let mut index = 0;
-       for frame in &self.pool {
-           *self.table.get_mut(&frame.page_id).unwrap() = index;
-           index += 1;
-       }   
+       self.table.insert(page_id, self.pool.len() - 1);
+       Ok(())
     }
 
-    fn flush_page(&self, frame: Frame) {
-       let table = &self.file;
-       table.update_page(frame.page.page_to_buff().unwrap(), frame.page_id);
+    // Values that do not fit in a cell are written to a chain of overflow pages and replaced by a stub
+    // (Value::Overflow): first the strings and bytes longer than MAX_INLINE_VALUE, then the longest ones
+    // until the row fits in MAX_CELL_SIZE.
+    pub fn spill_row(&mut self, mut row: Vec<Value>) -> Result<Vec<Value>, PlanckError> {
+        let mut created = Vec::new();
+        loop {
+            let too_big = cell_size(&row) - 2 > MAX_CELL_SIZE;
+            let candidate = row.iter().enumerate()
+                .filter(|(_, value)| matches!(value, Value::String(_, _) | Value::Bytes(_)))
+                .filter(|(_, value)| value.encoded_size() > 1 + MAX_INLINE_VALUE || (too_big && value.encoded_size() > 1 + 8 + OVERFLOW_PREFIX))
+                .max_by_key(|(_, value)| value.encoded_size())
+                .map(|(index, _)| index);
+            let index = match candidate {
+                Some(index) => index,
+                None => break,
+            };
+            let (types, data) = match mem::replace(&mut row[index], Value::Null) {
+                Value::String(_, string) => (STRING_TYPE, string.into_bytes()),
+                Value::Bytes(bytes) => (BYTES_TYPE, bytes),
+                _ => unreachable!(),
+            };
+            let page = self.create_overflow(&data)?;
+            created.push(page);
+            let prefix = data[..data.len().min(OVERFLOW_PREFIX)].to_vec();
+            row[index] = Value::Overflow { types, length: data.len() as u32, page, prefix };
+        }
+        if cell_size(&row) - 2 > MAX_CELL_SIZE {
+            for page in created {
+                self.remove_overflow(page)?;
+            }
+            return Err(PlanckError::SchemaMismatch(format!("a row of table {} takes more than {} bytes without its strings and bytes", self.file.name, MAX_CELL_SIZE)));
+        }
+        Ok(row)
     }
 
-    
-    
-    pub fn flush_all(&mut self) {
-        let file = &self.file;
-        for frame in &self.pool {
-            if frame.dirty {
-                file.update_page(frame.page.page_to_buff().unwrap(), frame.page_id);
+    // Replaces the overflow stubs of a row with the values they stand for.
+    pub fn resolve_row(&self, row: Vec<Value>) -> Result<Vec<Value>, PlanckError> {
+        row.into_iter().map(|value| match value {
+            Value::Overflow { types, length, page, .. } => {
+                let (_, data) = self.read_overflow(page)?;
+                if data.len() != length as usize {
+                    return Err(PlanckError::Corruption(format!("overflow chain at page {} of table {} holds {} bytes instead of {}", page, self.file.name, data.len(), length)));
+                }
+                match types {
+                    STRING_TYPE => Ok(Value::string(String::from_utf8(data).map_err(|err| err.utf8_error())?)),
+                    _ => Ok(Value::Bytes(data)),
+                }
             }
+            value => Ok(value),
+        }).collect()
+    }
+
+    // Frees the overflow chains a row points to, once the row is gone from its page.
+    pub fn remove_row_overflow(&mut self, row: &[Value]) -> Result<(), PlanckError> {
+        for first in overflow_pages(row) {
+            self.remove_overflow(first)?;
         }
+        Ok(())
     }
-}
 
+    pub fn create_overflow(&mut self, data: &[u8]) -> Result<u32, PlanckError> {
+        let mut pager = lock(&self.pager);
+        let mut next = NO_PAGE;
+        // Written from the end, so that every page already knows the id of the next one.
+        for chunk in data.chunks(OVERFLOW_DATA_SIZE).rev() {
+            next = pager.allocate(overflow_page_to_buff(next, chunk))?;
+        }
+        Ok(next)
+    }
 
+    // Ids and data of the overflow chain starting at the given page.
+    pub fn read_overflow(&self, first: u32) -> Result<(Vec<u32>, Vec<u8>), PlanckError> {
+        let pager = lock(&self.pager);
+        let mut page_ids = Vec::new();
+        let mut data = Vec::new();
+        let mut page_id = first;
+        while page_id != NO_PAGE {
+            if page_ids.len() as u32 >= pager.len() {
+                return Err(PlanckError::Corruption(format!("overflow chain at page {} of table {} loops", first, self.file.name)));
+            }
+            let buffer = pager.read_page(page_id)?;
+            if !verify_checksum(&buffer) {
+                return Err(PlanckError::ChecksumMismatch { table: self.file.name.clone(), page_id });
+            }
+            let (next, bytes) = buff_to_overflow_page(&buffer)?;
+            page_ids.push(page_id);
+            data.extend_from_slice(bytes);
+            page_id = next;
+        }
+        Ok((page_ids, data))
+    }
+
+    pub fn remove_overflow(&mut self, first: u32) -> Result<(), PlanckError> {
+        let (page_ids, _) = self.read_overflow(first)?;
+        let mut pager = lock(&self.pager);
+        for page_id in page_ids {
+            pager.free(page_id)?;
+        }
+        Ok(())
+    }
+
+    fn flush_page(&mut self, frame: Frame) -> Result<(), PlanckError> {
+       lock(&self.pager).write_page(frame.page.page_to_buff()?, frame.page_id)?;
+       self.free_dropped(frame.dropped)
+    }
+
+    // Frees the chains of dropped columns once the upgraded pages that stopped pointing to them are written.
+    // They are synced first, like drop_table never leaves the catalog pointing to free pages.
+    fn free_dropped(&mut self, dropped: Vec<u32>) -> Result<(), PlanckError> {
+        if dropped.is_empty() {
+            return Ok(());
+        }
+        lock(&self.pager).sync()?;
+        for first in dropped {
+            self.remove_overflow(first)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush_all(&mut self) -> Result<(), PlanckError> {
+        let mut dropped = Vec::new();
+        let mut pager = lock(&self.pager);
+        for frame in &mut self.pool {
+            if frame.dirty {
+                pager.write_page(frame.page.page_to_buff()?, frame.page_id)?;
+                frame.dirty = false;
+                dropped.append(&mut frame.dropped);
+            }
+        }
+        drop(pager);
+        self.free_dropped(dropped)
+    }
+}
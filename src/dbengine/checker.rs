@@ -0,0 +1,521 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::path::Path;
+
+use crate::dbengine::btrees::NodeType;
+use crate::dbengine::engine::*;
+use crate::dbengine::error::PlanckError;
+use crate::dbengine::pages::*;
+use crate::dbengine::storage::StorageBackend;
+
+// Offline consistency checker. It reads the database file page by page without going through the
+// buffer pool, so it never writes anything and keeps going after the first problem it finds.
+
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub table: String,
+    pub page_id: Option<u32>,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.page_id {
+            Some(page_id) => write!(f, "{} page {}: {}", self.table, page_id, self.message),
+            None => write!(f, "{}: {}", self.table, self.message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Owner {
+    Tree(String),
+    FreeList,
+}
+
+struct Checker {
+    storage: FileStorage,
+    owners: HashMap<u32, Owner>,
+    violations: Vec<Violation>,
+    // Leaves of the tree being checked in key order, with the next and previous leaves each one links to.
+    leaves: Vec<(u32, u32, u32)>,
+}
+
+impl Checker {
+    fn report(&mut self, table: &str, page_id: Option<u32>, message: String) {
+        self.violations.push(Violation { table: table.to_string(), page_id, message });
+    }
+
+    // Marks the page as used by `owner`, false if it cannot be used (out of bounds or already used).
+    fn claim(&mut self, table: &str, page_id: u32, owner: Owner) -> bool {
+        if page_id >= self.storage.page_id_count {
+            self.report(table, Some(page_id), format!("page id is out of bounds, the file has {} pages", self.storage.page_id_count));
+            return false;
+        }
+        if let Some(previous) = self.owners.get(&page_id) {
+            let message = match previous {
+                Owner::Tree(other) => format!("page is already used by table {}", other),
+                Owner::FreeList => "page is on the free list".to_string(),
+            };
+            self.report(table, Some(page_id), message);
+            return false;
+        }
+        self.owners.insert(page_id, owner);
+        true
+    }
+
+    fn check_free_list(&mut self) {
+        let mut page_id = self.storage.free_list_head;
+        let mut count = 0;
+        while page_id != NO_PAGE {
+            if !self.claim("free list", page_id, Owner::FreeList) {
+                // Either out of bounds or a cycle, the rest of the list cannot be trusted.
+                break;
+            }
+            count += 1;
+            let buffer = match self.storage.read_page(page_id) {
+                Ok(buffer) => buffer,
+                Err(err) => {
+                    self.report("free list", Some(page_id), err.to_string());
+                    break;
+                }
+            };
+            match buff_to_free_page(&buffer) {
+                Ok(next) => page_id = next,
+                Err(_) => {
+                    self.report("free list", Some(page_id), "page on the free list is not a free page".to_string());
+                    break;
+                }
+            }
+        }
+        if count != self.storage.free_page_num {
+            self.report("free list", None, format!("header counts {} free pages but the list has {}", self.storage.free_page_num, count));
+        }
+    }
+
+    fn read(&self, table: &Table, page_id: u32) -> Result<Page, String> {
+        let buffer = self.storage.read_page(page_id).map_err(|err| err.to_string())?;
+        if !verify_checksum(&buffer) {
+            return Err("checksum mismatch".to_string());
+        }
+        let types = match is_leaf_buff(&buffer) {
+            true => table.types_of(buff_schema_version(&buffer)).map_err(|err| err.to_string())?.to_vec(),
+            false => Vec::new(),
+        };
+        Page::buff_to_page(&types, &buffer).map_err(|err| err.to_string())
+    }
+
+    // Reads and decodes a page of the table, every problem is reported and ends with None.
+    fn load(&mut self, table: &Table, page_id: u32) -> Option<Page> {
+        match self.read(table, page_id) {
+            Ok(page) => Some(page),
+            Err(message) => {
+                self.report(&table.name, Some(page_id), message);
+                None
+            }
+        }
+    }
+
+    // Slot array and cells must not overlap, cells must stay in the page and must not overlap each other,
+    // and the free space pointer must point to the lowest cell.
+    fn check_layout(&mut self, table: &str, page_id: u32, page: &Page) {
+        let slots_end = PAGE_HEADER_SIZE + page.slots.iter().map(|slot| slot_size(&slot.value)).sum::<usize>();
+        let mut cells: Vec<(usize, usize)> = Vec::new();
+        for slot in &page.slots {
+            let size = match page.cells.get(&slot.pointer) {
+                Some(row) => row.size as usize + 2,
+                None => {
+                    self.report(table, Some(page_id), format!("slot {} points to no cell", slot.value));
+                    continue;
+                }
+            };
+            let start = slot.pointer as usize;
+            if start < slots_end || start + size > PAGE_SIZE {
+                self.report(table, Some(page_id), format!("cell of key {} at offset {} is outside of the cell area", slot.value, start));
+            }
+            cells.push((start, start + size));
+        }
+        cells.sort();
+        for pair in cells.windows(2) {
+            if pair[0].1 > pair[1].0 {
+                self.report(table, Some(page_id), format!("cells at offsets {} and {} overlap", pair[0].0, pair[1].0));
+            }
+        }
+        let lowest = cells.first().map(|cell| cell.0 as u16).unwrap_or(4096);
+        if page.free_space_pointer != lowest {
+            self.report(table, Some(page_id), format!("free space pointer is {} but the lowest cell starts at {}", page.free_space_pointer, lowest));
+        }
+    }
+
+    // Checks the subtree under page_id, every key has to be in [lower, upper).
+    // Returns the depth of its leaves, None if the subtree could not be read.
+    fn check_node(&mut self, table: &Table, page_id: u32, lower: Option<&Value>, upper: Option<&Value>, is_root: bool) -> Option<usize> {
+        if !self.claim(&table.name, page_id, Owner::Tree(table.name.clone())) {
+            return None;
+        }
+        let page = self.load(table, page_id)?;
+        self.check_layout(&table.name, page_id, &page);
+
+        // The key of the last slot of an internal page is never compared, only the dividers before it have an order.
+        let ordered = match page.page_type {
+            NodeType::Internal => &page.slots[..page.slots.len().saturating_sub(1)],
+            NodeType::Leaf => &page.slots[..],
+        };
+        for pair in ordered.windows(2) {
+            if pair[0].value >= pair[1].value {
+                self.report(&table.name, Some(page_id), format!("keys {} and {} are out of order", pair[0].value, pair[1].value));
+            }
+        }
+
+        match page.page_type {
+            NodeType::Leaf => {
+                for slot in &page.slots {
+                    let too_low = lower.is_some_and(|lower| &slot.value < lower);
+                    let too_high = upper.is_some_and(|upper| &slot.value >= upper);
+                    if too_low || too_high {
+                        self.report(&table.name, Some(page_id), format!("key {} is outside of the range its parent gives", slot.value));
+                    }
+                    if let Some(row) = page.cells.get(&slot.pointer) {
+                        for value in &row.values {
+                            if let Value::Overflow { length, page: first, .. } = value {
+                                self.check_overflow(&table.name, page_id, *first, *length);
+                            }
+                        }
+                        self.check_row_key(table, page_id, page.schema_version, &slot.value, &row.values);
+                    }
+                }
+                self.leaves.push((page_id, page.next_leaf, page.prev_leaf));
+                Some(0)
+            }
+            NodeType::Internal => {
+                if page.slots.is_empty() {
+                    self.report(&table.name, Some(page_id), "internal page has no children".to_string());
+                    return None;
+                }
+                if !is_root && page.slots.len() < 2 {
+                    self.report(&table.name, Some(page_id), "internal page has a single child".to_string());
+                }
+                let mut depth = None;
+                let last = page.slots.len() - 1;
+                for (index, slot) in page.slots.iter().enumerate() {
+                    let child = match page.cells.get(&slot.pointer).map(|row| row.values.first().map(Value::extract_pointer)) {
+                        Some(Some(Ok(child))) => child,
+                        _ => {
+                            self.report(&table.name, Some(page_id), format!("separator {} has no child pointer", slot.value));
+                            continue;
+                        }
+                    };
+                    // Separators are upper bounds of their child, the key of the last slot is never compared.
+                    let child_lower = if index == 0 { lower } else { Some(&page.slots[index - 1].value) };
+                    let child_upper = if index == last { upper } else { Some(&slot.value) };
+                    if index != last {
+                        let above_upper = upper.is_some_and(|upper| &slot.value > upper);
+                        let below_lower = lower.is_some_and(|lower| &slot.value < lower);
+                        if above_upper || below_lower {
+                            self.report(&table.name, Some(page_id), format!("separator {} is outside of the range its parent gives", slot.value));
+                        }
+                    }
+                    if let Some(child_depth) = self.check_node(table, child, child_lower, child_upper, false) {
+                        match depth {
+                            None => depth = Some(child_depth),
+                            Some(depth) if depth != child_depth => {
+                                self.report(&table.name, Some(page_id), format!("child {} has leaves at depth {}, its siblings at {}", child, child_depth, depth));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                depth.map(|depth| depth + 1)
+            }
+        }
+    }
+
+    // Claims the pages of an overflow chain and checks that it holds as many bytes as the stub in the leaf says.
+    fn check_overflow(&mut self, table: &str, leaf_id: u32, first: u32, length: u32) {
+        let mut page_id = first;
+        let mut bytes = 0;
+        while page_id != NO_PAGE {
+            if !self.claim(table, page_id, Owner::Tree(table.to_string())) {
+                return;
+            }
+            let buffer = match self.storage.read_page(page_id) {
+                Ok(buffer) => buffer,
+                Err(err) => return self.report(table, Some(page_id), err.to_string()),
+            };
+            if !verify_checksum(&buffer) {
+                return self.report(table, Some(page_id), "checksum mismatch".to_string());
+            }
+            match buff_to_overflow_page(&buffer) {
+                Ok((next, data)) => {
+                    bytes += data.len();
+                    page_id = next;
+                }
+                Err(err) => return self.report(table, Some(page_id), err.to_string()),
+            }
+        }
+        if bytes != length as usize {
+            self.report(table, Some(leaf_id), format!("overflow chain at page {} holds {} bytes instead of {}", first, bytes, length));
+        }
+    }
+
+    // The key of a slot has to be the value of the key columns of its row. The catalog and the schemas tree keep
+    // their key out of the row, and key values moved to overflow pages are not read back.
+    fn check_row_key(&mut self, table: &Table, page_id: u32, version: u16, key: &Value, row: &[Value]) {
+        if table.name == CATALOG_NAME || table.name == SCHEMAS_NAME {
+            return;
+        }
+        let row = match version == table.schema_version {
+            true => row.to_vec(),
+            false => match table.upgrade_row(version, row.to_vec()) {
+                Ok(row) => row,
+                Err(_) => return,
+            },
+        };
+        if table.key_indexes().iter().any(|index| matches!(row.get(*index), Some(Value::Overflow { .. }))) {
+            return;
+        }
+        match table.key_of(&row) {
+            Ok(row_key) if &row_key == key => {}
+            _ => self.report(&table.name, Some(page_id), format!("key {} does not match the key columns of its row", key)),
+        }
+    }
+
+    fn check_tree(&mut self, table: &Table) {
+        let reported = self.violations.len();
+        self.check_node(table, table.root_node_offset, None, None, true);
+        let leaves = mem::take(&mut self.leaves);
+        // Leaves of a broken tree are missing from the list, the chain would only repeat what was reported.
+        if self.violations.len() != reported {
+            return;
+        }
+        let name = |page_id: u32| if page_id == NO_PAGE { "none".to_string() } else { page_id.to_string() };
+        for (index, (page_id, next_leaf, prev_leaf)) in leaves.iter().enumerate() {
+            let after = leaves.get(index + 1).map_or(NO_PAGE, |leaf| leaf.0);
+            if *next_leaf != after {
+                self.report(&table.name, Some(*page_id), format!("next leaf is {} but the leaf after it in key order is {}", name(*next_leaf), name(after)));
+            }
+            let before = index.checked_sub(1).map_or(NO_PAGE, |before| leaves[before].0);
+            if *prev_leaf != before {
+                self.report(&table.name, Some(*page_id), format!("previous leaf is {} but the leaf before it in key order is {}", name(*prev_leaf), name(before)));
+            }
+        }
+    }
+
+    // Every row of a tree whose pages were already claimed by check_tree.
+    fn rows(&mut self, table: &Table) -> Vec<KeyRow> {
+        let mut rows = Vec::new();
+        let mut stack = vec![table.root_node_offset];
+        let mut seen = 0;
+        while let Some(page_id) = stack.pop() {
+            // A corrupted tree may point in circles, the checker already reported it.
+            seen += 1;
+            if seen > self.storage.page_id_count || self.owners.get(&page_id) != Some(&Owner::Tree(table.name.clone())) {
+                continue;
+            }
+            // Problems were reported by check_tree already.
+            let page = match self.read(table, page_id) {
+                Ok(page) => page,
+                Err(_) => continue,
+            };
+            for slot in page.slots.iter().rev() {
+                let row = match page.cells.get(&slot.pointer) {
+                    Some(row) => row.values.clone(),
+                    None => continue,
+                };
+                match page.page_type {
+                    NodeType::Internal => {
+                        if let Some(Ok(child)) = row.first().map(Value::extract_pointer) {
+                            stack.push(child);
+                        }
+                    }
+                    NodeType::Leaf => rows.push(KeyRow { key: slot.value.clone(), row }),
+                }
+            }
+        }
+        rows
+    }
+}
+
+pub fn check_database(dir: &Path) -> Result<Vec<Violation>, PlanckError> {
+    // Read-only, the checker works on a read-only copy too and cannot change the file.
+    let storage = FileStorage::open_readable(&dir.join(DATABASE_FILE))?;
+    storage.check_current()?;
+    let mut checker = Checker { storage, owners: HashMap::new(), violations: Vec::new(), leaves: Vec::new() };
+
+    checker.check_free_list();
+
+    let catalog = Table::catalog(CATALOG_ROOT);
+    let schemas = Table::schemas(SCHEMAS_ROOT);
+    checker.check_tree(&catalog);
+    checker.check_tree(&schemas);
+
+    let mut old_schemas = HashMap::new();
+    for kr in checker.rows(&schemas) {
+        if let Value::String(_, key) = kr.key {
+            old_schemas.insert(key, kr.row);
+        }
+    }
+
+    for kr in checker.rows(&catalog) {
+        let name = match kr.key {
+            Value::String(_, name) => name,
+            key => {
+                checker.report(CATALOG_NAME, None, format!("key {} is not a table name", key));
+                continue;
+            }
+        };
+        let mut table = match Table::from_catalog_row(&name, &kr.row) {
+            Ok(table) => table,
+            Err(err) => {
+                checker.report(CATALOG_NAME, None, err.to_string());
+                continue;
+            }
+        };
+        for version in 0..table.schema_version {
+            if let Some(row) = old_schemas.get(&table.schema_key(version)) {
+                if let Err(err) = table.add_old_schema(version, row) {
+                    checker.report(SCHEMAS_NAME, None, err.to_string());
+                }
+            }
+        }
+        checker.check_tree(&table);
+        let rows = checker.rows(&table).len() as u32;
+        if rows != table.row_count {
+            checker.report(&name, None, format!("catalog counts {} rows but the tree has {}", table.row_count, rows));
+        }
+    }
+
+    for page_id in 0..checker.storage.page_id_count {
+        if !checker.owners.contains_key(&page_id) {
+            checker.report("file", Some(page_id), "page is neither used by a table nor on the free list".to_string());
+        }
+    }
+    Ok(checker.violations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::database::Database;
+
+    // A closed database with a users table of a few leaves under an internal root.
+    fn users_dir(name: &str) -> (PathBuf, Table) {
+        let dir = std::env::temp_dir().join(format!("planck_checker_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut db = Database::open(&dir).unwrap();
+        db.create_table("users", 0, vec!["id".to_string(), "name".to_string()], vec![NUMBER_TYPE, STRING_TYPE]).unwrap();
+        for id in 0..600 {
+            db.insert("users", vec![Value::Number(id), Value::string("n".repeat(100))]).unwrap();
+        }
+        for id in 0..300 {
+            db.delete("users", Value::Number(id * 2)).unwrap();
+        }
+        let table = db.table("users").unwrap().clone();
+        (dir, table)
+    }
+
+    fn storage(dir: &Path) -> FileStorage {
+        FileStorage::open(&dir.join(DATABASE_FILE)).unwrap()
+    }
+
+    // Decodes a page of the table, lets `change` alter it and writes it back with a valid checksum.
+    fn rewrite_page(dir: &Path, table: &Table, page_id: u32, change: impl FnOnce(&mut Page)) {
+        let mut storage = storage(dir);
+        let buffer = storage.read_page(page_id).unwrap();
+        let types = if is_leaf_buff(&buffer) { table.column_types.clone() } else { Vec::new() };
+        let mut page = Page::buff_to_page(&types, &buffer).unwrap();
+        change(&mut page);
+        storage.write_page(page.page_to_buff().unwrap(), page_id).unwrap();
+        storage.sync().unwrap();
+    }
+
+    fn leaves(dir: &Path, table: &Table) -> Vec<u32> {
+        let root = Page::buff_to_page(&[], &storage(dir).read_page(table.root_node_offset).unwrap()).unwrap();
+        assert!(matches!(root.page_type, NodeType::Internal));
+        root.slots.iter().map(|slot| root.cells[&slot.pointer].values[0].extract_pointer().unwrap()).collect()
+    }
+
+    fn messages(dir: &Path) -> Vec<String> {
+        check_database(dir).unwrap().iter().map(Violation::to_string).collect()
+    }
+
+    #[test]
+    fn healthy_database_has_no_violations() {
+        let (dir, _) = users_dir("healthy");
+        assert_eq!(messages(&dir), Vec::<String>::new());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checker_opens_the_file_read_only() {
+        let (dir, _) = users_dir("read_only");
+        let path = dir.join(DATABASE_FILE);
+        let before = fs::read(&path).unwrap();
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&path, permissions).unwrap();
+        assert_eq!(messages(&dir), Vec::<String>::new());
+        assert_eq!(fs::read(&path).unwrap(), before);
+        // Writes fail even where the permissions would let them through, e.g. for root.
+        let mut storage = FileStorage::open_readable(&path).unwrap();
+        assert!(storage.write_page([0; PAGE_SIZE], 0).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn broken_leaf_link_is_reported() {
+        let (dir, table) = users_dir("link");
+        let leaves = leaves(&dir, &table);
+        rewrite_page(&dir, &table, leaves[1], |page| page.next_leaf = leaves[1]);
+        let expected = format!("users page {}: next leaf is {} but the leaf after it in key order is {}", leaves[1], leaves[1], leaves[2]);
+        assert_eq!(messages(&dir), vec![expected]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checksum_mismatch_is_reported_and_refused() {
+        let (dir, table) = users_dir("checksum");
+        let leaf = leaves(&dir, &table)[0];
+        let mut storage = storage(&dir);
+        let mut buffer = storage.read_page(leaf).unwrap();
+        buffer[PAGE_SIZE - 1] ^= 1;
+        storage.write_page(buffer, leaf).unwrap();
+        storage.sync().unwrap();
+        assert!(messages(&dir).contains(&format!("users page {}: checksum mismatch", leaf)));
+
+        let mut db = Database::open(&dir).unwrap();
+        assert!(matches!(db.read("users", Value::Number(1)), Err(PlanckError::ChecksumMismatch { .. })));
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unused_page_is_reported() {
+        let (dir, _) = users_dir("unused");
+        let mut storage = storage(&dir);
+        let page_id = storage.allocate(Page::new_leaf().page_to_buff().unwrap()).unwrap();
+        storage.sync().unwrap();
+        let expected = format!("file page {}: page is neither used by a table nor on the free list", page_id);
+        assert_eq!(messages(&dir), vec![expected]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn internal_page_without_children_is_corruption() {
+        let (dir, table) = users_dir("childless");
+        rewrite_page(&dir, &table, table.root_node_offset, |page| *page = Page::new_internal());
+        let root = format!("users page {}: internal page has no children", table.root_node_offset);
+        assert!(messages(&dir).contains(&root));
+
+        let mut db = Database::open(&dir).unwrap();
+        assert!(matches!(db.read("users", Value::Number(1)), Err(PlanckError::Corruption(_))));
+        assert!(matches!(db.insert("users", vec![Value::Number(1000), Value::string("n".to_string())]), Err(PlanckError::Corruption(_))));
+        assert!(matches!(db.delete("users", Value::Number(1)), Err(PlanckError::Corruption(_))));
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+// Calendar dates and timestamps, always UTC and proleptic Gregorian.
+// Dates are days since 1970-01-01 and timestamps are milliseconds since 1970-01-01T00:00:00.
+
+const MS_PER_DAY: i64 = 86_400_000;
+
+// Days since 1970-01-01 of a civil date (Howard Hinnant's days_from_civil).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn number(part: &str, digits: usize) -> Option<i64> {
+    if part.len() != digits || !part.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    part.parse().ok()
+}
+
+// Parses YYYY-MM-DD.
+pub fn parse_date(input: &str) -> Option<i32> {
+    let mut parts = input.splitn(3, '-');
+    let year = number(parts.next()?, 4)?;
+    let month = number(parts.next()?, 2)?;
+    let day = number(parts.next()?, 2)?;
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+    Some(days_from_civil(year, month, day) as i32)
+}
+
+// Parses YYYY-MM-DDTHH:MM:SS with optional milliseconds (.mmm) and an optional trailing Z.
+pub fn parse_timestamp(input: &str) -> Option<i64> {
+    let input = input.strip_suffix('Z').unwrap_or(input);
+    let (date, time) = input.split_once('T')?;
+    let (time, millis) = match time.split_once('.') {
+        Some((time, millis)) => (time, number(millis, 3)?),
+        None => (time, 0),
+    };
+    let mut parts = time.splitn(3, ':');
+    let hour = number(parts.next()?, 2)?;
+    let minute = number(parts.next()?, 2)?;
+    let second = number(parts.next()?, 2)?;
+    if hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    let days = parse_date(date)? as i64;
+    Some(days * MS_PER_DAY + ((hour * 60 + minute) * 60 + second) * 1000 + millis)
+}
+
+pub fn format_date(days: i32) -> String {
+    let (year, month, day) = civil_from_days(days as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+pub fn format_timestamp(millis: i64) -> String {
+    let days = millis.div_euclid(MS_PER_DAY);
+    let in_day = millis.rem_euclid(MS_PER_DAY);
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day,
+        in_day / 3_600_000, in_day / 60_000 % 60, in_day / 1000 % 60, in_day % 1000)
+}
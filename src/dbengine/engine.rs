@@ -1,22 +1,392 @@
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Write, Seek, SeekFrom, IoSliceMut};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::collections::HashMap;
 use std::fmt;
-// use crate::dbengine::btrees::*;
-use crate::dbengine::pages::Page;
+use std::path::{Path, PathBuf};
+use crate::dbengine::checksum::crc32_update;
+use crate::dbengine::error::PlanckError;
+use crate::dbengine::pages::{Value, PAGE_HEADER_SIZE, free_page_to_buff, buff_to_free_page, base_type, is_column_type, is_nullable, type_name, v11_page_to_buff, verify_checksum};
+use crate::dbengine::storage::StorageBackend;
 
+// A database is a single file. It starts with a HEADER_SIZE byte header, pages of every table come right after it.
+// Header layout:
+//   0..8   magic bytes "PLANCKDB"
+//   8..10  format version
+//   10..12 page size
+//   12..14 reserved
+//   14..16 flags
+//   16..20 CRC-32 of the header (computed with these 4 bytes left out)
+//   20..32 reserved
+//   32..36 page_id_count
+//   36..40 free_page_num
+//   40..44 free_list_head
+//   44..   reserved
+// The root of the catalog is always page 0, it is the first page a new database allocates.
+// Page 1 is the root of the tree that keeps the older schemas of altered tables.
+pub const MAGIC: &[u8; 8] = b"PLANCKDB";
+pub const FORMAT_VERSION: u16 = 14;
+// Files of these versions are rebuilt by Database::open: the leaves of versions 12 and 13 are not linked both
+// ways, and the string keys of version 12 are ordered by length first.
+pub const V13_FORMAT_VERSION: u16 = 13;
+pub const V12_FORMAT_VERSION: u16 = 12;
+// Files of this version are converted to version 12 by FileStorage::migrate.
+pub const V11_FORMAT_VERSION: u16 = 11;
+// Marks the end of a page chain (the free list for example)
+pub const NO_PAGE: u32 = u32::MAX;
+pub const PAGE_SIZE: usize = 4096;
+pub const HEADER_SIZE: usize = 4096;
+pub const DATABASE_FILE: &str = "planck.db";
+pub const CATALOG_NAME: &str = "__catalog";
+pub const CATALOG_ROOT: u32 = 0;
+pub const SCHEMAS_NAME: &str = "__schemas";
+pub const SCHEMAS_ROOT: u32 = 1;
 
+fn bytes_to_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([buffer[offset], buffer[offset + 1], buffer[offset + 2], buffer[offset + 3]])
+}
+
+fn header_checksum(buffer: &[u8; HEADER_SIZE]) -> u32 {
+    crc32_update(crc32_update(0, &buffer[0..16]), &buffer[20..])
+}
+
+// The file storage owns the database file: its header, the page space and the free list.
+#[derive(Debug)]
+pub struct FileStorage {
+    // Full path of the database file, e.g. "PlanckDB/planck.db".
+    pub path: PathBuf,
+    // Opened once, every page is read and written with positioned I/O so no call has to open or seek the file.
+    file: File,
+    // Flags of the file header, reserved for later use.
+    pub flags: u16,
+    pub page_id_count: u32,
+    pub free_page_num: u32,
+    // First page of the free list, NO_PAGE when there are no free pages
+    pub free_list_head: u32,
+    // FORMAT_VERSION, or V12_FORMAT_VERSION and V13_FORMAT_VERSION for a file that is about to be rebuilt.
+    pub format_version: u16,
+}
+
+impl FileStorage {
+    // Creates a new database file without any pages.
+    pub fn create(path: &Path) -> Result<FileStorage, PlanckError> {
+        let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+
+        let storage = FileStorage { path: path.to_path_buf(), file, flags: 0, page_id_count: 0, free_page_num: 0, free_list_head: NO_PAGE, format_version: FORMAT_VERSION };
+        storage.update_header()?;
+        Ok(storage)
+    }
+
+    pub fn open(path: &Path) -> Result<FileStorage, PlanckError> {
+        let storage = FileStorage::open_file(path, true)?;
+        storage.check_current()?;
+        Ok(storage)
+    }
+
+    // Opens the file read-only, every write to it fails. Files of versions 12 and 13 are opened too, their B+ tree
+    // pages are read with Page::old_buff_to_page: Database only does that to rebuild the file, and the checker
+    // rejects them with check_current.
+    pub fn open_readable(path: &Path) -> Result<FileStorage, PlanckError> {
+        FileStorage::open_file(path, false)
+    }
+
+    pub fn check_current(&self) -> Result<(), PlanckError> {
+        if self.format_version != FORMAT_VERSION {
+            return Err(PlanckError::UnsupportedFormat(format!("{} has format version {}, opening it with Database::open converts it to version {}", self.path.display(), self.format_version, FORMAT_VERSION)));
+        }
+        Ok(())
+    }
+
+    fn open_file(path: &Path, write: bool) -> Result<FileStorage, PlanckError> {
+        let file = match OpenOptions::new().read(true).write(write).open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(PlanckError::NotFound(format!("database {}", path.display()))),
+            Err(err) => return Err(PlanckError::Io(err)),
+        };
+
+        let mut buffer = [0u8; HEADER_SIZE];
+        match file.read_exact_at(&mut buffer, 0) {
+            Ok(_) => {},
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Err(PlanckError::UnsupportedFormat(format!("{} is too short to be a PlanckDB file", path.display()))),
+            Err(err) => return Err(PlanckError::Io(err)),
+        }
+        FileStorage::buff_to_header(path.to_path_buf(), file, &buffer)
+    }
+
+    // Converts a file of version 11 to version 12, other files are left alone. Database::open calls it before
+    // opening the file and then rebuilds a version 12 or 13 file, the checker never does either.
+    pub fn migrate(path: &Path) -> Result<(), PlanckError> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let mut header = [0u8; HEADER_SIZE];
+        if file.read_exact_at(&mut header, 0).is_err() || &header[0..8] != MAGIC {
+            // Not a database file at all, open reports it.
+            return Ok(());
+        }
+        match u16::from_be_bytes([header[8], header[9]]) {
+            V11_FORMAT_VERSION => FileStorage::migrate_v11(path, file, &header),
+            _ => Ok(()),
+        }
+    }
+
+    // Version 11 counted the slots of a page in a single byte. Its B+ tree pages are converted into a copy of
+    // the file (free and overflow pages did not change) and the copy is renamed over it, a crash leaves
+    // either the old file or the converted one. Pages with a bad checksum are copied as they are.
+    fn migrate_v11(path: &Path, file: File, header: &[u8; HEADER_SIZE]) -> Result<(), PlanckError> {
+        if u32::from_be_bytes([header[16], header[17], header[18], header[19]]) != header_checksum(header) {
+            return Err(PlanckError::Corruption(format!("header checksum of {} does not match", path.display())));
+        }
+        let mut converted = *header;
+        converted[8..10].copy_from_slice(&V12_FORMAT_VERSION.to_be_bytes());
+        let checksum = header_checksum(&converted);
+        converted[16..20].copy_from_slice(&checksum.to_be_bytes());
+
+        let target_path = path.with_extension("migrate");
+        let target_file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&target_path)?;
+        let mut target = FileStorage::buff_to_header(target_path.clone(), target_file, &converted)?;
+        for page_id in 0..target.page_id_count {
+            let mut buffer = [0u8; PAGE_SIZE];
+            file.read_exact_at(&mut buffer, FileStorage::page_offset(page_id))?;
+            if matches!(buffer[0], 0 | 1) && verify_checksum(&buffer) {
+                buffer = v11_page_to_buff(&buffer)?;
+            }
+            target.write_page(buffer, page_id)?;
+        }
+        target.update_header()?;
+        target.sync()?;
+        drop(target);
+        fs::rename(&target_path, path)?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    pub fn header_to_buff(&self) -> [u8; HEADER_SIZE] {
+        let mut buffer = [0u8; HEADER_SIZE];
+        buffer[0..8].copy_from_slice(MAGIC);
+        buffer[8..10].copy_from_slice(&self.format_version.to_be_bytes());
+        buffer[10..12].copy_from_slice(&(PAGE_SIZE as u16).to_be_bytes());
+        buffer[14..16].copy_from_slice(&self.flags.to_be_bytes());
+        // 16..20 is the checksum, 20..32 is reserved for later use
+        buffer[32..36].copy_from_slice(&self.page_id_count.to_be_bytes());
+        buffer[36..40].copy_from_slice(&self.free_page_num.to_be_bytes());
+        buffer[40..44].copy_from_slice(&self.free_list_head.to_be_bytes());
+
+        let checksum = header_checksum(&buffer);
+        buffer[16..20].copy_from_slice(&checksum.to_be_bytes());
+        buffer
+    }
+
+    pub fn buff_to_header(path: PathBuf, file: File, buffer: &[u8; HEADER_SIZE]) -> Result<FileStorage, PlanckError> {
+        if &buffer[0..8] != MAGIC {
+            return Err(PlanckError::UnsupportedFormat(format!("{} is not a PlanckDB file", path.display())));
+        }
+        let version = u16::from_be_bytes([buffer[8], buffer[9]]);
+        if version == V11_FORMAT_VERSION {
+            return Err(PlanckError::UnsupportedFormat(format!("{} has format version {}, opening it with Database::open converts it to version {}", path.display(), version, FORMAT_VERSION)));
+        }
+        if !matches!(version, FORMAT_VERSION | V13_FORMAT_VERSION | V12_FORMAT_VERSION) {
+            return Err(PlanckError::UnsupportedFormat(format!("{} has format version {}, this build only reads version {}", path.display(), version, FORMAT_VERSION)));
+        }
+        let page_size = u16::from_be_bytes([buffer[10], buffer[11]]);
+        if page_size as usize != PAGE_SIZE {
+            return Err(PlanckError::UnsupportedFormat(format!("{} uses {} byte pages, this build only supports {}", path.display(), page_size, PAGE_SIZE)));
+        }
+        let stored_checksum = u32::from_be_bytes([buffer[16], buffer[17], buffer[18], buffer[19]]);
+        if stored_checksum != header_checksum(buffer) {
+            return Err(PlanckError::Corruption(format!("header checksum of {} does not match", path.display())));
+        }
+        let flags = u16::from_be_bytes([buffer[14], buffer[15]]);
+        let page_id_count = bytes_to_u32(buffer, 32);
+        let free_page_num = bytes_to_u32(buffer, 36);
+        let free_list_head = bytes_to_u32(buffer, 40);
+        if free_page_num > page_id_count || (free_list_head != NO_PAGE && free_list_head >= page_id_count) {
+            return Err(PlanckError::Corruption(format!("free list of {} is out of bounds", path.display())));
+        }
+
+        Ok(FileStorage { path, file, flags, page_id_count, free_page_num, free_list_head, format_version: version })
+    }
+
+    pub fn update_header(&self) -> Result<(), PlanckError> {
+        self.file.write_all_at(&self.header_to_buff(), 0)?;
+        Ok(())
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn page_offset(page_id: u32) -> u64 {
+        HEADER_SIZE as u64 + page_id as u64 * PAGE_SIZE as u64
+    }
+
+}
+
+impl StorageBackend for FileStorage {
+    fn allocate(&mut self, buffer: [u8;4096]) -> Result<u32, PlanckError> {
+        let page_id = if self.free_list_head != NO_PAGE {
+            // Reuse the first page of the free list, the list continues from the page it points to.
+            let free_page_id = self.free_list_head;
+            let next = match buff_to_free_page(&self.read_page(free_page_id)?) {
+                Ok(next) => next,
+                Err(_) => return Err(PlanckError::Corruption(format!("page {} is on the free list but it is not a free page", free_page_id)))
+            };
+            self.free_list_head = next;
+            self.free_page_num -= 1;
+            free_page_id
+        } else {
+            self.page_id_count += 1;
+            self.page_id_count - 1
+        };
+        self.write_page(buffer, page_id)?;
+        self.update_header()?;
+        Ok(page_id)
+    }
+
+    fn write_page(&mut self, buffer: [u8;4096], page_id: u32) -> Result<(), PlanckError> {
+        self.file.write_all_at(&buffer, FileStorage::page_offset(page_id))?;
+        Ok(())
+    }
+
+    fn read_page(&self, page_id: u32) -> Result<[u8;4096], PlanckError> {
+        if page_id >= self.page_id_count {
+            return Err(PlanckError::Corruption(format!("page {} is out of bounds of {}", page_id, self.path.display())));
+        }
+        let mut buffer: [u8;4096] = [0; 4096];
+        self.file.read_exact_at(&mut buffer, FileStorage::page_offset(page_id))?;
+        Ok(buffer)
+    }
+
+    // Freed pages are linked together, each one keeps the id of the next free page so the list has no size limit.
+    fn free(&mut self, page_id: u32) -> Result<(), PlanckError> {
+        self.write_page(free_page_to_buff(self.free_list_head), page_id)?;
+        self.free_list_head = page_id;
+        self.free_page_num += 1;
+
+        self.update_header()
+    }
+
+    fn sync(&mut self) -> Result<(), PlanckError> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn len(&self) -> u32 {
+        self.page_id_count
+    }
+
+    fn free_pages(&self) -> u32 {
+        self.free_page_num
+    }
+
+    fn format_version(&self) -> u16 {
+        self.format_version
+    }
+}
+
+
+// A table is an entry of the catalog: its schema, the page its B+ tree starts from and some statistics.
 #[derive(Debug, Clone)]
 pub struct Table {
     pub name: String,
+    // Index of the primary key column, the key of a row is the value of that column.
     pub pk_column: u8,
-    // Column number is actually the end of the column bytes so sorry for the naming I was confussed too.
-    pub column_number: u8,
+    // Indexes of the columns of a composite key in key order (pk_column is the first one), empty for every other table.
+    pub key_columns: Vec<u8>,
     pub column_names: Vec<String>,
     pub column_types: Vec<u8>,
-    pub page_id_count: u32,
+    // Every column keeps its id for life, renaming or dropping other columns does not change it.
+    pub column_ids: Vec<u16>,
+    pub next_column_id: u16,
+    // Bumped by every ALTER, leaf pages remember the version their rows were written with.
+    pub schema_version: u16,
+    // Column ids and types of the older versions, used to read pages that were not upgraded yet.
+    pub old_schemas: HashMap<u16, (Vec<u16>, Vec<u8>)>,
+    // Page id of the root of the table's B+ tree, it only moves when adding a column rebuilds the tree.
     pub root_node_offset: u32,
-    pub free_page_num: u32,
-    pub free_page_list: Vec<u32>
+    pub row_count: u32,
+    // Shape of the tree, see TreeStats for which numbers are kept up to date.
+    pub tree_stats: TreeStats,
+}
+
+// Shape of a table's B+ tree, kept in the catalog. The page counts and the height follow every split and merge,
+// the byte counts are only a snapshot of the last walk of the tree (rewrite, vacuum, bulk load, adding a column).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TreeStats {
+    pub leaf_pages: u32,
+    pub internal_pages: u32,
+    pub height: u32,
+    // Bytes of headers, slots and cells over every page of the tree.
+    pub bytes_used: u32,
+    // Bytes used by the emptiest page.
+    pub min_page_bytes: u32,
+}
+
+impl TreeStats {
+    // A tree made of one empty leaf, the tree of a new table.
+    pub fn single_leaf() -> Self {
+        TreeStats { leaf_pages: 1, internal_pages: 0, height: 1, bytes_used: PAGE_HEADER_SIZE as u32, min_page_bytes: PAGE_HEADER_SIZE as u32 }
+    }
+}
+
+// Everything the stats command reports about a table.
+#[derive(Debug, Clone)]
+pub struct TableStats {
+    pub name: String,
+    pub row_count: u32,
+    pub tree: TreeStats,
+    // Free pages of the whole database file, every table takes its new pages from them.
+    pub free_pages: u32,
+    // Number of pages by fill: 0-10%, 10-20%, ... 90-100%
+    pub fill_histogram: [u32; 10],
+}
+
+impl TableStats {
+    pub fn pages(&self) -> u32 {
+        self.tree.leaf_pages + self.tree.internal_pages
+    }
+
+    // Average fill of the pages of the tree, in percent.
+    pub fn average_fill(&self) -> f64 {
+        match self.pages() {
+            0 => 0.0,
+            pages => self.tree.bytes_used as f64 * 100.0 / (pages as f64 * PAGE_SIZE as f64),
+        }
+    }
+
+    pub fn min_fill(&self) -> f64 {
+        self.tree.min_page_bytes as f64 * 100.0 / PAGE_SIZE as f64
+    }
+}
+
+impl fmt::Display for TableStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Table: {}", self.name)?;
+        writeln!(f, "Rows: {}", self.row_count)?;
+        writeln!(f, "Leaf Pages: {}", self.tree.leaf_pages)?;
+        writeln!(f, "Internal Pages: {}", self.tree.internal_pages)?;
+        writeln!(f, "Height: {}", self.tree.height)?;
+        writeln!(f, "Bytes Used: {}", self.tree.bytes_used)?;
+        writeln!(f, "Average Fill: {:.1}%", self.average_fill())?;
+        writeln!(f, "Minimum Fill: {:.1}%", self.min_fill())?;
+        writeln!(f, "Free Pages (database): {}", self.free_pages)?;
+        writeln!(f, "Fill Histogram:")?;
+        for (bucket, count) in self.fill_histogram.iter().enumerate() {
+            writeln!(f, "  {:>3}-{:<3}% | {}", bucket * 10, bucket * 10 + 10, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AlterTable {
+    AddColumn(String, u8),
+    DropColumn(String),
+    RenameColumn(String, String),
 }
 
 impl fmt::Display for Table {
@@ -30,232 +400,304 @@ impl fmt::Display for Table {
     }
 }
 
+// Columns are stored in the catalog as "id type name,id type name,..."
+fn columns_to_string(ids: &[u16], types: &[u8], names: &[String]) -> String {
+    let columns: Vec<String> = ids.iter().zip(types.iter()).zip(names.iter()).map(|((id, types), name)| format!("{} {} {}", id, types, name)).collect();
+    columns.join(",")
+}
+
+fn string_to_columns(columns: &str) -> Option<(Vec<u16>, Vec<u8>, Vec<String>)> {
+    let mut ids = Vec::new();
+    let mut types = Vec::new();
+    let mut names = Vec::new();
+    for column in columns.split(',').filter(|column| !column.is_empty()) {
+        let mut parts = column.splitn(3, ' ');
+        ids.push(parts.next()?.parse::<u16>().ok()?);
+        types.push(parts.next()?.parse::<u8>().ok()?);
+        names.push(parts.next()?.to_string());
+    }
+    Some((ids, types, names))
+}
+
+// Columns of a composite key are stored in the catalog as "0,2".
+fn indexes_to_string(indexes: &[u8]) -> String {
+    indexes.iter().map(|index| index.to_string()).collect::<Vec<String>>().join(",")
+}
+
+fn string_to_indexes(indexes: &str) -> Option<Vec<u8>> {
+    indexes.split(',').filter(|index| !index.is_empty()).map(|index| index.parse::<u8>().ok()).collect()
+}
+
 impl Table {
 
+    pub fn new(table_name: &str, pk_index: u8, field_names: Vec<String>, field_types: Vec<u8>, root: u32) -> Self {
+        let column_ids: Vec<u16> = (0..field_names.len() as u16).collect();
+        Table {name: table_name.to_string(), pk_column: pk_index, key_columns: Vec::new(), next_column_id: column_ids.len() as u16, column_ids, column_names: field_names, column_types: field_types,
+            schema_version: 0, old_schemas: HashMap::new(), root_node_offset: root, row_count: 0, tree_stats: TreeStats::single_leaf() }
+    }
+
+    // The catalog is a B+ tree too: table name -> root page, pk column, columns, row count, schema version, next column id,
+    // the tree stats and the columns of a composite key.
+    // Its rows, like the ones of the schemas tree, do not hold their key, pk_column means nothing for them.
+    pub fn catalog(root: u32) -> Self {
+        Table::new(CATALOG_NAME, 0,
+            vec!["root".to_string(), "pk".to_string(), "columns".to_string(), "rows".to_string(), "version".to_string(), "next_column".to_string(),
+                "leaf_pages".to_string(), "internal_pages".to_string(), "height".to_string(), "bytes_used".to_string(), "min_page_bytes".to_string(),
+                "key_columns".to_string()],
+            vec![2, 2, 7, 2, 2, 2, 2, 2, 2, 2, 2, 7], root)
+    }
+
+    // Older schemas of every table, keyed by "table/version".
+    pub fn schemas(root: u32) -> Self {
+        Table::new(SCHEMAS_NAME, 0, vec!["columns".to_string()], vec![7], root)
+    }
+
     pub fn print(&self) {
          // Print the table header
-         println!("{:<15} | {:<15} | {:<15} | {:<15}", "Column Number", "Column Name", "Column Type", "Page ID Count");
-         println!("{:-<60}", ""); // Separator line
- 
+         println!("{:<15} | {:<15} | {:<15}", "Column Number", "Column Name", "Column Type");
+         println!("{:-<50}", ""); // Separator line
+
          // Print the column data
          for (index, column_name) in self.column_names.iter().enumerate() {
-             let column_type = match self.column_types[index] {
-                 7 => "String",
-                 2 => "Number",
-                 _ => "Unknown",
+             let column_type = match is_nullable(self.column_types[index]) {
+                 true => format!("{} NULL", type_name(self.column_types[index])),
+                 false => type_name(self.column_types[index]).to_string(),
              };
- 
-             println!("{:<15} | {:<15} | {:<15} | {:<15}", index + 1, column_name, column_type, self.page_id_count);
+
+             println!("{:<15} | {:<15} | {:<15}", index + 1, column_name, column_type);
          }
- 
+
          // Print additional metadata
          println!("\nRoot Node Offset: {}", self.root_node_offset);
-         println!("Free Page Num: {}", self.free_page_num);
-         println!("Free Page List: {:?}", self.free_page_list);
-         println!("Primary Key Column Index: {}", self.pk_column);
+         println!("Row Count: {}", self.row_count);
+         println!("Primary Key: {}", self.key_names().join(", "));
+         println!("Schema Version: {}", self.schema_version);
     }
-    pub fn new(table_name: &str,pk_index: u8, field_names: Vec<String>, field_types: Vec<u8>) -> Self {
-        let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)  // This will create the file if it doesn't exist
-        .open(format!("{}{}{}", "PlanckDB/", table_name, ".db")).unwrap();
-        
-        // file.write_all(header.as_bytes()).unwrap();
-        let root_node_offset: u32 = 0;
-        let  free_page_num: u32 = 0;
-        let free_page_list: Vec<u32> = Vec::new();
-        let page_id_count: u32 = 0;
-        let mut cursor = 32;
-        file.seek(SeekFrom::Start(cursor)).unwrap(); // Assuming header is 32 bytes
-        file.write_all(&[11 as u8]).unwrap();
-        file.write_all(&[pk_index]).unwrap();
-        file.write_all(&[field_types.len() as u8]).unwrap();
-        cursor += 3;
-        let mut end_size = 0;
-        let mut i = 0;
-        for field in &field_names {
-            file.write_all(&[field_types[i]]).unwrap();
-            let size = field.as_bytes().len() as u8;
-            file.write_all(&[size]).unwrap();
-            file.write_all(field.as_bytes()).unwrap();
-            end_size += size + 2;
-            i += 1;
-        }
-        cursor += end_size as u64;
-        file.seek(SeekFrom::Start(32)).unwrap();
-        file.write_all(&[end_size]).unwrap();
-        file.seek(SeekFrom::Start(cursor)).unwrap(); // Assuming header is 32 bytes
-        
-        file.write_all(&page_id_count.to_be_bytes()).unwrap();
-        file.write_all(&root_node_offset.to_be_bytes()).unwrap();
-        file.write_all(&free_page_num.to_be_bytes()).unwrap();
-        
-        for page in &free_page_list {
-            file.write_all(&page.to_be_bytes()).unwrap();
-        }
-        
-        let mut table = Table {name: table_name.to_string(), pk_column: pk_index,column_number: end_size, column_names: field_names, column_types: field_types, page_id_count: page_id_count, root_node_offset: root_node_offset, free_page_num: free_page_num, free_page_list: free_page_list };
-        table.create_page(Page::new_leaf().page_to_buff().unwrap());
-        table
-    }
-
-    pub fn update_table(&self) {
-        let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)  // This will create the file if it doesn't exist
-        .open(format!("{}{}{}", "PlanckDB/", self.name, ".db")).unwrap();
-        
-        // file.write_all(header.as_bytes()).unwrap();
-        let root_node_offset: u32 = self.root_node_offset;
-        let  free_page_num: u32 = self.free_page_num;
-        let free_page_list: Vec<u32> = self.free_page_list.clone();
-        let page_id_count: u32 = self.page_id_count;
-        let field_names = self.column_names.clone();
-        let field_types = self.column_types.clone();
-        let pk_index = self.pk_column;
-        let mut cursor = 32;
-        file.seek(SeekFrom::Start(cursor)).unwrap(); // Assuming header is 32 bytes
-        file.write_all(&[11 as u8]).unwrap();
-        file.write_all(&[pk_index]).unwrap();
-        file.write_all(&[field_types.len() as u8]).unwrap();
-        cursor += 3;
-        let mut end_size = 0;
-        let mut i = 0;
-        for field in &field_names {
-            file.write_all(&[field_types[i]]).unwrap();
-            let size = field.as_bytes().len() as u8;
-            file.write_all(&[size]).unwrap();
-            file.write_all(field.as_bytes()).unwrap();
-            end_size += size + 2;
-            i += 1;
-        }
-        cursor += end_size as u64;
-        file.seek(SeekFrom::Start(32)).unwrap();
-        file.write_all(&[end_size]).unwrap();
-        file.seek(SeekFrom::Start(cursor)).unwrap(); // Assuming header is 32 bytes
-
-        file.write_all(&page_id_count.to_be_bytes()).unwrap();
-        file.write_all(&root_node_offset.to_be_bytes()).unwrap();
-        file.write_all(&free_page_num.to_be_bytes()).unwrap();
-
-        for page in &free_page_list {
-            file.write_all(&page.to_be_bytes()).unwrap();
-        }
-    }
-    
-    pub fn read_table(table_name: &str) -> Table {
-        let mut file = OpenOptions::new()
-        .read(true)
-        .write(false) 
-        .open(format!("{}{}{}", "PlanckDB/", table_name, ".db")).unwrap();
-
-        let mut column_number: u8;
-        let mut pk_index: u8 ;
-        let mut column_names: Vec<String>= Vec::new();
-        let mut column_types: Vec<u8>  = Vec::new();
-        let mut page_id_count: u32;
-        let mut root_node_offset: u32;
-        let mut free_page_num: u32;
-        let mut free_page_list: Vec<u32> = Vec::new();
-        file.seek(SeekFrom::Start(32)).unwrap();
-        let mut temp4: [u8; 4] = [0;4];
-        let mut temp2: [u8; 2] = [0;2];
-        let mut temp1: [u8; 1] = [0;1];
-        file.read(&mut temp1).unwrap();
-        column_number = temp1[0];
-        file.read(&mut temp1).unwrap();
-        pk_index = temp1[0];
-        file.read(&mut temp1).unwrap();
-        let len = temp1[0];
-        for ix in 0..len {
-            file.read(&mut temp1).unwrap();
-            column_types.push(temp1[0]);
-            file.read(&mut temp1).unwrap();
-            let str_len = temp1[0];   
-            let mut buff = vec![0; str_len as usize];
-            file.read(&mut buff).unwrap();
-            column_names.push(String::from_utf8(buff).unwrap()); 
-        }
-        file.read(&mut temp4).unwrap();
-        page_id_count = u32::from_be_bytes(temp4);
-        file.read(&mut temp4).unwrap();
-        root_node_offset= u32::from_be_bytes(temp4);
-        file.read(&mut temp4).unwrap();
-        free_page_num = u32::from_be_bytes(temp4);
-        for id in 0..free_page_num {
-            file.read(&mut temp4).unwrap();
-            free_page_list.push(u32::from_be_bytes(temp4));
-        }
-
-        Table {name: table_name.to_string(),pk_column: pk_index,column_number: column_number, column_names: column_names, column_types: column_types, page_id_count: page_id_count, root_node_offset: root_node_offset, free_page_num: free_page_num, free_page_list: free_page_list }
-    }   
-
-
-    pub fn create_page(&mut self, buffer: [u8;4096]) -> u32{
-
-        let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(false)  // This will create the file if it doesn't exist
-        .open(format!("{}{}{}", "PlanckDB/", self.name, ".db")).unwrap();
-        let header_end = (47 + self.column_number as u32 + 100 * 4) as u64;
-        let page_id_new = self.page_id_count;
-        if self.free_page_list.len() != 0 {
-            let free_page_id = self.free_page_list.pop().unwrap();
-            file.seek(SeekFrom::Start(header_end + free_page_id as u64 * 4096)).unwrap();
-            file.write_all(&buffer).unwrap();
-            self.update_table();
-            return  free_page_id;
-        } else {
-            file.seek(SeekFrom::Start(header_end + page_id_new as u64 * 4096)).unwrap();
-            file.write_all(&buffer).unwrap(); 
-            self.page_id_count += 1;
-            self.update_table();
-            return page_id_new;
+
+    pub fn columns_string(&self) -> String {
+        columns_to_string(&self.column_ids, &self.column_types, &self.column_names)
+    }
+
+    pub fn to_catalog_row(&self) -> Vec<Value> {
+        vec![Value::Number(self.root_node_offset), Value::Number(self.pk_column as u32), Value::string(self.columns_string()), Value::Number(self.row_count),
+            Value::Number(self.schema_version as u32), Value::Number(self.next_column_id as u32),
+            Value::Number(self.tree_stats.leaf_pages), Value::Number(self.tree_stats.internal_pages), Value::Number(self.tree_stats.height),
+            Value::Number(self.tree_stats.bytes_used), Value::Number(self.tree_stats.min_page_bytes), Value::string(indexes_to_string(&self.key_columns))]
+    }
+
+    pub fn from_catalog_row(table_name: &str, row: &[Value]) -> Result<Table, PlanckError> {
+        let corrupted = || PlanckError::Corruption(format!("catalog entry of table {} is corrupted", table_name));
+        let (root, pk, columns, rows, version, next_column, stats, key_columns) = match row {
+            [Value::Number(root), Value::Number(pk), Value::String(_, columns), Value::Number(rows), Value::Number(version), Value::Number(next_column),
+             Value::Number(leaf_pages), Value::Number(internal_pages), Value::Number(height), Value::Number(bytes_used), Value::Number(min_page_bytes),
+             Value::String(_, key_columns)] =>
+                (*root, *pk, columns, *rows, *version, *next_column,
+                 TreeStats { leaf_pages: *leaf_pages, internal_pages: *internal_pages, height: *height, bytes_used: *bytes_used, min_page_bytes: *min_page_bytes },
+                 key_columns),
+            _ => return Err(corrupted())
+        };
+        let (column_ids, column_types, column_names) = string_to_columns(columns).ok_or_else(corrupted)?;
+        let mut table = Table::new(table_name, pk as u8, column_names, column_types, root);
+        table.column_ids = column_ids;
+        table.next_column_id = next_column as u16;
+        table.schema_version = version as u16;
+        table.row_count = rows;
+        table.tree_stats = stats;
+        table.key_columns = string_to_indexes(key_columns).ok_or_else(corrupted)?;
+        Ok(table)
+    }
+
+    pub fn schema_key(&self, version: u16) -> String {
+        format!("{}/{}", self.name, version)
+    }
+
+    // Row of the schemas tree that describes the current version, saved before an ALTER replaces it.
+    pub fn add_old_schema(&mut self, version: u16, row: &[Value]) -> Result<(), PlanckError> {
+        let (ids, types, _) = match row {
+            [Value::String(_, columns)] => string_to_columns(columns),
+            _ => None
+        }.ok_or_else(|| PlanckError::Corruption(format!("schema {} of table {} is corrupted", version, self.name)))?;
+        self.old_schemas.insert(version, (ids, types));
+        Ok(())
+    }
+
+    // A row has to have a value of the right type for every column, NULL only where the column is nullable.
+    pub fn check_row(&self, row: &[Value]) -> Result<(), PlanckError> {
+        if row.len() != self.column_types.len() {
+            return Err(PlanckError::SchemaMismatch(format!("row has {} values but table {} has {} columns", row.len(), self.name, self.column_types.len())));
         }
-       
+        for ((value, types), name) in row.iter().zip(self.column_types.iter()).zip(self.column_names.iter()) {
+            match value {
+                Value::Null if !is_nullable(*types) => {
+                    return Err(PlanckError::SchemaMismatch(format!("column {} of table {} is not nullable", name, self.name)));
+                }
+                Value::Null => {}
+                value if value.type_code() != base_type(*types) => {
+                    return Err(PlanckError::SchemaMismatch(format!("{} is not a {} for column {}", value, type_name(*types), name)));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
     }
 
-    pub fn update_page(&self, buffer: [u8;4096], page_id: u32){
-        
-        let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(false)  // This will create the file if it doesn't exist
-        .open(format!("{}{}{}", "PlanckDB/", self.name, ".db")).unwrap();
-        let header_end = (47 + self.column_number as u32 + 100 * 4) as u64;
-        
-        file.seek(SeekFrom::Start(header_end + page_id as u64 * 4096)).unwrap();
-        file.write_all(&buffer).unwrap();
+    pub fn is_composite(&self) -> bool {
+        !self.key_columns.is_empty()
+    }
 
+    // Indexes of the columns the key is made of, in key order.
+    pub fn key_indexes(&self) -> Vec<usize> {
+        match self.is_composite() {
+            true => self.key_columns.iter().map(|index| *index as usize).collect(),
+            false => vec![self.pk_column as usize],
+        }
     }
 
-    pub fn read_page(&self, page_id: u32) -> [u8;4096] {
-        let mut file = OpenOptions::new()
-        .read(true)
-        .write(false) // This will create the file if it doesn't exist
-        .open(format!("{}{}{}", "PlanckDB/", self.name, ".db")).unwrap();
-        let header_end = (47 + self.column_number as u32 + 100 * 4) as u64;
-        let mut buffer: [u8;4096] = [0; 4096];
-        file.seek(SeekFrom::Start(header_end + page_id as u64 * 4096)).unwrap();
-        file.read(&mut buffer).unwrap();
-        return buffer;
+    // Types of the key columns, in key order.
+    pub fn key_types(&self) -> Vec<u8> {
+        self.key_indexes().into_iter().filter_map(|index| self.column_types.get(index).copied()).collect()
     }
-    pub fn remove_page(&mut self, page_id: u32) {
-        self.update_page([0;4096], page_id);
-        self.free_page_num += 1;
-        self.free_page_list.push(page_id);
 
-        while self.free_page_list.len() >= 100 {
-            self.free_page_list.remove(0);
-            self.free_page_num -= 1;
+    pub fn key_names(&self) -> Vec<&str> {
+        self.key_indexes().into_iter().filter_map(|index| self.column_names.get(index).map(String::as_str)).collect()
+    }
+
+    // Key columns have to exist, they cannot be nullable and a column is part of the key at most once.
+    pub fn check_key_columns(&self) -> Result<(), PlanckError> {
+        let indexes = self.key_indexes();
+        for (position, index) in indexes.iter().enumerate() {
+            match self.column_types.get(*index) {
+                None => return Err(PlanckError::SchemaMismatch(format!("table {} has no column {} for its key", self.name, index))),
+                Some(types) if is_nullable(*types) => {
+                    return Err(PlanckError::SchemaMismatch(format!("key column {} of table {} cannot be nullable", self.column_names[*index], self.name)));
+                }
+                _ if indexes[..position].contains(index) => {
+                    return Err(PlanckError::SchemaMismatch(format!("column {} is twice in the key of table {}", self.column_names[*index], self.name)));
+                }
+                _ => {}
+            }
         }
+        if self.is_composite() && (indexes.len() < 2 || self.pk_column as usize != indexes[0]) {
+            return Err(PlanckError::SchemaMismatch(format!("the composite key of table {} needs at least two columns", self.name)));
+        }
+        Ok(())
+    }
 
-        self.update_table();
+    // Key of a row that passed check_row: the value of the primary key column, or the composite of the key columns.
+    pub fn key_of(&self, row: &[Value]) -> Result<Value, PlanckError> {
+        let values: Vec<Value> = self.key_indexes().into_iter().map(|index| row.get(index).cloned().unwrap_or(Value::Null)).collect();
+        match self.is_composite() {
+            true => Value::composite(&values),
+            false => Ok(values.into_iter().next().unwrap_or(Value::Null)),
+        }
     }
-}
 
+    // A key given to read or delete has to have the type of the key column, one value per column for a composite key.
+    pub fn check_key(&self, key: &Value) -> Result<(), PlanckError> {
+        if !self.is_composite() {
+            let types = self.column_types.get(self.pk_column as usize).copied().unwrap_or(0);
+            if key.type_code() != base_type(types) {
+                return Err(PlanckError::SchemaMismatch(format!("{} is not a {} for the key of table {}", key, type_name(types), self.name)));
+            }
+            return Ok(());
+        }
+        let values = key.components()?;
+        if values.len() != self.key_columns.len() {
+            return Err(PlanckError::SchemaMismatch(format!("key {} has {} values but the key of table {} has {}", key, values.len(), self.name, self.key_columns.len())));
+        }
+        self.check_key_prefix(&values)
+    }
 
+    // The first values of a composite key, e.g. for a prefix read.
+    pub fn check_key_prefix(&self, values: &[Value]) -> Result<(), PlanckError> {
+        if !self.is_composite() {
+            return Err(PlanckError::SchemaMismatch(format!("table {} does not have a composite key", self.name)));
+        }
+        if values.is_empty() || values.len() > self.key_columns.len() {
+            return Err(PlanckError::SchemaMismatch(format!("the key of table {} has {} values", self.name, self.key_columns.len())));
+        }
+        for (value, index) in values.iter().zip(self.key_columns.iter()) {
+            let types = self.column_types[*index as usize];
+            if value.type_code() != base_type(types) {
+                return Err(PlanckError::SchemaMismatch(format!("{} is not a {} for the key of table {}", value, type_name(types), self.name)));
+            }
+        }
+        Ok(())
+    }
 
+    fn column_index(&self, name: &str) -> Result<usize, PlanckError> {
+        match self.column_names.iter().position(|column| column == name) {
+            Some(index) => Ok(index),
+            None => Err(PlanckError::NotFound(format!("column {} of table {}", name, self.name)))
+        }
+    }
+
+    // Applies the change to the schema and moves to a new version, rows on disk are upgraded when they are read.
+    pub fn alter(&mut self, alteration: &AlterTable) -> Result<(), PlanckError> {
+        let old_schema = (self.column_ids.clone(), self.column_types.clone());
+        match alteration {
+            AlterTable::AddColumn(name, types) => {
+                if self.column_index(name).is_ok() {
+                    return Err(PlanckError::SchemaMismatch(format!("table {} already has a column {}", self.name, name)));
+                }
+                if !is_column_type(*types) {
+                    return Err(PlanckError::SchemaMismatch(format!("unknown column type {}", types)));
+                }
+                self.column_ids.push(self.next_column_id);
+                self.next_column_id += 1;
+                self.column_names.push(name.clone());
+                self.column_types.push(*types);
+            }
+            AlterTable::DropColumn(name) => {
+                let index = self.column_index(name)?;
+                if self.key_indexes().contains(&index) {
+                    return Err(PlanckError::SchemaMismatch(format!("column {} is part of the key of table {}", name, self.name)));
+                }
+                // Key columns after the dropped one move one place to the left.
+                let shift = |key: &mut u8| if *key as usize > index { *key -= 1 };
+                shift(&mut self.pk_column);
+                self.key_columns.iter_mut().for_each(shift);
+                self.column_ids.remove(index);
+                self.column_names.remove(index);
+                self.column_types.remove(index);
+            }
+            AlterTable::RenameColumn(name, new_name) => {
+                if self.column_index(new_name).is_ok() {
+                    return Err(PlanckError::SchemaMismatch(format!("table {} already has a column {}", self.name, new_name)));
+                }
+                let index = self.column_index(name)?;
+                self.column_names[index] = new_name.clone();
+                // Only the name changed, rows written with the old version are still read the same way.
+                return Ok(());
+            }
+        }
+        self.old_schemas.insert(self.schema_version, old_schema);
+        self.schema_version += 1;
+        Ok(())
+    }
+
+    // Column types the rows of a page written with the given schema version have.
+    pub fn types_of(&self, version: u16) -> Result<&[u8], PlanckError> {
+        if version == self.schema_version {
+            return Ok(&self.column_types);
+        }
+        match self.old_schemas.get(&version) {
+            Some((_, types)) => Ok(types),
+            None => Err(PlanckError::Corruption(format!("table {} has no schema version {}", self.name, version)))
+        }
+    }
+
+    // Turns a row written with an older schema into a row of the current one.
+    // Dropped columns are left out, added columns get 0 or an empty string.
+    pub fn upgrade_row(&self, version: u16, row: Vec<Value>) -> Result<Vec<Value>, PlanckError> {
+        let old_ids = match self.old_schemas.get(&version) {
+            Some((ids, _)) => ids,
+            None => return Err(PlanckError::Corruption(format!("table {} has no schema version {}", self.name, version)))
+        };
+        let mut old_values: HashMap<u16, Value> = old_ids.iter().copied().zip(row).collect();
+        Ok(self.column_ids.iter().zip(self.column_types.iter()).map(|(id, types)| match old_values.remove(id) {
+            Some(value) => value,
+            None => Value::default_of(*types),
+        }).collect())
+    }
+}
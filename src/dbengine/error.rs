@@ -0,0 +1,58 @@
+use std::fmt;
+use std::io;
+
+// Every failure of the engine ends up here, so the server can answer with an error instead of panicking.
+#[derive(Debug)]
+pub enum PlanckError {
+    Io(io::Error),
+    // The bytes on disk do not make sense (bad utf-8, out of bounds pointers, unknown page types...)
+    Corruption(String),
+    // A page read from disk does not match the checksum stored in its header (torn write, bit rot...)
+    ChecksumMismatch { table: String, page_id: u32 },
+    // Not a PlanckDB file, or written by a newer version of PlanckDB
+    UnsupportedFormat(String),
+    NotFound(String),
+    DuplicateKey(String),
+    TableExists(String),
+    SchemaMismatch(String),
+    // The page does not have enough free bytes left for the given cell
+    PageFull,
+    Syntax(String),
+}
+
+impl fmt::Display for PlanckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanckError::Io(err) => write!(f, "IO error: {}", err),
+            PlanckError::Corruption(x) => write!(f, "Corrupted data: {}", x),
+            PlanckError::ChecksumMismatch { table, page_id } => write!(f, "Corrupted data: checksum mismatch on page {} of table {}", page_id, table),
+            PlanckError::UnsupportedFormat(x) => write!(f, "Unsupported file format: {}", x),
+            PlanckError::NotFound(x) => write!(f, "Not found: {}", x),
+            PlanckError::DuplicateKey(x) => write!(f, "Duplicate key: {}", x),
+            PlanckError::TableExists(x) => write!(f, "Table already exists: {}", x),
+            PlanckError::SchemaMismatch(x) => write!(f, "Schema mismatch: {}", x),
+            PlanckError::PageFull => write!(f, "Page is full"),
+            PlanckError::Syntax(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+impl std::error::Error for PlanckError {}
+
+impl From<io::Error> for PlanckError {
+    fn from(err: io::Error) -> Self {
+        PlanckError::Io(err)
+    }
+}
+
+impl From<std::str::Utf8Error> for PlanckError {
+    fn from(err: std::str::Utf8Error) -> Self {
+        PlanckError::Corruption(format!("invalid utf-8 ({})", err))
+    }
+}
+
+impl From<std::string::FromUtf8Error> for PlanckError {
+    fn from(err: std::string::FromUtf8Error) -> Self {
+        PlanckError::Corruption(format!("invalid utf-8 ({})", err))
+    }
+}
@@ -2,3 +2,8 @@ pub mod engine;
 pub mod btrees;
 pub mod pages;
 pub mod buffer_manager;
+pub mod error;
+pub mod checksum;
+pub mod storage;
+pub mod checker;
+pub mod datetime;
@@ -1,11 +1,27 @@
 use crate::dbengine::btrees::*;
-use std::{collections::HashMap, fmt::Error};
+use crate::dbengine::checksum::crc32_update;
+use crate::dbengine::datetime;
+use crate::dbengine::engine::{NO_PAGE, V12_FORMAT_VERSION, V13_FORMAT_VERSION};
+use crate::dbengine::error::PlanckError;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fmt;
 
 fn combine_bytes(high_byte: u8, low_byte: u8) -> u16 {
     // Combine the two bytes into a u16
-    let combined = ((high_byte as u16) << 8) | (low_byte as u16);
-    combined
+    ((high_byte as u16) << 8) | (low_byte as u16)
+}
+
+fn bytes_to_u16(bytes: &[u8]) -> u16 {
+    combine_bytes(bytes[0], bytes[1])
+}
+
+// Bounds checked slicing, a pointer or a length that goes out of the page means the page is corrupted.
+fn read_bytes(buffer: &[u8], offset: usize, len: usize) -> Result<&[u8], PlanckError> {
+    match buffer.get(offset..offset + len) {
+        Some(bytes) => Ok(bytes),
+        None => Err(PlanckError::Corruption(format!("read of {} bytes at offset {} is out of the page", len, offset)))
+    }
 }
 
 fn bytes_to_u32(buffer: &[u8], offset: usize) -> u32 {
@@ -16,35 +32,599 @@ fn bytes_to_u32(buffer: &[u8], offset: usize) -> u32 {
     u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
 }
 
-fn row_size(row: &Vec<Value>) -> u8 {
-    let mut size = 0;
+fn row_size(row: &[Value]) -> u16 {
+    let mut size = null_bitmap_size(row.len()) as u16;
     for value in row {
-        match  value {
-            Value::Number(x) => size += 4,
-            Value::String(len, x) => size += len + 1,
+        size += value.encoded_size() as u16;
+    };
+    size
+}
+
+// Every cell starts with a bitmap of its NULL values, one bit per column, and NULL values take no other space.
+pub fn null_bitmap_size(columns: usize) -> usize {
+    columns.div_ceil(8)
+}
+
+// Bytes a key takes in the slot array, the pointer to the cell included.
+pub fn slot_size(key: &Value) -> usize {
+    key.encoded_size() + 2
+}
+
+// Bytes a row takes in a cell, the two length bytes and the null bitmap included.
+pub fn cell_size(row: &[Value]) -> usize {
+    2 + null_bitmap_size(row.len()) + row.iter().map(Value::encoded_size).sum::<usize>()
+}
+
+// Column type codes, the same codes are used in the catalog, in the create and alter commands and on the wire.
+pub const BOOL_TYPE: u8 = 1;
+pub const NUMBER_TYPE: u8 = 2;
+pub const INT64_TYPE: u8 = 3;
+pub const FLOAT64_TYPE: u8 = 4;
+pub const DATE_TYPE: u8 = 5;
+pub const TIMESTAMP_TYPE: u8 = 6;
+pub const STRING_TYPE: u8 = 7;
+pub const BYTES_TYPE: u8 = 8;
+// Set on top of a type code for columns that accept NULL, e.g. `INT64_TYPE | NULLABLE`.
+pub const NULLABLE: u8 = 0x80;
+// Key type of the tables whose key is made of several values, it is never the type of a column.
+pub const COMPOSITE_TYPE: u8 = 9;
+
+pub fn base_type(types: u8) -> u8 {
+    types & !NULLABLE
+}
+
+pub fn is_nullable(types: u8) -> bool {
+    types & NULLABLE != 0
+}
+
+pub fn is_column_type(types: u8) -> bool {
+    matches!(base_type(types), BOOL_TYPE | NUMBER_TYPE | INT64_TYPE | FLOAT64_TYPE | DATE_TYPE | TIMESTAMP_TYPE | STRING_TYPE | BYTES_TYPE)
+}
+
+pub fn type_name(types: u8) -> &'static str {
+    match base_type(types) {
+        BOOL_TYPE => "Bool",
+        NUMBER_TYPE => "Number",
+        INT64_TYPE => "Int64",
+        FLOAT64_TYPE => "Float64",
+        DATE_TYPE => "Date",
+        TIMESTAMP_TYPE => "Timestamp",
+        STRING_TYPE => "String",
+        BYTES_TYPE => "Bytes",
+        COMPOSITE_TYPE => "Composite",
+        _ => "Unknown",
+    }
+}
+
+// Int64, Float64, Date and Timestamp are stored so that comparing the bytes gives the same order as comparing the values:
+// the sign bit of an integer is flipped, a positive f64 gets its sign bit set and a negative one has all its bits inverted.
+pub fn encode_i32(x: i32) -> [u8; 4] {
+    ((x as u32) ^ (1 << 31)).to_be_bytes()
+}
+
+pub fn decode_i32(bytes: &[u8]) -> i32 {
+    (u32::from_be_bytes(bytes[0..4].try_into().unwrap()) ^ (1 << 31)) as i32
+}
+
+pub fn encode_i64(x: i64) -> [u8; 8] {
+    ((x as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+pub fn decode_i64(bytes: &[u8]) -> i64 {
+    (u64::from_be_bytes(bytes[0..8].try_into().unwrap()) ^ (1 << 63)) as i64
+}
+
+// -0.0 is encoded as 0.0, equal keys always get the same bytes however they were made.
+pub fn encode_f64(x: f64) -> [u8; 8] {
+    let bits = (x + 0.0).to_bits();
+    let bits = if bits >> 63 == 1 { !bits } else { bits | (1 << 63) };
+    bits.to_be_bytes()
+}
+
+pub fn decode_f64(bytes: &[u8]) -> f64 {
+    let bits = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
+    f64::from_bits(if bits >> 63 == 1 { bits & !(1 << 63) } else { !bits })
+}
+
+// A composite key is the concatenation of its values, each one is its type code followed by the same order preserving
+// bytes a page uses. Strings and bytes have every 0x00 escaped as 0x00 0xFF and end with 0x00 0x00, so a shorter value
+// sorts before the longer ones it is a prefix of. Comparing the bytes of two keys compares their values one by one,
+// and the encoding of the first values of a key is a prefix of the encoding of the whole key.
+fn encode_component(bytes: &mut Vec<u8>, value: &Value) -> Result<(), PlanckError> {
+    bytes.push(value.type_code());
+    match value {
+        Value::Bool(x) => bytes.push(*x as u8),
+        Value::Number(x) => bytes.extend_from_slice(&x.to_be_bytes()),
+        Value::Int64(x) | Value::Timestamp(x) => bytes.extend_from_slice(&encode_i64(*x)),
+        Value::Float64(x) if x.is_nan() => return Err(PlanckError::SchemaMismatch("NaN cannot be used as a key".to_string())),
+        Value::Float64(x) => bytes.extend_from_slice(&encode_f64(*x)),
+        Value::Date(x) => bytes.extend_from_slice(&encode_i32(*x)),
+        Value::String(_, _) | Value::Bytes(_) => {
+            let raw = match value {
+                Value::String(_, x) => x.as_bytes(),
+                Value::Bytes(x) => x.as_slice(),
+                _ => unreachable!(),
+            };
+            for byte in raw {
+                bytes.push(*byte);
+                if *byte == 0 {
+                    bytes.push(u8::MAX);
+                }
+            }
+            bytes.extend_from_slice(&[0, 0]);
+        }
+        Value::Null | Value::Overflow { .. } | Value::Composite(_) => {
+            return Err(PlanckError::SchemaMismatch(format!("{} cannot be part of a key", value)));
+        }
+    }
+    Ok(())
+}
+
+fn decode_component(bytes: &[u8], offset: &mut usize) -> Result<Value, PlanckError> {
+    let types = read_bytes(bytes, *offset, 1)?[0];
+    *offset += 1;
+    match types {
+        STRING_TYPE | BYTES_TYPE => {
+            let mut raw = Vec::new();
+            loop {
+                match read_bytes(bytes, *offset, 2)? {
+                    [0, 0] => break,
+                    [0, u8::MAX] => raw.push(0),
+                    [0, x] => return Err(PlanckError::Corruption(format!("0x00 0x{:02x} in a composite key", x))),
+                    _ => raw.push(bytes[*offset]),
+                }
+                *offset += if bytes[*offset] == 0 { 2 } else { 1 };
+            }
+            *offset += 2;
+            match types {
+                STRING_TYPE => Ok(Value::string(String::from_utf8(raw).map_err(|err| err.utf8_error())?)),
+                _ => Ok(Value::Bytes(raw)),
+            }
+        }
+        BOOL_TYPE | NUMBER_TYPE | INT64_TYPE | FLOAT64_TYPE | DATE_TYPE | TIMESTAMP_TYPE => read_value(bytes, offset, types),
+        x => Err(PlanckError::Corruption(format!("unknown type {} in a composite key", x)))
+    }
+}
+
+// Strings and bytes longer than this never stay in a cell, a length byte of 255 marks a value moved to overflow pages.
+pub const MAX_INLINE_VALUE: usize = 254;
+const OVERFLOW_MARKER: u8 = 255;
+// Bytes of a value moved to overflow pages that stay in the cell.
+pub const OVERFLOW_PREFIX: usize = 16;
+// Largest cell (without its length bytes) a row may need, so that a leaf always has room for MAX_KEY + 1 rows
+// before it is split. Values are moved to overflow pages until the row fits.
+pub const MAX_CELL_SIZE: usize = 512;
+// Keys are never moved to overflow pages.
+pub const MAX_KEY_SIZE: usize = 1 + MAX_INLINE_VALUE;
+
+// A page of an overflow chain: page type 3 (1 byte), next page id (4 bytes), CRC-32 (4 bytes),
+// number of data bytes (2 bytes) and the data.
+pub const OVERFLOW_HEADER_SIZE: usize = 11;
+pub const OVERFLOW_DATA_SIZE: usize = 4096 - OVERFLOW_HEADER_SIZE;
+
+pub fn overflow_page_to_buff(next: u32, data: &[u8]) -> [u8; 4096] {
+    let mut buffer = [0u8; 4096];
+    buffer[0] = 3;
+    buffer[1..5].copy_from_slice(&next.to_be_bytes());
+    buffer[9..11].copy_from_slice(&(data.len() as u16).to_be_bytes());
+    buffer[OVERFLOW_HEADER_SIZE..OVERFLOW_HEADER_SIZE + data.len()].copy_from_slice(data);
+    let checksum = page_checksum(&buffer);
+    buffer[5..9].copy_from_slice(&checksum.to_be_bytes());
+    buffer
+}
+
+// Returns the next page of the chain and the data of this one, the checksum is verified by the caller.
+pub fn buff_to_overflow_page(buffer: &[u8; 4096]) -> Result<(u32, &[u8]), PlanckError> {
+    if buffer[0] != 3 {
+        return Err(PlanckError::Corruption("not an overflow page".to_string()));
+    }
+    let len = bytes_to_u16(&buffer[9..11]) as usize;
+    Ok((bytes_to_u32(buffer, 1), read_bytes(buffer, OVERFLOW_HEADER_SIZE, len)?))
+}
+
+// First pages of the overflow chains a row points to.
+pub fn overflow_pages(row: &[Value]) -> impl Iterator<Item = u32> + '_ {
+    row.iter().filter_map(|value| match value {
+        Value::Overflow { page, .. } => Some(*page),
+        _ => None,
+    })
+}
+
+// Writes the value at offset and returns the offset right after it, the caller checks that it fits.
+fn write_value(buffer: &mut [u8], offset: usize, value: &Value) -> usize {
+    match value {
+        Value::Bool(x) => buffer[offset] = *x as u8,
+        Value::Number(x) => buffer[offset..offset + 4].copy_from_slice(&x.to_be_bytes()),
+        Value::Int64(x) | Value::Timestamp(x) => buffer[offset..offset + 8].copy_from_slice(&encode_i64(*x)),
+        Value::Float64(x) => buffer[offset..offset + 8].copy_from_slice(&encode_f64(*x)),
+        Value::Date(x) => buffer[offset..offset + 4].copy_from_slice(&encode_i32(*x)),
+        Value::String(_, x) => {
+            buffer[offset] = x.len() as u8;
+            buffer[offset + 1..offset + 1 + x.len()].copy_from_slice(x.as_bytes());
+        }
+        Value::Bytes(x) | Value::Composite(x) => {
+            buffer[offset] = x.len() as u8;
+            buffer[offset + 1..offset + 1 + x.len()].copy_from_slice(x);
         }
+        Value::Overflow { length, page, prefix, .. } => {
+            buffer[offset] = OVERFLOW_MARKER;
+            buffer[offset + 1..offset + 5].copy_from_slice(&length.to_be_bytes());
+            buffer[offset + 5..offset + 9].copy_from_slice(&page.to_be_bytes());
+            buffer[offset + 9..offset + 9 + prefix.len()].copy_from_slice(prefix);
+        }
+        Value::Null => {}
+    }
+    offset + value.encoded_size()
+}
+
+// Writes the null bitmap and the values of a row at offset and returns the offset right after it.
+fn write_row(buffer: &mut [u8], mut offset: usize, row: &[Value]) -> usize {
+    let bitmap = offset;
+    offset += null_bitmap_size(row.len());
+    buffer[bitmap..offset].fill(0);
+    for (index, value) in row.iter().enumerate() {
+        if let Value::Null = value {
+            buffer[bitmap + index / 8] |= 1 << (index % 8);
+        }
+        offset = write_value(buffer, offset, value);
+    }
+    offset
+}
+
+// Reads a row written by write_row, the column types tell how many values there are and how to decode them.
+fn read_row(buffer: &[u8], field_types: &[u8]) -> Result<Vec<Value>, PlanckError> {
+    let bitmap = read_bytes(buffer, 0, null_bitmap_size(field_types.len()))?;
+    let mut offset = bitmap.len();
+    let mut values = Vec::with_capacity(field_types.len());
+    for (index, field_type) in field_types.iter().enumerate() {
+        if bitmap[index / 8] & (1 << (index % 8)) != 0 {
+            values.push(Value::Null);
+        } else {
+            values.push(read_value(buffer, &mut offset, *field_type)?);
+        }
+    }
+    Ok(values)
+}
+
+// Reads a value of the given column type at offset and moves the offset past it.
+fn read_value(buffer: &[u8], offset: &mut usize, types: u8) -> Result<Value, PlanckError> {
+    let value = match base_type(types) {
+        BOOL_TYPE => match read_bytes(buffer, *offset, 1)?[0] {
+            0 => Value::Bool(false),
+            1 => Value::Bool(true),
+            x => return Err(PlanckError::Corruption(format!("{} is not a boolean", x)))
+        },
+        NUMBER_TYPE => Value::Number(bytes_to_u32(read_bytes(buffer, *offset, 4)?, 0)),
+        INT64_TYPE => Value::Int64(decode_i64(read_bytes(buffer, *offset, 8)?)),
+        FLOAT64_TYPE => Value::Float64(decode_f64(read_bytes(buffer, *offset, 8)?)),
+        DATE_TYPE => Value::Date(decode_i32(read_bytes(buffer, *offset, 4)?)),
+        TIMESTAMP_TYPE => Value::Timestamp(decode_i64(read_bytes(buffer, *offset, 8)?)),
+        STRING_TYPE | BYTES_TYPE if read_bytes(buffer, *offset, 1)?[0] == OVERFLOW_MARKER => {
+            let length = bytes_to_u32(read_bytes(buffer, *offset + 1, 4)?, 0);
+            let page = bytes_to_u32(read_bytes(buffer, *offset + 5, 4)?, 0);
+            let prefix = read_bytes(buffer, *offset + 9, (length as usize).min(OVERFLOW_PREFIX))?.to_vec();
+            Value::Overflow { types: base_type(types), length, page, prefix }
+        }
+        STRING_TYPE => {
+            let len = *read_bytes(buffer, *offset, 1)?.first().unwrap();
+            let string = std::str::from_utf8(read_bytes(buffer, *offset + 1, len as usize)?)?;
+            Value::String(len as u32, string.to_string())
+        }
+        BYTES_TYPE => {
+            let len = *read_bytes(buffer, *offset, 1)?.first().unwrap();
+            Value::Bytes(read_bytes(buffer, *offset + 1, len as usize)?.to_vec())
+        }
+        COMPOSITE_TYPE => {
+            let len = *read_bytes(buffer, *offset, 1)?.first().unwrap();
+            Value::Composite(read_bytes(buffer, *offset + 1, len as usize)?.to_vec())
+        }
+        x => return Err(PlanckError::Corruption(format!("unknown column type {}", x)))
     };
-    return size;
-    
+    *offset += value.encoded_size();
+    Ok(value)
+}
 
+// Page header: page type (1 byte), free space pointer (2 bytes), slot count (2 bytes), CRC-32 (4 bytes),
+// schema version of the rows (2 bytes, only used by leaf pages),
+// key type (1 byte: 0 Number, 1 String, 2 Int64, 3 Float64, 4 Bool, 5 Date, 6 Timestamp, 7 Bytes, 8 Composite),
+// next and previous leaf pages in key order (4 bytes each, NO_PAGE at the ends of the chain and for internal pages)
+pub const PAGE_HEADER_SIZE: usize = 20;
+// Format version 12 had the same header without the next and previous leaves, version 13 without the previous one.
+const V12_PAGE_HEADER_SIZE: usize = 12;
+const V13_PAGE_HEADER_SIZE: usize = 16;
+
+// CRC-32 of the whole page with the checksum bytes themselves left out.
+pub fn page_checksum(buffer: &[u8; 4096]) -> u32 {
+    crc32_update(crc32_update(0, &buffer[0..5]), &buffer[9..])
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub fn verify_checksum(buffer: &[u8; 4096]) -> bool {
+    u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]) == page_checksum(buffer)
+}
+
+// A page on the free list only stores the id of the next free page: page type 2 (1 byte), next page id (4 bytes), CRC-32 (4 bytes)
+pub fn free_page_to_buff(next: u32) -> [u8; 4096] {
+    let mut buffer = [0u8; 4096];
+    buffer[0] = 2;
+    buffer[1..5].copy_from_slice(&next.to_be_bytes());
+    let checksum = page_checksum(&buffer);
+    buffer[5..9].copy_from_slice(&checksum.to_be_bytes());
+    buffer
+}
+
+// Type code of the keys of a page, from the key type byte of its header.
+fn key_type(byte: u8) -> Result<u8, PlanckError> {
+    match byte {
+        0 => Ok(NUMBER_TYPE),
+        1 => Ok(STRING_TYPE),
+        2 => Ok(INT64_TYPE),
+        3 => Ok(FLOAT64_TYPE),
+        4 => Ok(BOOL_TYPE),
+        5 => Ok(DATE_TYPE),
+        6 => Ok(TIMESTAMP_TYPE),
+        7 => Ok(BYTES_TYPE),
+        8 => Ok(COMPOSITE_TYPE),
+        x => Err(PlanckError::Corruption(format!("unknown key type {}", x)))
+    }
+}
+
+// Converts a leaf or internal page of format version 11: its header was page type, free space pointer,
+// key type, slot count (1 byte), CRC-32 and schema version, 11 bytes. Its cells ended at byte 4095 at
+// the latest, so the slots and cells move one byte up and every slot points one byte further.
+pub fn v11_page_to_buff(old: &[u8; 4096]) -> Result<[u8; 4096], PlanckError> {
+    let key_type = key_type(old[3])?;
+    let mut buffer = [0u8; 4096];
+    buffer[0] = old[0];
+    buffer[1..3].copy_from_slice(&(bytes_to_u16(&old[1..3]) + 1).to_be_bytes());
+    buffer[3..5].copy_from_slice(&(old[4] as u16).to_be_bytes());
+    buffer[9..11].copy_from_slice(&old[9..11]);
+    buffer[11] = old[3];
+    buffer[V12_PAGE_HEADER_SIZE..].copy_from_slice(&old[11..4095]);
+    let mut offset = V12_PAGE_HEADER_SIZE;
+    for _ in 0..old[4] {
+        read_value(&buffer, &mut offset, key_type)?;
+        let pointer = bytes_to_u16(read_bytes(&buffer, offset, 2)?) + 1;
+        buffer[offset..offset + 2].copy_from_slice(&pointer.to_be_bytes());
+        offset += 2;
+    }
+    let checksum = page_checksum(&buffer);
+    buffer[5..9].copy_from_slice(&checksum.to_be_bytes());
+    Ok(buffer)
+}
+
+pub fn is_leaf_buff(buffer: &[u8; 4096]) -> bool {
+    buffer[0] == 1
+}
+
+pub fn buff_schema_version(buffer: &[u8; 4096]) -> u16 {
+    bytes_to_u16(&buffer[9..11])
+}
+
+pub fn buff_to_free_page(buffer: &[u8; 4096]) -> Result<u32, PlanckError> {
+    if buffer[0] != 2 || !verify_checksum(buffer) {
+        return Err(PlanckError::Corruption("not a free page".to_string()));
+    }
+    Ok(bytes_to_u32(buffer, 1))
+}
+
+// Bytes literals are written in hex with a 0x prefix.
+fn parse_hex(lexeme: &str) -> Option<Vec<u8>> {
+    let digits = lexeme.strip_prefix("0x")?;
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    (0..digits.len()).step_by(2).map(|index| u8::from_str_radix(digits.get(index..index + 2)?, 16).ok()).collect()
+}
+
+// Ordered by hand, see the Ord impl below.
+#[derive(Debug, Clone)]
 pub enum Value {
+    // First so that NULL sorts before every other value. Never a key.
+    Null,
     Number(u32),
-    String(u8, String)
+    // The length in bytes, Value::string fills it in. Values are encoded with the length of the string itself.
+    String(u32, String),
+    Int64(i64),
+    // Never NaN when used as a key, keys must be totally ordered.
+    Float64(f64),
+    Bool(bool),
+    // Days since 1970-01-01.
+    Date(i32),
+    // Milliseconds since 1970-01-01T00:00:00 UTC.
+    Timestamp(i64),
+    // Ordered byte by byte, like strings.
+    Bytes(Vec<u8>),
+    // Only inside of pages: a string or bytes value moved to a chain of overflow pages, with its first bytes.
+    // Reading a row through the tree always gives back the whole value.
+    Overflow { types: u8, length: u32, page: u32, prefix: Vec<u8> },
+    // Key of a table with a composite key, the encoding of its values (see encode_component).
+    Composite(Vec<u8>),
 }
 
+// Values of one type compare by value, strings and bytes byte by byte whatever their length, so that "ba" sorts
+// between "b" and "c". Values of different types compare by the order of the variants, keys of a table never do.
+// Floats compare as numbers, 0.0 and -0.0 are equal, and NaN, never a key, is only equal to itself.
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::Null, Value::Null) => Ordering::Equal,
+            (Value::Number(a), Value::Number(b)) => a.cmp(b),
+            (Value::String(_, a), Value::String(_, b)) => a.cmp(b),
+            (Value::Int64(a), Value::Int64(b)) | (Value::Timestamp(a), Value::Timestamp(b)) => a.cmp(b),
+            (Value::Float64(a), Value::Float64(b)) => a.partial_cmp(b).unwrap_or_else(|| a.total_cmp(b)),
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::Date(a), Value::Date(b)) => a.cmp(b),
+            (Value::Bytes(a), Value::Bytes(b)) | (Value::Composite(a), Value::Composite(b)) => a.cmp(b),
+            (Value::Overflow { types, length, page, prefix }, Value::Overflow { types: other_types, length: other_length, page: other_page, prefix: other_prefix }) => {
+                (types, length, page, prefix).cmp(&(other_types, other_length, other_page, other_prefix))
+            }
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
 impl Value {
     pub fn string(str: String) -> Self {
         let bytes = str.as_bytes();
-        Value::String(bytes.len() as u8, str)
+        Value::String(bytes.len() as u32, str)
+    }
+
+    // Position of the variant in the declaration of Value.
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::Number(_) => 1,
+            Value::String(_, _) => 2,
+            Value::Int64(_) => 3,
+            Value::Float64(_) => 4,
+            Value::Bool(_) => 5,
+            Value::Date(_) => 6,
+            Value::Timestamp(_) => 7,
+            Value::Bytes(_) => 8,
+            Value::Overflow { .. } => 9,
+            Value::Composite(_) => 10,
+        }
     }
 
-    pub fn extract_pointer(&self) -> u32 {
+    // Type code of a value, NULL has none and gets 0.
+    pub fn type_code(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::Number(_) => NUMBER_TYPE,
+            Value::Int64(_) => INT64_TYPE,
+            Value::Float64(_) => FLOAT64_TYPE,
+            Value::String(_, _) => STRING_TYPE,
+            Value::Bool(_) => BOOL_TYPE,
+            Value::Date(_) => DATE_TYPE,
+            Value::Timestamp(_) => TIMESTAMP_TYPE,
+            Value::Bytes(_) => BYTES_TYPE,
+            Value::Overflow { types, .. } => *types,
+            Value::Composite(_) => COMPOSITE_TYPE,
+        }
+    }
+
+    // Bytes the value takes in a page or in a message.
+    pub fn encoded_size(&self) -> usize {
+        match self {
+            Value::Bool(_) => 1,
+            Value::Number(_) | Value::Date(_) => 4,
+            Value::Int64(_) | Value::Float64(_) | Value::Timestamp(_) => 8,
+            Value::String(_, x) => 1 + x.len(),
+            Value::Bytes(bytes) | Value::Composite(bytes) => 1 + bytes.len(),
+            Value::Overflow { prefix, .. } => 1 + 4 + 4 + prefix.len(),
+            Value::Null => 0,
+        }
+    }
+
+    // Value a column added by an ALTER gets in the rows written before it, NULL if the column accepts it.
+    pub fn default_of(types: u8) -> Value {
+        if is_nullable(types) {
+            return Value::Null;
+        }
+        match types {
+            STRING_TYPE => Value::string(String::new()),
+            INT64_TYPE => Value::Int64(0),
+            FLOAT64_TYPE => Value::Float64(0.0),
+            BOOL_TYPE => Value::Bool(false),
+            DATE_TYPE => Value::Date(0),
+            TIMESTAMP_TYPE => Value::Timestamp(0),
+            BYTES_TYPE => Value::Bytes(Vec::new()),
+            _ => Value::Number(0),
+        }
+    }
+
+    // Parses a lexeme of a command as a value of the given column type, NULL is only accepted by nullable columns.
+    pub fn parse(lexeme: &str, types: u8) -> Result<Value, PlanckError> {
+        let not_a = |kind: &str| PlanckError::SchemaMismatch(format!("{} is not {}", lexeme, kind));
+        if lexeme == "NULL" {
+            return match is_nullable(types) {
+                true => Ok(Value::Null),
+                false => Err(PlanckError::SchemaMismatch("NULL given for a column that is not nullable".to_string())),
+            };
+        }
+        match base_type(types) {
+            NUMBER_TYPE => lexeme.parse::<u32>().map(Value::Number).map_err(|_| not_a("a number")),
+            INT64_TYPE => lexeme.parse::<i64>().map(Value::Int64).map_err(|_| not_a("a 64-bit integer")),
+            FLOAT64_TYPE => match lexeme.parse::<f64>() {
+                // -0.0 is read as 0.0, the value encode_f64 stores for both.
+                Ok(x) if !x.is_nan() => Ok(Value::Float64(x + 0.0)),
+                _ => Err(not_a("a floating point number")),
+            },
+            STRING_TYPE => Ok(Value::string(lexeme.to_string())),
+            BOOL_TYPE => match lexeme {
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                _ => Err(not_a("true or false")),
+            },
+            DATE_TYPE => datetime::parse_date(lexeme).map(Value::Date).ok_or_else(|| not_a("a date (YYYY-MM-DD)")),
+            TIMESTAMP_TYPE => datetime::parse_timestamp(lexeme).map(Value::Timestamp).ok_or_else(|| not_a("a timestamp (YYYY-MM-DDTHH:MM:SS.mmm)")),
+            BYTES_TYPE => parse_hex(lexeme).map(Value::Bytes).ok_or_else(|| not_a("hex bytes (0x...)")),
+            x => Err(PlanckError::SchemaMismatch(format!("unknown column type {}", x)))
+        }
+    }
+
+    // Builds the key of a table with a composite key, or the prefix of one from its first values.
+    pub fn composite(values: &[Value]) -> Result<Value, PlanckError> {
+        let mut bytes = Vec::new();
+        for value in values {
+            encode_component(&mut bytes, value)?;
+        }
+        if bytes.len() > MAX_INLINE_VALUE {
+            return Err(PlanckError::SchemaMismatch(format!("composite keys take at most {} bytes", MAX_INLINE_VALUE)));
+        }
+        Ok(Value::Composite(bytes))
+    }
+
+    // Values of a composite key.
+    pub fn components(&self) -> Result<Vec<Value>, PlanckError> {
+        let bytes = match self {
+            Value::Composite(bytes) => bytes,
+            _ => return Err(PlanckError::SchemaMismatch(format!("{} is not a composite key", self)))
+        };
+        let mut offset = 0;
+        let mut values = Vec::new();
+        while offset < bytes.len() {
+            values.push(decode_component(bytes, &mut offset)?);
+        }
+        Ok(values)
+    }
+
+    // Parses comma separated values of a composite key, e.g. `42,2024-01-31`. Fewer values than types give a prefix.
+    pub fn parse_composite(lexeme: &str, key_types: &[u8]) -> Result<Value, PlanckError> {
+        let lexemes: Vec<&str> = lexeme.split(',').collect();
+        if lexemes.len() > key_types.len() {
+            return Err(PlanckError::SchemaMismatch(format!("{} has more than {} values", lexeme, key_types.len())));
+        }
+        let values = lexemes.iter().zip(key_types.iter()).map(|(lexeme, types)| Value::parse(lexeme, *types)).collect::<Result<Vec<Value>, PlanckError>>()?;
+        Value::composite(&values)
+    }
+
+    // True for a composite key that starts with the values of the prefix.
+    pub fn has_prefix(&self, prefix: &Value) -> bool {
+        match (self, prefix) {
+            (Value::Composite(bytes), Value::Composite(prefix)) => bytes.starts_with(prefix),
+            _ => false,
+        }
+    }
+
+    pub fn extract_pointer(&self) -> Result<u32, PlanckError> {
         match  self {
-            Value::Number(x) => *x,
-            _ => panic!("No pointer")
+            Value::Number(x) => Ok(*x),
+            _ => Err(PlanckError::Corruption(format!("{} is not a page pointer", self)))
         }
     }
 }
@@ -54,6 +634,21 @@ impl fmt::Display for Value {
         match self {
             Value::Number(n) => write!(f, "{}", n),
             Value::String(_, s) => write!(f, "{}", s),
+            Value::Int64(n) => write!(f, "{}", n),
+            Value::Float64(n) => write!(f, "{}", n),
+            Value::Null => write!(f, "NULL"),
+            Value::Overflow { length, prefix, .. } => write!(f, "{}... ({} bytes)", String::from_utf8_lossy(prefix), length),
+            Value::Bool(x) => write!(f, "{}", x),
+            Value::Date(days) => write!(f, "{}", datetime::format_date(*days)),
+            Value::Timestamp(millis) => write!(f, "{}", datetime::format_timestamp(*millis)),
+            Value::Bytes(bytes) => {
+                write!(f, "0x")?;
+                bytes.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+            Value::Composite(bytes) => match self.components() {
+                Ok(values) => write!(f, "{}", values.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(",")),
+                Err(_) => write!(f, "{}", Value::Bytes(bytes.clone())),
+            },
         }
     }
 }
@@ -64,7 +659,7 @@ pub struct Slot {
 }
 #[derive(Debug, Clone)]
 pub struct Rows {
-    pub size: u8,
+    pub size: u16,
     pub values: Vec<Value>
 }
 
@@ -73,7 +668,11 @@ pub struct Page {
     pub page_type: NodeType,
     pub free_space_pointer: u16,
     pub slots: Vec<Slot>,
-    pub cells: HashMap<u16,Rows>
+    pub cells: HashMap<u16,Rows>,
+    pub schema_version: u16,
+    // Leaves are linked both ways so that range reads in either order do not go back up the tree.
+    pub next_leaf: u32,
+    pub prev_leaf: u32,
 }
 #[derive(Debug, Clone)]
 pub struct KeyRow {
@@ -89,269 +688,416 @@ impl fmt::Display for KeyRow {
 
 impl Page {
     pub fn new_leaf() -> Self {
-        Page { page_type: NodeType::Leaf(Vec::new()), free_space_pointer: 4093, slots: Vec::new(), cells: HashMap::new() }
+        Page { page_type: NodeType::Leaf, free_space_pointer: 4096, slots: Vec::new(), cells: HashMap::new(), schema_version: 0, next_leaf: NO_PAGE, prev_leaf: NO_PAGE }
     }
     pub fn new_internal() -> Self {
-        Page { page_type: NodeType::Internal(Vec::new()), free_space_pointer: 4093, slots: Vec::new(), cells: HashMap::new() }
+        Page { page_type: NodeType::Internal, free_space_pointer: 4096, slots: Vec::new(), cells: HashMap::new(), schema_version: 0, next_leaf: NO_PAGE, prev_leaf: NO_PAGE }
+    }
+
+    // Bytes of the page in use: header, slot array and cells.
+    pub fn used_bytes(&self) -> usize {
+        let slots: usize = self.slots.iter().map(|slot| slot_size(&slot.value)).sum();
+        let cells: usize = self.cells.values().map(|row| row.size as usize + 2).sum();
+        PAGE_HEADER_SIZE + slots + cells
     }
 
+    // Whether page_to_buff can write the page. Between an insert and the split that follows it a page of
+    // the B+ tree holds more than that.
+    pub fn fits(&self) -> bool {
+        self.used_bytes() <= 4096
+    }
+
+    // Empties the page, a leaf keeps its place in the chain of leaves.
     pub fn clean_page(&mut self) {
         self.slots = Vec::new();
         self.cells = HashMap::new();
-        self.free_space_pointer = 4095;
+        self.free_space_pointer = 4096;
     }
 
     pub fn vacuum(&mut self) {
-        let mut free_pointer: u16 = 4095;
+        let mut free_pointer: u16 = 4096;
         let slots = &mut self.slots;
         let cells = &self.cells;
         let mut rows = HashMap::new();
         for slot in slots {
             let row = cells.get(&slot.pointer).unwrap();
-            free_pointer -= (row.size + 1) as u16;
+            free_pointer = free_pointer.wrapping_sub(row.size + 2);
             slot.pointer = free_pointer;
             rows.insert(slot.pointer, row.clone());
         };
         self.cells = rows;
+        self.free_space_pointer = free_pointer;
     }
 
-    pub fn mut_slot(&mut self, key: Value) -> &mut Slot {
-        let mut idx = 0;
-        for slot in &self.slots {
-            if slot.value == key {
-                break;
-            }
-            idx += 1;
+    pub fn mut_slot(&mut self, key: Value) -> Result<&mut Slot, PlanckError> {
+        match self.slots.iter_mut().find(|slot| slot.value == key) {
+            Some(slot) => Ok(slot),
+            None => Err(PlanckError::NotFound(format!("key {}", key)))
         }
-
-        &mut self.slots[idx]
     }
    
-    pub fn insert(&mut self,kv: KeyRow) {
+    // Inserts the entry in key order. The last slot of an internal page points to the child of every key from
+    // the divider before it on, whatever key it holds, so a new divider always goes before it.
+    pub fn insert(&mut self,kv: KeyRow) -> Result<(), PlanckError> {
+          let dividers = match self.page_type {
+              NodeType::Internal => self.slots.len().saturating_sub(1),
+              NodeType::Leaf => self.slots.len(),
+          };
+          let index = self.slots[..dividers].iter().position(|slot| slot.value > kv.key).unwrap_or(dividers);
+          self.insert_at(index, kv);
+          Ok(())
+    }
+
+    // Appends the entry after every slot, for pages built from entries that are already in order.
+    pub fn push(&mut self, kv: KeyRow) {
+          self.insert_at(self.slots.len(), kv);
+    }
+
+    fn insert_at(&mut self, index: usize, kv: KeyRow) {
           let row_size = row_size(&kv.row);
-          
-          let mut index = 0;
-          for slot in &self.slots {
-            if slot.value > kv.key {
-                break;
-            }
-            index += 1;
-          }
-          let pointer = self.free_space_pointer - row_size as u16 - 1;
-          self.slots.insert(index, Slot { value: kv.key, pointer:  pointer });
+          // page_to_buff lays the cells out again, in memory the pointer only has to be unique. It wraps
+          // around when the page holds more than fits, until the page is split.
+          let pointer = self.free_space_pointer.wrapping_sub(row_size + 2);
+          self.slots.insert(index, Slot { value: kv.key, pointer });
           self.cells.insert(pointer, Rows { size: row_size, values: kv.row });
           self.free_space_pointer = pointer;
-          
     }
 
-
-    pub fn delete(&mut self, key: Value){
-        let mut index = 0;
-        for slot in &self.slots {
-            if slot.value == key {
+    pub fn delete(&mut self, key: Value) -> bool {
+        for index in 0..self.slots.len() {
+            if self.slots[index].value == key {
                 let removed = self.slots.remove(index);            
                 self.cells.remove(&removed.pointer);
-                break;
+                return true;
             }
-            index += 1;
-        }  
+        }
+        false
     }
 
-    pub fn page_to_buff(&self) -> Result<[u8; 4096], Error>{
+    pub fn page_to_buff(&self) -> Result<[u8; 4096], PlanckError>{
         let mut buffer: [u8; 4096] = [0; 4096];
         let mut offset = 0;
-        let mut free_space_pointer: u16 = 4095;
+        // Cells are written from the end of the page in slot order, whatever their pointers are in memory.
+        let mut free_space_pointer: usize = 4096;
         match &self.page_type {
-            NodeType::Leaf(_) => {
+            NodeType::Leaf => {
                 buffer[0] = 1;
             },
-            NodeType::Internal(_) => {
+            NodeType::Internal => {
                 buffer[0] = 0;
             }
         };
         offset += 1;
 
-        // buffer[offset..offset + 2].copy_from_slice(&self.free_space_pointer.to_be_bytes());
-        
-        offset += 3;
-        
-        buffer[offset] = self.slots.len() as u8;
+        // The free space pointer is written at the end, once the cells are laid out.
+        offset += 2;
+
+        buffer[offset..offset + 2].copy_from_slice(&(self.slots.len() as u16).to_be_bytes());
+        offset += 2;
+
+        // Checksum goes here, it is computed at the end once the page is complete.
+        offset += 4;
+
+        buffer[offset..offset + 2].copy_from_slice(&self.schema_version.to_be_bytes());
+        offset += 2;
+
+        // Key type, set by the slots.
         offset += 1;
 
+        buffer[offset..offset + 4].copy_from_slice(&self.next_leaf.to_be_bytes());
+        offset += 4;
+        buffer[offset..offset + 4].copy_from_slice(&self.prev_leaf.to_be_bytes());
+        offset += 4;
+
         for slot in &self.slots {
-            match &slot.value {
-                Value::Number(x) => {
-                    buffer[3] = 0;
-                    buffer[offset..offset + 4].copy_from_slice(&x.to_be_bytes());
-                    offset += 4;
-                    buffer[offset..offset + 2].copy_from_slice(&slot.pointer.to_be_bytes());
-                    if free_space_pointer > slot.pointer {
-                        free_space_pointer = slot.pointer;
-                    }
-                    offset += 2;
-                    
-                    let row = &self.cells.get(&slot.pointer).unwrap();
-                    let mut internal_offset = slot.pointer as usize;
-                    if internal_offset <= offset {
-                        return Err(Error);
-                    }
-                    
-                    buffer[internal_offset]= row.size;
-                    internal_offset += 1;
-                    for value in &row.values {
-                        match value {
-                            Value::Number(xi) => {
-                                buffer[internal_offset..internal_offset + 4].copy_from_slice(&xi.to_be_bytes());
-                                internal_offset += 4;
-                            },
-                            Value::String(size, xi) => {
-                                buffer[internal_offset] = *size;
-                                internal_offset += 1;
-                                buffer[internal_offset..internal_offset + *size as usize].copy_from_slice(&xi.as_bytes());
-                                internal_offset += *size as usize;
-                            }
-                        }
-                    }
-                    
-                    
-                },
-                Value::String(size, x) => {
-                    buffer[3] = 1;
-                    buffer[offset] = *size;
-                    offset += 1;
-                    buffer[offset..offset + *size as usize].copy_from_slice(&x.as_bytes());
-                    offset += *size as usize;
-                    buffer[offset..offset + 2].copy_from_slice(&slot.pointer.to_be_bytes());
-                    if free_space_pointer > slot.pointer {
-                        free_space_pointer = slot.pointer;
-                    }
-                    offset += 2;
-
-                    
-                    let row = &self.cells.get(&slot.pointer).unwrap();
-                    let mut internal_offset = slot.pointer as usize;
-                    if internal_offset <= offset {
-                        return Err(Error);
-                    }
-                    buffer[internal_offset]= row.size;
-                    internal_offset += 1;
-                    for value in &row.values {
-                        match value {
-                            Value::Number(xi) => {
-                                buffer[internal_offset..internal_offset + 4].copy_from_slice(&xi.to_be_bytes());
-                                internal_offset += 4;
-                            },
-                            Value::String(size, xi) => {
-                                buffer[internal_offset] = *size;
-                                internal_offset += 1;
-                                buffer[internal_offset..internal_offset + *size as usize].copy_from_slice(&xi.as_bytes());
-                                internal_offset += *size as usize;
-                            }
-                        }
-                    }
-                    
-                }
-            }
+            buffer[11] = match &slot.value {
+                Value::Number(_) => 0,
+                Value::String(_, _) => 1,
+                Value::Int64(_) => 2,
+                Value::Float64(_) => 3,
+                Value::Bool(_) => 4,
+                Value::Date(_) => 5,
+                Value::Timestamp(_) => 6,
+                Value::Bytes(_) => 7,
+                Value::Composite(_) => 8,
+                Value::Null | Value::Overflow { .. } => return Err(PlanckError::Corruption(format!("{} cannot be a key", slot.value))),
+            };
+            let row = match self.cells.get(&slot.pointer) {
+                Some(row) => row,
+                None => return Err(PlanckError::Corruption(format!("slot {} points to no cell", slot.value)))
+            };
+            free_space_pointer = match free_space_pointer.checked_sub(row.size as usize + 2) {
+                Some(pointer) if offset + slot_size(&slot.value) <= pointer => pointer,
+                _ => return Err(PlanckError::PageFull)
+            };
+            offset = write_value(&mut buffer, offset, &slot.value);
+            buffer[offset..offset + 2].copy_from_slice(&(free_space_pointer as u16).to_be_bytes());
+            offset += 2;
+
+            buffer[free_space_pointer..free_space_pointer + 2].copy_from_slice(&row.size.to_be_bytes());
+            write_row(&mut buffer, free_space_pointer + 2, &row.values);
         }
-        buffer[1..3].copy_from_slice(&free_space_pointer.to_be_bytes());
+        buffer[1..3].copy_from_slice(&(free_space_pointer as u16).to_be_bytes());
+        let checksum = page_checksum(&buffer);
+        buffer[5..9].copy_from_slice(&checksum.to_be_bytes());
 
-        return Ok(buffer)
+        Ok(buffer)
     }
 
-    pub fn buff_to_page(field_types: Vec<u8>,buffer: [u8; 4096]) -> Page {
-        let mut offset = 0;
-        let mut free_space_pointer: u16 = 4095;
-
-        let mut node_type ;
+    pub fn buff_to_page(field_types: &[u8], buffer: &[u8; 4096]) -> Result<Page, PlanckError> {
+        Page::read_page(field_types, buffer, PAGE_HEADER_SIZE)
+    }
 
-        if buffer[offset] == 1 {
-            node_type = NodeType::Leaf(Vec::new());
-        } else {
-            node_type = NodeType::Internal(Vec::new());
+    // A page of an older format version, only read while such a file is rebuilt. The links its header does
+    // not have are NO_PAGE.
+    pub fn old_buff_to_page(format_version: u16, field_types: &[u8], buffer: &[u8; 4096]) -> Result<Page, PlanckError> {
+        match format_version {
+            V12_FORMAT_VERSION => Page::read_page(field_types, buffer, V12_PAGE_HEADER_SIZE),
+            V13_FORMAT_VERSION => Page::read_page(field_types, buffer, V13_PAGE_HEADER_SIZE),
+            _ => Page::buff_to_page(field_types, buffer),
         }
+    }
+
+    fn read_page(field_types: &[u8], buffer: &[u8; 4096], header_size: usize) -> Result<Page, PlanckError> {
+        let mut offset = 0;
+
+        let node_type = match buffer[offset] {
+            1 => NodeType::Leaf,
+            0 => NodeType::Internal,
+            2 => return Err(PlanckError::Corruption("page is on the free list".to_string())),
+            x => return Err(PlanckError::Corruption(format!("unknown page type {}", x)))
+        };
 
         offset += 1;
          
-        free_space_pointer = combine_bytes(buffer[offset], buffer[offset+1]);
+        let free_space_pointer = combine_bytes(buffer[offset], buffer[offset+1]);
         offset += 2;
 
-        let is_string = buffer[offset] == 1;
-        offset += 1;
+        let slot_count = bytes_to_u16(&buffer[offset..offset + 2]);
+        offset += 2;
+
+        // The checksum is verified by the caller with verify_checksum, who knows which table and page this is.
+        offset += 4;
+
+        // The caller also picks field_types according to the schema version.
+        let schema_version = bytes_to_u16(&buffer[offset..offset + 2]);
+        offset += 2;
 
-        let slot_count = buffer[offset];
+        let key_type = key_type(buffer[offset])?;
         offset += 1;
-        let mut slot_vec = Vec::new();
-        if is_string {
-            for i in 0..slot_count {
-               let len: u8 = buffer[offset];
-               offset += 1;
-               
-               let string = std::str::from_utf8(&buffer[offset..offset+len as usize]).unwrap().to_string();
-               offset += len as usize;
-               let pointer = combine_bytes(buffer[offset], buffer[offset+1]);
-               offset += 2;
-               slot_vec.push(Slot { value: Value::String(len, string), pointer: pointer })
 
+        let mut links = [NO_PAGE; 2];
+        for link in links.iter_mut() {
+            if offset + 4 <= header_size {
+                *link = bytes_to_u32(buffer, offset);
+                offset += 4;
             }
-        } else {
-          for i in 0..slot_count {
-             let number = bytes_to_u32(&buffer, offset);
-             offset += 4;
-             let pointer = combine_bytes(buffer[offset], buffer[offset+1]);
-             offset += 2;
-             slot_vec.push(Slot{value: Value::Number(number), pointer: pointer})
-          }
+        }
+        let [next_leaf, prev_leaf] = links;
+        offset = header_size;
+        let mut slot_vec = Vec::new();
+        for _ in 0..slot_count {
+            let value = read_value(buffer, &mut offset, key_type)?;
+            let pointer = bytes_to_u16(read_bytes(buffer, offset, 2)?);
+            offset += 2;
+            slot_vec.push(Slot { value, pointer })
         }
         let mut cells = HashMap::new();
-        let mut int_pointer = 0;
         match node_type {
-            NodeType::Internal(_) => {
+            NodeType::Internal => {
                 for slot in &slot_vec {
-                    let mut int_pointer = slot.pointer;
-                    let size = buffer[int_pointer as usize];
-                    int_pointer += 1;
-                    let slice = &buffer[int_pointer as usize..(int_pointer + size as u16) as usize];
-                    let mut row = Rows { size: size, values: Vec::new() };
-                    
-                    let mut slice_pointer = 0;
-                    row.values.push(Value::Number(u32::from_be_bytes(slice[slice_pointer as usize..(slice_pointer+4) as usize].try_into().unwrap())));
-                    slice_pointer += 4;
+                    let size = bytes_to_u16(read_bytes(buffer, slot.pointer as usize, 2)?);
+                    let slice = read_bytes(buffer, slot.pointer as usize + 2, size as usize)?;
+                    // Internal cells only hold the pointer to the child.
+                    let row = Rows { size, values: read_row(slice, &[NUMBER_TYPE])? };
                     cells.insert(slot.pointer, row);
                 }
             }
-            NodeType::Leaf(_) => {
+            NodeType::Leaf => {
                 for slot in &slot_vec {
-                    let mut int_pointer = slot.pointer;
-                    let size = buffer[int_pointer as usize];
-                    int_pointer += 1;
-                    let slice = &buffer[int_pointer as usize..(int_pointer + size as u16) as usize];
-                    let mut row = Rows { size: size, values: Vec::new() };
-                    
-                    let mut slice_pointer = 0;
-                    for field_type in &field_types {
-                        if field_type == &2 {
-                           row.values.push(Value::Number(u32::from_be_bytes(slice[slice_pointer as usize..(slice_pointer+4) as usize].try_into().unwrap())));
-                           slice_pointer += 4;
-                        } else if field_type == &7 {
-                           let str_len =  slice[slice_pointer as usize];
-                           slice_pointer += 1;
-                           let string = std::str::from_utf8(&slice[slice_pointer as usize..(slice_pointer + str_len as u16) as usize]).unwrap();
-                           row.values.push(Value::String(str_len, string.to_string()));
-                           slice_pointer += str_len as u16;
-                        }
-                    }
+                    let size = bytes_to_u16(read_bytes(buffer, slot.pointer as usize, 2)?);
+                    let slice = read_bytes(buffer, slot.pointer as usize + 2, size as usize)?;
+                    let row = Rows { size, values: read_row(slice, field_types)? };
                     cells.insert(slot.pointer, row);
                 }
             }
         }
-        
-        
-        if free_space_pointer == 4093 {
-        
-        }
 
-        return Page { page_type: node_type, free_space_pointer: free_space_pointer, slots: slot_vec, cells: cells}
+        Ok(Page { page_type: node_type, free_space_pointer, slots: slot_vec, cells, schema_version, next_leaf, prev_leaf })
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    // A leaf holding every key, written out and read back.
+    fn round_trip(keys: &[Value]) -> Vec<Value> {
+        let mut page = Page::new_leaf();
+        for key in keys {
+            page.insert(KeyRow { key: key.clone(), row: vec![key.clone()] }).unwrap();
+        }
+        let types = vec![keys[0].type_code()];
+        let page = Page::buff_to_page(&types, &page.page_to_buff().unwrap()).unwrap();
+        for slot in &page.slots {
+            assert_eq!(page.cells[&slot.pointer].values, vec![slot.value.clone()]);
+        }
+        page.slots.into_iter().map(|slot| slot.value).collect()
+    }
+
+    #[test]
+    fn int64_and_float64_keep_their_order_in_bytes() {
+        let ints = [i64::MIN, i64::MIN + 1, -256, -1, 0, 1, 255, i64::MAX - 1, i64::MAX];
+        for pair in ints.windows(2) {
+            assert!(encode_i64(pair[0]) < encode_i64(pair[1]));
+        }
+        assert!(ints.iter().all(|x| decode_i64(&encode_i64(*x)) == *x));
+
+        let floats = [f64::NEG_INFINITY, f64::MIN, -1.5, -f64::MIN_POSITIVE, 0.0, f64::MIN_POSITIVE, 1.5, f64::MAX, f64::INFINITY];
+        for pair in floats.windows(2) {
+            assert!(encode_f64(pair[0]) < encode_f64(pair[1]));
+        }
+        assert!(floats.iter().all(|x| decode_f64(&encode_f64(*x)) == *x));
+
+        let keys: Vec<Value> = ints.iter().map(|x| Value::Int64(*x)).collect();
+        assert_eq!(round_trip(&keys), keys);
+        let keys: Vec<Value> = floats.iter().map(|x| Value::Float64(*x)).collect();
+        assert_eq!(round_trip(&keys), keys);
+    }
+
+    #[test]
+    fn negative_zero_is_stored_as_zero() {
+        assert_eq!(encode_f64(-0.0), encode_f64(0.0));
+        let keys = round_trip(&[Value::Float64(-0.0)]);
+        assert!(matches!(keys[..], [Value::Float64(x)] if x.to_bits() == 0));
+    }
+
+    #[test]
+    fn string_lengths_come_from_the_strings() {
+        // Built without Value::string, the lengths they carry are wrong.
+        let row = vec![Value::String(3, "hello".to_string()), Value::String(200, "hi".to_string())];
+        assert_eq!(row.iter().map(Value::encoded_size).collect::<Vec<usize>>(), [6, 3]);
+        let mut page = Page::new_leaf();
+        page.insert(KeyRow { key: Value::String(1, "key".to_string()), row }).unwrap();
+        let page = Page::buff_to_page(&[STRING_TYPE, STRING_TYPE], &page.page_to_buff().unwrap()).unwrap();
+        let values = &page.cells[&page.slots[0].pointer].values;
+        assert!(matches!(&values[..], [Value::String(5, x), Value::String(2, y)] if x == "hello" && y == "hi"));
+        assert!(matches!(&page.slots[0].value, Value::String(3, x) if x == "key"));
+    }
+
+    #[test]
+    fn int64_and_float64_parse() {
+        assert_eq!(Value::parse("9223372036854775807", INT64_TYPE).unwrap(), Value::Int64(i64::MAX));
+        assert_eq!(Value::parse("-9223372036854775808", INT64_TYPE).unwrap(), Value::Int64(i64::MIN));
+        assert!(Value::parse("9223372036854775808", INT64_TYPE).is_err());
+        assert_eq!(Value::parse("inf", FLOAT64_TYPE).unwrap(), Value::Float64(f64::INFINITY));
+        assert_eq!(Value::parse("-inf", FLOAT64_TYPE).unwrap(), Value::Float64(f64::NEG_INFINITY));
+        // -0 is stored as 0, equal keys have the same bytes.
+        assert!(matches!(Value::parse("-0", FLOAT64_TYPE).unwrap(), Value::Float64(x) if x.to_bits() == 0));
+        assert!(Value::parse("NaN", FLOAT64_TYPE).is_err());
+    }
+
+    #[test]
+    fn dates_and_timestamps_keep_their_order_in_bytes() {
+        let days = [i32::MIN, i32::MIN + 1, -1, 0, 1, i32::MAX - 1, i32::MAX];
+        for pair in days.windows(2) {
+            assert!(encode_i32(pair[0]) < encode_i32(pair[1]));
+        }
+        assert!(days.iter().all(|x| decode_i32(&encode_i32(*x)) == *x));
+
+        let keys: Vec<Value> = days.iter().map(|x| Value::Date(*x)).collect();
+        assert_eq!(round_trip(&keys), keys);
+        let millis = [i64::MIN, -86_400_000, -1, 0, 1, 86_400_000, i64::MAX];
+        let keys: Vec<Value> = millis.iter().map(|x| Value::Timestamp(*x)).collect();
+        assert_eq!(round_trip(&keys), keys);
+    }
+
+    #[test]
+    fn dates_and_timestamps_parse() {
+        assert_eq!(Value::parse("1970-01-01", DATE_TYPE).unwrap(), Value::Date(0));
+        assert_eq!(Value::parse("1969-12-31", DATE_TYPE).unwrap(), Value::Date(-1));
+        assert_eq!(Value::parse("2024-02-29", DATE_TYPE).unwrap(), Value::Date(19_782));
+        assert!(Value::parse("2023-02-29", DATE_TYPE).is_err());
+        assert_eq!(Value::parse("1970-01-02T00:00:01.500Z", TIMESTAMP_TYPE).unwrap(), Value::Timestamp(86_401_500));
+        assert_eq!(Value::parse("1969-12-31T23:59:59", TIMESTAMP_TYPE).unwrap(), Value::Timestamp(-1000));
+        assert!(Value::parse("1970-01-01T24:00:00", TIMESTAMP_TYPE).is_err());
+        assert_eq!(Value::Date(19_782).to_string(), "2024-02-29");
+        assert_eq!(Value::Timestamp(-1000).to_string(), "1969-12-31T23:59:59.000Z");
+    }
+
+    #[test]
+    fn composite_keys_compare_value_by_value() {
+        let tuples = [
+            vec![Value::Number(1), Value::string(String::new())],
+            vec![Value::Number(1), Value::string("a".to_string())],
+            vec![Value::Number(1), Value::string("a\0".to_string())],
+            vec![Value::Number(1), Value::string("a\0b".to_string())],
+            vec![Value::Number(1), Value::string("ab".to_string())],
+            vec![Value::Number(1), Value::string("b".to_string())],
+            vec![Value::Number(2), Value::string(String::new())],
+            vec![Value::Number(2), Value::Bytes(vec![0, 0])],
+        ];
+        let keys: Vec<Value> = tuples.iter().map(|values| Value::composite(values).unwrap()).collect();
+        for pair in keys.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} < {:?}", pair[0], pair[1]);
+        }
+        for (key, values) in keys.iter().zip(&tuples) {
+            assert_eq!(&key.components().unwrap(), values);
+        }
+        assert_eq!(round_trip(&keys), keys);
+
+        let mixed = [Value::Int64(-5), Value::Float64(-0.5), Value::Date(-1), Value::Timestamp(i64::MAX), Value::Bool(true), Value::Bytes(vec![0xFF, 0])];
+        assert_eq!(Value::composite(&mixed).unwrap().components().unwrap(), mixed);
+    }
+
+    #[test]
+    fn composite_prefixes() {
+        let types = [NUMBER_TYPE, DATE_TYPE, STRING_TYPE];
+        let key = Value::parse_composite("42,2024-01-31,abc", &types).unwrap();
+        assert_eq!(key.components().unwrap(), vec![Value::Number(42), Value::Date(19_753), Value::string("abc".to_string())]);
+        assert!(key.has_prefix(&Value::parse_composite("42", &types).unwrap()));
+        assert!(key.has_prefix(&Value::parse_composite("42,2024-01-31", &types).unwrap()));
+        assert!(!key.has_prefix(&Value::parse_composite("4", &types).unwrap()));
+        assert!(!key.has_prefix(&Value::parse_composite("42,2024-01-31,ab", &types).unwrap()));
+        assert!(Value::parse_composite("42,2024-01-31,abc,1", &types).is_err());
+        assert!(Value::parse_composite("x", &types).is_err());
+    }
+
+    #[test]
+    fn composite_keys_are_limited() {
+        assert!(Value::composite(&[Value::Float64(f64::NAN)]).is_err());
+        assert!(Value::composite(&[Value::Null]).is_err());
+        assert!(Value::composite(&[Value::string("x".repeat(MAX_INLINE_VALUE))]).is_err());
+        // -0.0 and 0.0 are the same key.
+        assert_eq!(Value::composite(&[Value::Float64(-0.0)]).unwrap(), Value::composite(&[Value::Float64(0.0)]).unwrap());
+    }
+
+    #[test]
+    fn pages_hold_more_than_255_slots() {
+        let keys: Vec<Value> = (0..280).map(Value::Number).collect();
+        assert_eq!(round_trip(&keys), keys);
+    }
+
+    #[test]
+    fn strings_and_bytes_compare_byte_by_byte() {
+        let strings = ["", "a", "ab", "b", "ba", "bb", "bbbbbbbbbbbb", "c", "zzzzzzzz", "zzzzzzzzz", "é"];
+        for pair in strings.windows(2) {
+            assert!(Value::string(pair[0].to_string()) < Value::string(pair[1].to_string()), "{} < {}", pair[0], pair[1]);
+        }
+        assert!(Value::Bytes(vec![1, 255]) < Value::Bytes(vec![2]));
+        assert!(Value::Bytes(vec![2]) < Value::Bytes(vec![2, 0]));
+        assert_eq!(Value::string("ab".to_string()), Value::String(2, "ab".to_string()));
+    }
+
+    #[test]
+    fn values_are_totally_ordered() {
+        assert_eq!(Value::Float64(-0.0), Value::Float64(0.0));
+        assert_eq!(Value::Float64(f64::NAN), Value::Float64(f64::NAN));
+        assert!(Value::Float64(f64::NEG_INFINITY) < Value::Float64(f64::MIN));
+        assert!(Value::Null < Value::Number(0));
+        assert!(Value::Number(u32::MAX) < Value::string(String::new()));
+        let mut values = vec![Value::Int64(3), Value::Null, Value::Int64(-3), Value::Bool(false)];
+        values.sort();
+        assert_eq!(values, vec![Value::Null, Value::Int64(-3), Value::Int64(3), Value::Bool(false)]);
+    }
+}
@@ -0,0 +1,255 @@
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use memmap2::{Mmap, MmapOptions};
+
+use crate::dbengine::engine::{FileStorage, FORMAT_VERSION, PAGE_SIZE};
+use crate::dbengine::error::PlanckError;
+
+// Where the pages of a database live. The buffer pool only talks to this trait,
+// so the B+ tree works the same on top of a file or on top of plain memory.
+pub trait StorageBackend: Debug + Send {
+    fn read_page(&self, page_id: u32) -> Result<[u8; PAGE_SIZE], PlanckError>;
+    // The bytes of a page without copying them, for storages that have them at hand. The buffer pool
+    // falls back to read_page when there are none.
+    fn page_ref(&self, _page_id: u32) -> Option<&[u8; PAGE_SIZE]> {
+        None
+    }
+    fn write_page(&mut self, buffer: [u8; PAGE_SIZE], page_id: u32) -> Result<(), PlanckError>;
+    // Stores the buffer in a new page (a freed one if there is any) and returns its id.
+    fn allocate(&mut self, buffer: [u8; PAGE_SIZE]) -> Result<u32, PlanckError>;
+    fn free(&mut self, page_id: u32) -> Result<(), PlanckError>;
+    // Makes every write so far durable.
+    fn sync(&mut self) -> Result<(), PlanckError>;
+    // Number of page ids handed out so far, free pages included.
+    fn len(&self) -> u32;
+    fn free_pages(&self) -> u32;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    // Format version of the pages, they are read according to it.
+    fn format_version(&self) -> u16 {
+        FORMAT_VERSION
+    }
+}
+
+// Chosen when a database is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    File,
+    // Same file format as File, but pages are read from a memory mapping of the file.
+    Mmap,
+    Memory,
+}
+
+// Every table of a database shares the same storage.
+pub type SharedPager = Arc<Mutex<Box<dyn StorageBackend>>>;
+
+pub fn lock(pager: &SharedPager) -> MutexGuard<'_, Box<dyn StorageBackend>> {
+    // A panic while holding the lock leaves nothing half written in the storage itself, so poisoning is ignored.
+    pager.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn out_of_bounds(page_id: u32) -> PlanckError {
+    PlanckError::Corruption(format!("page {} is out of bounds of the in-memory database", page_id))
+}
+
+// Pages kept in a Vec, nothing ever reaches the disk. Used for ephemeral databases and tests.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    pages: Vec<[u8; PAGE_SIZE]>,
+    free_pages: Vec<u32>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn read_page(&self, page_id: u32) -> Result<[u8; PAGE_SIZE], PlanckError> {
+        self.pages.get(page_id as usize).copied().ok_or_else(|| out_of_bounds(page_id))
+    }
+
+    fn write_page(&mut self, buffer: [u8; PAGE_SIZE], page_id: u32) -> Result<(), PlanckError> {
+        let page = self.pages.get_mut(page_id as usize).ok_or_else(|| out_of_bounds(page_id))?;
+        *page = buffer;
+        Ok(())
+    }
+
+    fn allocate(&mut self, buffer: [u8; PAGE_SIZE]) -> Result<u32, PlanckError> {
+        match self.free_pages.pop() {
+            Some(page_id) => {
+                self.pages[page_id as usize] = buffer;
+                Ok(page_id)
+            }
+            None => {
+                self.pages.push(buffer);
+                Ok(self.pages.len() as u32 - 1)
+            }
+        }
+    }
+
+    fn free(&mut self, page_id: u32) -> Result<(), PlanckError> {
+        if page_id as usize >= self.pages.len() {
+            return Err(out_of_bounds(page_id));
+        }
+        self.free_pages.push(page_id);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), PlanckError> {
+        Ok(())
+    }
+
+    fn len(&self) -> u32 {
+        self.pages.len() as u32
+    }
+
+    fn free_pages(&self) -> u32 {
+        self.free_pages.len() as u32
+    }
+}
+
+// Reads come straight from a read-only mapping of the database file and the OS page cache does the caching.
+// Writes, allocations and the free list go through the normal file storage (positioned writes, same sync),
+// a shared mapping sees them right away so there is only one copy of every page.
+#[derive(Debug)]
+pub struct MmapStorage {
+    file: FileStorage,
+    map: Mmap,
+    // Pages the mapping covers, the file may have fewer.
+    mapped_pages: u32,
+}
+
+// Smallest mapping, in pages.
+const MIN_MAPPED_PAGES: u32 = 64;
+
+impl MmapStorage {
+    pub fn new(file: FileStorage) -> Result<Self, PlanckError> {
+        let pages = file.len().saturating_mul(2);
+        let map = Self::map(&file, pages.max(MIN_MAPPED_PAGES))?;
+        Ok(MmapStorage { file, map, mapped_pages: pages.max(MIN_MAPPED_PAGES) })
+    }
+
+    // The mapping reaches past the end of the file, so that it is not renewed on every allocation.
+    fn map(file: &FileStorage, pages: u32) -> Result<Mmap, PlanckError> {
+        let len = FileStorage::page_offset(pages) as usize;
+        // Safety: PlanckDB only ever grows the database file while it is open (pages are freed, not cut off),
+        // so the mapped range never shrinks under a reader. The part of the mapping past the end of the file
+        // is never touched: only pages below len() are read and the file always holds them.
+        Ok(unsafe { MmapOptions::new().len(len).map(file.file())? })
+    }
+
+    fn mapped(&self, page_id: u32) -> Option<&[u8; PAGE_SIZE]> {
+        if page_id >= self.file.len() || page_id >= self.mapped_pages {
+            return None;
+        }
+        let start = FileStorage::page_offset(page_id) as usize;
+        self.map.get(start..start + PAGE_SIZE)?.try_into().ok()
+    }
+}
+
+impl StorageBackend for MmapStorage {
+    fn read_page(&self, page_id: u32) -> Result<[u8; PAGE_SIZE], PlanckError> {
+        match self.mapped(page_id) {
+            Some(page) => Ok(*page),
+            None => self.file.read_page(page_id),
+        }
+    }
+
+    fn page_ref(&self, page_id: u32) -> Option<&[u8; PAGE_SIZE]> {
+        self.mapped(page_id)
+    }
+
+    fn write_page(&mut self, buffer: [u8; PAGE_SIZE], page_id: u32) -> Result<(), PlanckError> {
+        self.file.write_page(buffer, page_id)
+    }
+
+    fn allocate(&mut self, buffer: [u8; PAGE_SIZE]) -> Result<u32, PlanckError> {
+        let page_id = self.file.allocate(buffer)?;
+        // Doubling the mapping keeps the number of remaps logarithmic in the size of the file.
+        if page_id >= self.mapped_pages {
+            let pages = self.mapped_pages.saturating_mul(2).max(page_id + 1);
+            self.map = Self::map(&self.file, pages)?;
+            self.mapped_pages = pages;
+        }
+        Ok(page_id)
+    }
+
+    fn free(&mut self, page_id: u32) -> Result<(), PlanckError> {
+        self.file.free(page_id)
+    }
+
+    fn sync(&mut self) -> Result<(), PlanckError> {
+        self.file.sync()
+    }
+
+    fn len(&self) -> u32 {
+        self.file.len()
+    }
+
+    fn free_pages(&self) -> u32 {
+        self.file.free_pages()
+    }
+
+    fn format_version(&self) -> u16 {
+        self.file.format_version()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("planck_storage_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("planck.db")
+    }
+
+    fn numbered(number: u32) -> [u8; PAGE_SIZE] {
+        let mut buffer = [0u8; PAGE_SIZE];
+        buffer[100..104].copy_from_slice(&number.to_be_bytes());
+        buffer
+    }
+
+    #[test]
+    fn mmap_lends_pages_and_doubles_the_mapping() {
+        let path = temp_file("mmap");
+        let mut storage = MmapStorage::new(FileStorage::create(&path).unwrap()).unwrap();
+        assert_eq!(storage.mapped_pages, MIN_MAPPED_PAGES);
+        for number in 0..200 {
+            assert_eq!(storage.allocate(numbered(number)).unwrap(), number);
+        }
+        assert_eq!(storage.mapped_pages, MIN_MAPPED_PAGES * 4);
+        for page_id in 0..200 {
+            let page = storage.page_ref(page_id).unwrap();
+            assert_eq!(page[100..104], page_id.to_be_bytes());
+            assert_eq!(storage.read_page(page_id).unwrap(), *page);
+        }
+        assert!(storage.page_ref(200).is_none());
+
+        storage.write_page(numbered(1000), 5).unwrap();
+        assert_eq!(storage.page_ref(5).unwrap()[100..104], 1000u32.to_be_bytes());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn memory_storage_reuses_freed_pages() {
+        let mut storage = MemoryStorage::new();
+        for number in 0..3 {
+            storage.allocate(numbered(number)).unwrap();
+        }
+        storage.free(1).unwrap();
+        assert_eq!(storage.free_pages(), 1);
+        assert_eq!(storage.allocate(numbered(7)).unwrap(), 1);
+        assert_eq!(storage.read_page(1).unwrap(), numbered(7));
+        assert!(storage.page_ref(1).is_none());
+        assert!(storage.read_page(3).is_err());
+    }
+}
@@ -0,0 +1,6 @@
+#[allow(non_snake_case)]
+pub mod TCP_connections;
+pub mod dbengine;
+pub mod database;
+
+pub use crate::database::Database;
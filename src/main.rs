@@ -1,8 +1,30 @@
-mod TCP_connections;
-mod dbengine;
+use std::path::Path;
+use std::process::exit;
 
-use crate::TCP_connections::server::*;
+use planck_db::TCP_connections::server::*;
+use planck_db::dbengine::checker::check_database;
 
 fn main() {
-   server()
-}
\ No newline at end of file
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|arg| arg.as_str()) {
+        // planck check [data directory], checks the database file without changing it.
+        Some("check") => {
+            let dir = args.get(2).map(|dir| dir.as_str()).unwrap_or("PlanckDB");
+            match check_database(Path::new(dir)) {
+                Ok(violations) if violations.is_empty() => println!("{}: no problems found", dir),
+                Ok(violations) => {
+                    for violation in &violations {
+                        println!("{}", violation);
+                    }
+                    println!("{}: {} problems found", dir, violations.len());
+                    exit(1);
+                }
+                Err(err) => {
+                    eprintln!("{}: {}", dir, err);
+                    exit(2);
+                }
+            }
+        }
+        _ => server()
+    }
+}